@@ -1,6 +1,10 @@
 import { storage } from "near-runtime-ts";
 import { Contract } from "../contract";
 import { VM } from "..";
+import { Value } from "../outcome";
+
+@external("env", "value_return")
+declare function value_return(value_len: u64, value_ptr: u64): void;
 
 describe("Contract", () => {
   beforeAll(() => {
@@ -49,4 +53,39 @@ describe("Contract", () => {
     // storage.set("contract", contract);
     expect(storage.contains("contract")).toBe(false, "the contract shouldn't exist");
   });
-});
\ No newline at end of file
+});
+
+export function writeGreeting(): void {
+  storage.setString("greeting", "hello");
+}
+
+export function returnGreeting(): void {
+  const greeting = String.UTF8.encode("hello");
+  value_return(greeting.byteLength, changetype<usize>(greeting));
+}
+
+describe("VM.call", () => {
+  beforeEach(() => {
+    VM.saveState();
+  });
+
+  afterEach(() => {
+    VM.restoreState();
+  });
+
+  it("should keep the changes of a call", () => {
+    VM.call("writeGreeting");
+    expect(storage.getString("greeting")).toBe("hello", "the call should have written the greeting");
+  });
+
+  it("should return the value of the method as it is", () => {
+    const data = VM.call("returnGreeting").return_data;
+    expect(data instanceof Value).toBe(true, "the method should have returned a value");
+    expect((data as Value).data).toBe("hello", "the value shouldn't be quoted");
+  });
+
+  it("should roll back the changes of a view", () => {
+    VM.view("writeGreeting");
+    expect(storage.contains("greeting")).toBe(false, "a view call shouldn't change the storage");
+  });
+});
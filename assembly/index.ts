@@ -1,33 +1,79 @@
 import { u128 } from "near-runtime-ts";
 import { Outcome } from "./outcome";
 
+//@ts-ignore
+@external("vm", "saveState")
+declare function _saveState(): void;
+
+//@ts-ignore
+@external("vm", "restoreState")
+declare function _restoreState(): void;
+
+//@ts-ignore
+@external("vm", "outcome")
+declare function _outcome(): Outcome;
+
+//@ts-ignore
+@external("vm", "call")
+declare function _call(method: usize, args: usize): Outcome;
+
+//@ts-ignore
+@external("vm", "view")
+declare function _view(method: usize, args: usize): Outcome;
+
+//@ts-ignore
+@external("vm", "addInvariant")
+declare function _addInvariant(name: usize): void;
+
 /**
  * Methods on the current VM
  */ 
-export declare namespace VM {
+export namespace VM {
   /** 
    * Saves the internal state of the VM. 
    * */ 
-  //@ts-ignore
-  @external("vm", "saveState")
-  export function saveState(): void;
+  export function saveState(): void {
+    _saveState();
+  }
 
   /** 
    * Restores the internal state of the VM. 
    * 
    */ 
-  //@ts-ignore
-  @external("vm", "restoreState")
-  export function restoreState(): void;
+  export function restoreState(): void {
+    _restoreState();
+  }
 
   /**
    * Return the VM Outcome of the current running contract
    */ 
-  //@ts-ignore
-  @external("vm", "outcome")
-  export function outcome(): Outcome;
+  export function outcome(): Outcome {
+    return _outcome();
+  }
 
-  
+  /**
+   * Calls an exported method of the contract as a new receipt with the given JSON arguments.
+   */
+  export function call(method: string, args: string = ""): Outcome {
+    return _call(changetype<usize>(String.UTF8.encode(method, true)), changetype<usize>(String.UTF8.encode(args, true)));
+  }
+
+  /**
+   * Calls an exported method of the contract as a view function. Changes to the state are
+   * rolled back afterwards.
+   */
+  export function view(method: string, args: string = ""): Outcome {
+    return _view(changetype<usize>(String.UTF8.encode(method, true)), changetype<usize>(String.UTF8.encode(args, true)));
+  }
+
+  /**
+   * Checks the invariant `name`, an exported function returning `false` when it is broken, after
+   * every call made with `call` and `view`. A broken invariant makes the call throw. Whatever
+   * the function writes to storage is undone.
+   */
+  export function addInvariant(name: string): void {
+    _addInvariant(changetype<usize>(String.UTF8.encode(name, true)));
+  }
 }  
 
 
@@ -56,15 +102,19 @@ declare function _setRandom_seed(s: usize): void;
 
 //@ts-ignore
 @external("vm", "setAttached_deposit")
-declare function _setAttached_deposit(lo: u64, hi: u64): void;
+declare function _setAttached_deposit(s: usize): void;
 
 //@ts-ignore
 @external("vm", "setAccount_balance")
-declare function _setAccount_balance(lo: u64, hi: u64): void;
+declare function _setAccount_balance(s: usize): void;
 
 //@ts-ignore
 @external("vm", "setAccount_locked_balance")
-declare function _setAccount_locked_balance(lo: u64, hi: u64): void;
+declare function _setAccount_locked_balance(s: usize): void;
+
+//@ts-ignore
+@external("vm", "setBalance_includes_deposit")
+declare function _setBalance_includes_deposit(b: bool): void;
 
 
 //@ts-ignore
@@ -137,11 +187,19 @@ export namespace Context {
   }
 
   export function setAccount_balance(_u128: u128): void {
-    _setAccount_balance(_u128.lo, _u128.hi);
+    _setAccount_balance(changetype<usize>(String.UTF8.encode(_u128.toString(), true)));
   }
 
   export function setAccount_locked_balance(_u128: u128): void {
-    _setAccount_locked_balance(_u128.lo, _u128.hi);
+    _setAccount_locked_balance(changetype<usize>(String.UTF8.encode(_u128.toString(), true)));
+  }
+
+  /**
+   * Whether balances passed to `setAccount_balance` already include the attached deposit.
+   * By default the attached deposit is added on top of the account balance, like on chain.
+   */
+  export function setBalance_includes_deposit(b: bool): void {
+    _setBalance_includes_deposit(b);
   }
 
   export function setStorage_usage(amt: u64): void {
@@ -149,7 +207,7 @@ export namespace Context {
   }
 
   export function setAttached_deposit(_u128: u128): void {
-    _setAttached_deposit(_u128.lo, _u128.hi);
+    _setAttached_deposit(changetype<usize>(String.UTF8.encode(_u128.toString(), true)));
   }
 
   export function setPrepaid_gas(_u64: u64): void {
@@ -21,14 +21,13 @@ export const NoneID = idof<None>();
 export class Outcome {
   balance: u128;
   constructor(
-  balance_lo: u64,
-  balance_hi: u64,
+  balance: string,
   public burnt_gas: u64,
   public used_gas: u64,
   public logs: string[],
   public storage_usage: u64,
   public return_data: ReturnData,
   ){
-    this.balance = new u128(balance_lo, balance_hi);
+    this.balance = u128.fromString(balance);
   }
 }
\ No newline at end of file
@@ -50,11 +50,13 @@ function UTF8toStr(array) {
     return out;
 }
 
+// The `context.json` of the project being tested, if any. The one shipped with this package isn't
+// looked up, so that projects without one get the defaults of `ContextBuilder`.
 function findContext() {
   let paths = [
                 path.join(process.cwd(),
                 "assembly", "__tests__"),
-                process.cwd(), __dirname
+                process.cwd()
               ]
               .map(p => path.join(p, "context.json"));
   let _paths = paths.filter(p => {
@@ -71,8 +73,111 @@ function findContext() {
 
 
 
+// Runs `method` of the instantiated `contract` as a new receipt on `vm`.
+// Exceptions thrown by the contract are reported in the outcome's `error`.
+function runMethod(vm, contract, method, args, opts, isView) {
+  if (contract == null || typeof contract[method] !== "function") {
+    throw new Error(`Contract has no exported method "${method}"`);
+  }
+  args = args === undefined ? "" : typeof args === "string" ? args : JSON.stringify(args);
+  vm.begin_call(method, args, opts, isView);
+  let error = invokeExport(contract, method);
+  if (vm.checks_determinism()) {
+    // Run it again from the same state, for the VM to compare both runs.
+    vm.rerun_call(error);
+    error = invokeExport(contract, method);
+  }
+  const outcome = vm.end_call(error);
+  checkHostInvariants(vm, method, outcome);
+  return outcome;
+}
+
+// Invokes an export of the contract, and returns the message of the exception it threw, if any.
+function invokeExport(contract, method) {
+  try {
+    contract[method]();
+  } catch (e) {
+    return e instanceof Error ? e.message : String(e);
+  }
+}
+
+// Runs the invariants that read the state through the host functions, like the ones registered
+// from AssemblyScript, after a call. The VM undoes whatever they change, and throws if any broke.
+function checkHostInvariants(vm, method, outcome) {
+  if (!vm.has_host_invariants()) {
+    return;
+  }
+  const { state, invariants } = vm.begin_invariants(method, outcome);
+  const failures = [];
+  for (const { name, check } of invariants) {
+    try {
+      if (check(state) === false) {
+        failures.push({ name, error: "Invariant returned false" });
+      }
+    } catch (e) {
+      failures.push({ name, error: e instanceof Error ? e.message : String(e) });
+    }
+  }
+  vm.end_invariants(failures);
+}
+
+/**
+ * Calls a method of the contract set as `vm.contract`.
+ * `opts` can contain `signer`, `predecessor`, `deposit` and `gas`.
+ * `deposit` can be a decimal string, a number or a `BigInt`.
+ */
+rust.VM.prototype.call = function (method, args, opts) {
+  return runMethod(this, this.contract, method, args, opts, false);
+};
+
+/**
+ * Calls a method of the contract set as `vm.contract` as a view function.
+ * Any changes it makes to the state are rolled back.
+ */
+rust.VM.prototype.view = function (method, args) {
+  return runMethod(this, this.contract, method, args, undefined, true);
+};
+
+/**
+ * Compares a snapshot of a call with the file `file`, or writes it there if the file doesn't exist
+ * or the `UPDATE_SNAPSHOTS` environment variable is set. The snapshot holds the `outcome` returned
+ * by `call`, the receipts it created and the storage diff since the checkpoint `opts.since`.
+ * Gas amounts may differ by `opts.gasTolerance` times the expected amount.
+ */
+rust.VM.prototype.matchSnapshot = function (file, outcome, opts) {
+  opts = opts || {};
+  const actual = this.snapshot(JSON.stringify(outcome), opts.since);
+  if (opts.update || process.env.UPDATE_SNAPSHOTS || !fs.existsSync(file)) {
+    fs.mkdirSync(path.dirname(file), { recursive: true });
+    fs.writeFileSync(file, actual);
+    return;
+  }
+  const diffs = rust.compare_snapshots(fs.readFileSync(file, "utf8"), actual, opts.gasTolerance || 0);
+  if (diffs.length > 0) {
+    const lines = diffs.map(d => `  ${d.path}: expected ${JSON.stringify(d.expected)}, found ${JSON.stringify(d.actual)}`);
+    throw new Error(`Outcome doesn't match snapshot ${file}:\n${lines.join("\n")}`);
+  }
+};
+
+// Coverage of the modules instantiated by as-pect, when the `COVERAGE` environment variable is
+// set. It is written in the lcov format to the file the variable names, or to `coverage/lcov.info`.
+const coverages = [];
+if (process.env.COVERAGE && rust.Coverage) {
+  process.on("exit", () => {
+    const file = ["1", "true"].includes(process.env.COVERAGE) ? path.join("coverage", "lcov.info") : process.env.COVERAGE;
+    fs.mkdirSync(path.dirname(file), { recursive: true });
+    fs.writeFileSync(file, coverages.map(c => c.lcov(".ts")).join(""));
+  });
+}
+
 function createImports(memory, createImports, instantiateSync, binary) {
   let wasm;
+  let coverage = null;
+  if (process.env.COVERAGE && rust.Coverage) {
+    coverage = new rust.Coverage(`module-${coverages.length}.wasm`, binary);
+    coverages.push(coverage);
+    binary = coverage.binary();
+  }
   let I8 = () => new Uint8Array(memory.buffer);
 
   function readUTF8Str(ptr) {
@@ -117,52 +222,43 @@ function createImports(memory, createImports, instantiateSync, binary) {
       I8().set(buffer, toNum(offset));
   }
 
-  const current_account_id = "alice"; 
-  const signer_account_id = "bob";
-  const signer_account_pk = "HuxUynD5GdrcZ5MauxJuu74sGHgS6wLfCqqhQkLWK";
-  const predecessor_account_id = "carol";
-  const input = "{ arg1: 1 }";
-  const block_index = 10;
-  const block_timestamp = 42;
-  const account_balance = 2;
-  const account_locked_balance = 1;
-  const storage_usage = 12;
-  const attached_deposit = 2;
-  const prepaid_gas = 10**(14);
-  const random_seed = "HuxUynD5GdrcZ5MauxJuu74sGHgS6wLfCqqhQkLWK";
-  const is_view = false;
-  const output_data_receivers= new Uint8Array([]);
-
+  // A `context.json` of the project wins over the defaults of `ContextBuilder`.
   function createContext() {
-    const _default = {
-        /// The account id of the current contract that we are executing.
-        current_account_id, // string
-        /// The account id of that signed the original transaction that led to this
-        /// execution.
-        signer_account_id, // string
-        /// The public key that was used to sign the original transaction that led to
-        /// this execution.
-        signer_account_pk, // string base58
-        predecessor_account_id, // string
-        input, // JSON string
-        block_index, // u128
-        block_timestamp,
-        account_balance,
-        account_locked_balance,
-        storage_usage,
-        attached_deposit,
-        prepaid_gas,
-        random_seed,
-        is_view,
-        output_data_receivers,
+    return findContext() || new rust.ContextBuilder().build();
+  }
+
+  function toOutcomePtr(outcome) {
+    let strArrPtr = wasm.newStringArray();
+    for (let str of outcome.logs) {
+      strArrPtr = wasm.pushString(strArrPtr, wasm.__allocString(str));
     }
-    
-    return findContext() || _default;
+    // `return_data` is set for `outcome` too, and holds the bytes as the method returned them.
+    let return_data_ptr;
+    const { return_data } = outcome;
+    if (return_data.Value != null) {
+      return_data_ptr = new wasm.Value(wasm.__allocString(UTF8toStr(return_data.Value))).valueOf();
+    } else if (return_data.ReceiptIndex != null) {
+      return_data_ptr = new wasm.ReceiptIndex(BigInt(return_data.ReceiptIndex)).valueOf();
+    } else {
+      return_data_ptr = wasm.NONE;
+    }
+    let outcomePtr = new wasm.Outcome(wasm.__allocString(outcome.balance),
+                                      BigInt(outcome.burnt_gas),
+                                      BigInt(outcome.used_gas),
+                                      strArrPtr,
+                                      BigInt(outcome.storage_usage),
+                                      return_data_ptr)
+    return outcomePtr.valueOf();
   }
 
   context =  createContext();
   vm = new rust.VM(context);
   let _imports =  {
+    coverage: {
+        hit(id) {
+          coverage.hit(id);
+        },
+    },
     vm: {
         saveState() {
           vm.save_state();
@@ -171,23 +267,17 @@ function createImports(memory, createImports, instantiateSync, binary) {
           vm.restore_state();
         },
         outcome() {
-          let outcome = vm.outcome();
-          let strArrPtr = wasm.newStringArray();
-          for (let str of outcome.logs) {
-            strArrPtr = wasm.pushString(strArrPtr, wasm.__allocString(str));
-          }
-          let return_data_ptr;
-          if (outcome.return_data === "None") {
-            return_data_ptr = wasm.NONE;
-          }
-          let outcomePtr = new wasm.Outcome(BigInt(outcome.balance1),
-                                            BigInt(outcome.balance2),
-                                            BigInt(outcome.burnt_gas),
-                                            BigInt(outcome.used_gas),
-                                            strArrPtr,
-                                            BigInt(outcome.storage_usage),
-                                            return_data_ptr)
-          return outcomePtr.valueOf();
+          return toOutcomePtr(vm.outcome());
+        },
+        call(method, args) {
+          return toOutcomePtr(vm.call(readUTF8Str(method), readUTF8Str(args)));
+        },
+        view(method, args) {
+          return toOutcomePtr(vm.view(readUTF8Str(method), readUTF8Str(args)));
+        },
+        addInvariant(name) {
+          const exported = readUTF8Str(name);
+          vm.add_invariant(exported, () => wasm[exported]() != 0, true);
         },
         saveContext() {
           vm.save_context();
@@ -218,18 +308,20 @@ function createImports(memory, createImports, instantiateSync, binary) {
         setBlock_timestamp(stmp) {
           vm.set_block_timestamp(stmp);
         },
-        setAccount_balance(lo, hi) {
-          //TODO: actually  u128
-          vm.set_account_balance(lo, hi);
+        setAccount_balance(s) {
+          vm.set_account_balance(readUTF8Str(s));
+        },
+        setAccount_locked_balance(s) {
+          vm.set_account_locked_balance(readUTF8Str(s));
         },
-        setAccount_locked_balance(lo, hi) {
-          vm.set_account_locked_balance(lo, hi);
+        setBalance_includes_deposit(b) {
+          vm.set_balance_includes_deposit(b==1);
         },
         setStorage_usage(amt) {
           vm.set_storage_usage(amt);
         },
-        setAttached_deposit(lo, hi) {
-          vm.set_attached_deposit(lo, hi);
+        setAttached_deposit(s) {
+          vm.set_attached_deposit(readUTF8Str(s));
         },
         setPrepaid_gas(_u64) {
           vm.set_prepaid_gas(_u64);
@@ -421,6 +513,7 @@ function createImports(memory, createImports, instantiateSync, binary) {
   };
   // Save reference to the instance
   wasm = instantiateSync(binary, createImports(_imports));
+  vm.contract = wasm;
   return wasm;
 }
 
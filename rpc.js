@@ -0,0 +1,413 @@
+const v8 = require('v8');
+v8.setFlagsFromString('--experimental-wasm-bigint');
+let rust = require(".");
+let crypto = require("crypto");
+let http = require("http");
+
+// A local stand-in for a NEAR node's JSON-RPC, backed by one mock VM per account, so that
+// near-api-js code can run without a network.
+//
+// Supports `query` (`view_account`, `view_access_key`, `view_state` and `call_function`, also in
+// their `account/...` path forms), `broadcast_tx_commit` for transactions that create accounts,
+// deploy contracts, call functions and transfer tokens, `block` and `status`.
+// Receipts created by contracts are not executed.
+
+const BASE58 = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+function base58(bytes) {
+  let n = BigInt("0x" + (Buffer.from(bytes).toString("hex") || "0"));
+  let s = "";
+  while (n > 0n) {
+    s = BASE58[Number(n % 58n)] + s;
+    n /= 58n;
+  }
+  for (const byte of bytes) {
+    if (byte !== 0) break;
+    s = "1" + s;
+  }
+  return s;
+}
+
+function unbase58(s) {
+  let n = 0n;
+  for (const c of s) {
+    const digit = BASE58.indexOf(c);
+    if (digit < 0) {
+      throw new RpcError(`Invalid base58 ${s}`);
+    }
+    n = n * 58n + BigInt(digit);
+  }
+  let hex = n > 0n ? n.toString(16) : "";
+  if (hex.length % 2) hex = "0" + hex;
+  const zeros = s.length - s.replace(/^1+/, "").length;
+  return Buffer.concat([Buffer.alloc(zeros), Buffer.from(hex, "hex")]);
+}
+
+function sha256(bytes) {
+  return crypto.createHash("sha256").update(bytes).digest();
+}
+
+// Reads the borsh encoding of transactions.
+class Reader {
+  constructor(buf) {
+    this.buf = buf;
+    this.offset = 0;
+  }
+  u8() { return this.buf[this.offset++]; }
+  u32() {
+    const v = this.buf.readUInt32LE(this.offset);
+    this.offset += 4;
+    return v;
+  }
+  u64() {
+    const v = this.buf.readBigUInt64LE(this.offset);
+    this.offset += 8;
+    return v;
+  }
+  u128() {
+    const lo = this.u64();
+    return (this.u64() << 64n) | lo;
+  }
+  bytes(len) {
+    const v = this.buf.slice(this.offset, this.offset + len);
+    this.offset += len;
+    return v;
+  }
+  vec() { return this.bytes(this.u32()); }
+  string() { return this.vec().toString("utf8"); }
+  publicKey() {
+    const keyType = this.u8();
+    return { keyType, data: this.bytes(32) };
+  }
+}
+
+function decodeAction(r) {
+  const kind = r.u8();
+  switch (kind) {
+    case 0: return { CreateAccount: {} };
+    case 1: return { DeployContract: { code: r.vec() } };
+    case 2: return { FunctionCall: { method_name: r.string(), args: r.vec(), gas: r.u64(), deposit: r.u128() } };
+    case 3: return { Transfer: { deposit: r.u128() } };
+    default: throw new RpcError(`Action ${kind} is not supported`);
+  }
+}
+
+function decodeTransaction(buf) {
+  const r = new Reader(buf);
+  const tx = {
+    signer_id: r.string(),
+    public_key: r.publicKey(),
+    nonce: r.u64(),
+    receiver_id: r.string(),
+    block_hash: r.bytes(32),
+  };
+  const count = r.u32();
+  tx.actions = [];
+  for (let i = 0; i < count; i++) {
+    tx.actions.push(decodeAction(r));
+  }
+  tx.hash = base58(sha256(buf.slice(0, r.offset)));
+  return tx;
+}
+
+// The host functions contracts can import from `env`, like the ones `createImports` of imports.js
+// provides, and `gas`, which the gas meter injects.
+const HOST_FUNCTIONS = [
+  "write_register", "read_register", "register_len",
+  "current_account_id", "signer_account_id", "signer_account_pk", "predecessor_account_id", "input",
+  "block_index", "storage_usage",
+  "account_balance", "attached_deposit", "prepaid_gas", "used_gas",
+  "random_seed", "sha256", "keccak256", "keccak512",
+  "value_return", "panic", "log_utf8", "log_utf16",
+  "promise_create", "promise_then", "promise_and", "promise_results_count", "promise_result", "promise_return",
+  "promise_batch_create", "promise_batch_then",
+  "promise_batch_action_create_account", "promise_batch_action_deploy_contract",
+  "promise_batch_action_function_call", "promise_batch_action_transfer", "promise_batch_action_stake",
+  "promise_batch_action_add_key_with_full_access", "promise_batch_action_add_key_with_function_call",
+  "promise_batch_action_delete_key", "promise_batch_action_delete_account",
+  "storage_write", "storage_read", "storage_remove", "storage_has_key",
+  "storage_iter_prefix", "storage_iter_range", "storage_iter_next",
+  "gas",
+];
+
+class RpcError extends Error {
+  constructor(message, code) {
+    super(message);
+    this.code = code || -32000;
+  }
+}
+
+class MockRpc {
+  /**
+   * `accounts` maps account ids to `{ balance, code }`, where `balance` is a decimal string
+   * and `code` the contract's wasm, if any.
+   */
+  constructor(accounts) {
+    this.accounts = new Map();
+    this.height = 1;
+    // Nanoseconds, as a `BigInt` since they don't fit in a number.
+    this.timestamp = BigInt(Date.now()) * 1000000n;
+    for (const [id, account] of Object.entries(accounts || {})) {
+      this.addAccount(id, account);
+    }
+  }
+
+  addAccount(id, { balance, code } = {}) {
+    const vm = new rust.VM({
+      current_account_id: id,
+      signer_account_id: id,
+      signer_account_pk: base58(Buffer.alloc(32)),
+      predecessor_account_id: id,
+      input: "",
+      block_index: this.height,
+      block_timestamp: 0,
+      account_balance: balance || "0",
+      account_locked_balance: "0",
+      storage_usage: 0,
+      attached_deposit: "0",
+      prepaid_gas: 0,
+      random_seed: base58(Buffer.alloc(32)),
+      is_view: false,
+      output_data_receivers: [],
+    });
+    const account = { id, vm, nonce: 0 };
+    if (code) {
+      this.deploy(account, code);
+    }
+    this.accounts.set(id, account);
+    return account;
+  }
+
+  account(id) {
+    const account = this.accounts.get(id);
+    if (!account) {
+      throw new RpcError(`Account ${id} doesn't exist`);
+    }
+    return account;
+  }
+
+  deploy(account, code) {
+    let memory = new WebAssembly.Memory({ initial: 17, maximum: 2048 });
+    const module = new WebAssembly.Module(code);
+    for (const { module: from, name } of WebAssembly.Module.imports(module)) {
+      if (from !== "env" || !(name === "memory" || HOST_FUNCTIONS.includes(name))) {
+        throw new RpcError(`Contract imports ${from}.${name}, which isn't a host function`);
+      }
+    }
+    const env = { memory };
+    for (const name of HOST_FUNCTIONS) {
+      env[name] = (...args) => account.vm[name](...args);
+    }
+    const instance = new WebAssembly.Instance(module, { env });
+    account.code = Buffer.from(code);
+    account.instance = instance;
+    account.memory = instance.exports.memory || memory;
+  }
+
+  blockHash(height) {
+    return base58(sha256(Buffer.from(String(height))));
+  }
+
+  nextBlock() {
+    this.height++;
+    this.timestamp += 1000000000n;
+  }
+
+  // Runs `method` on `account`, with the memory shims pointing at its contract.
+  run(account, method, args, opts, isView) {
+    if (!account.instance || typeof account.instance.exports[method] !== "function") {
+      throw new RpcError(`Contract ${account.id} has no method ${method}`);
+    }
+    const I8 = () => new Uint8Array(account.memory.buffer);
+    global.fits_memory = (offset, len) => Number(offset) + Number(len) <= I8().length;
+    global.read_memory = (offset, buffer) => buffer.set(I8().slice(Number(offset), Number(offset) + buffer.length));
+    global.read_memory_u8 = (offset) => I8()[Number(offset)];
+    global.write_memory = (offset, buffer) => I8().set(buffer, Number(offset));
+
+    const vm = account.vm;
+    vm.set_block_index(BigInt(this.height));
+    vm.set_block_timestamp(this.timestamp);
+    vm.begin_call(method, args.toString("utf8"), opts, isView);
+    let error;
+    try {
+      account.instance.exports[method]();
+    } catch (e) {
+      error = e instanceof Error ? e.message : String(e);
+    }
+    return vm.end_call(error);
+  }
+
+  balance(account) {
+    return BigInt(account.vm.outcome().balance);
+  }
+
+  setBalance(account, balance) {
+    if (balance < 0n) {
+      throw new RpcError(`Account ${account.id} doesn't have enough balance`);
+    }
+    account.vm.set_account_balance(balance.toString());
+  }
+
+  query(params) {
+    if (Array.isArray(params)) {
+      const [path, data] = params;
+      const [kind, account_id, arg] = path.split("/");
+      const request_type = { account: "view_account", access_key: "view_access_key", contract: "view_state", call: "call_function" }[kind];
+      // The arguments of calls are base58 encoded in the path form.
+      params = { request_type, account_id, method_name: arg, args_base64: unbase58(data || "").toString("base64"), prefix_base64: "" };
+    }
+    const account = this.account(params.account_id);
+    const block = { block_height: this.height, block_hash: this.blockHash(this.height) };
+    switch (params.request_type) {
+      case "view_account": {
+        const outcome = account.vm.outcome();
+        return {
+          amount: outcome.balance,
+          locked: "0",
+          code_hash: base58(account.code ? sha256(account.code) : Buffer.alloc(32)),
+          storage_usage: outcome.storage_usage,
+          storage_paid_at: 0,
+          ...block,
+        };
+      }
+      case "view_access_key":
+        return { nonce: account.nonce, permission: "FullAccess", ...block };
+      case "view_state": {
+        const prefix = Buffer.from(params.prefix_base64 || "", "base64");
+        const values = account.vm.storage_entries()
+          .map(([key, value]) => [Buffer.from(key), Buffer.from(value)])
+          .filter(([key]) => key.slice(0, prefix.length).equals(prefix))
+          .map(([key, value]) => ({ key: key.toString("base64"), value: value.toString("base64"), proof: [] }));
+        return { values, proof: [], ...block };
+      }
+      case "call_function": {
+        const args = Buffer.from(params.args_base64 || "", "base64");
+        const outcome = this.run(account, params.method_name, args, { predecessor: account.id }, true);
+        if (outcome.error) {
+          return { error: outcome.error, logs: outcome.logs, ...block };
+        }
+        const data = outcome.return_data && outcome.return_data.Value;
+        return { result: Array.from(data || []), logs: outcome.logs, ...block };
+      }
+      default:
+        throw new RpcError(`Unsupported query ${params.request_type}`);
+    }
+  }
+
+  broadcast_tx_commit([signedTx]) {
+    const tx = decodeTransaction(Buffer.from(signedTx, "base64"));
+    const signer = this.account(tx.signer_id);
+    signer.nonce = Math.max(signer.nonce, Number(tx.nonce));
+    this.nextBlock();
+    const logs = [];
+    let status = { SuccessValue: "" };
+    let gas_burnt = 0;
+    for (const action of tx.actions) {
+      if (action.CreateAccount) {
+        if (this.accounts.has(tx.receiver_id)) {
+          status = { Failure: { ActionError: { kind: { AccountAlreadyExists: { account_id: tx.receiver_id } } } } };
+          break;
+        }
+        this.addAccount(tx.receiver_id);
+      } else if (action.DeployContract) {
+        this.deploy(this.account(tx.receiver_id), action.DeployContract.code);
+      } else if (action.Transfer) {
+        const receiver = this.account(tx.receiver_id);
+        this.setBalance(signer, this.balance(signer) - action.Transfer.deposit);
+        this.setBalance(receiver, this.balance(receiver) + action.Transfer.deposit);
+      } else if (action.FunctionCall) {
+        const { method_name, args, gas, deposit } = action.FunctionCall;
+        const receiver = this.account(tx.receiver_id);
+        this.setBalance(signer, this.balance(signer) - deposit);
+        const opts = { signer: tx.signer_id, predecessor: tx.signer_id, deposit: deposit.toString(), gas: Number(gas) };
+        const outcome = this.run(receiver, method_name, args, opts, false);
+        logs.push(...outcome.logs);
+        gas_burnt += outcome.burnt_gas;
+        if (outcome.error) {
+          // The deposit goes back to the signer.
+          this.setBalance(signer, this.balance(signer) + deposit);
+          status = { Failure: { ActionError: { kind: { FunctionCallError: { HostError: outcome.error } } } } };
+          break;
+        }
+        // The attached deposit stays with the receiver.
+        this.setBalance(receiver, BigInt(outcome.balance));
+        const data = outcome.return_data && outcome.return_data.Value;
+        status = { SuccessValue: Buffer.from(data || []).toString("base64") };
+      }
+    }
+    const receipt_id = base58(sha256(Buffer.from(tx.hash + ":receipt")));
+    return {
+      status,
+      transaction: { hash: tx.hash, signer_id: tx.signer_id, receiver_id: tx.receiver_id, nonce: Number(tx.nonce) },
+      transaction_outcome: {
+        id: tx.hash,
+        block_hash: this.blockHash(this.height),
+        outcome: { logs: [], receipt_ids: [receipt_id], gas_burnt: 0, status: { SuccessReceiptId: receipt_id } },
+      },
+      receipts_outcome: [{
+        id: receipt_id,
+        block_hash: this.blockHash(this.height),
+        outcome: { logs, receipt_ids: [], gas_burnt, status },
+      }],
+    };
+  }
+
+  block() {
+    return {
+      author: "mock",
+      header: {
+        height: this.height,
+        hash: this.blockHash(this.height),
+        prev_hash: this.blockHash(this.height - 1),
+        timestamp: Number(this.timestamp),
+        gas_price: "0",
+      },
+      chunks: [],
+    };
+  }
+
+  status() {
+    return {
+      chain_id: "mock",
+      sync_info: {
+        latest_block_hash: this.blockHash(this.height),
+        latest_block_height: this.height,
+        latest_block_time: new Date(Number(this.timestamp / 1000000n)).toISOString(),
+        syncing: false,
+      },
+    };
+  }
+
+  // Answers a JSON-RPC request object.
+  handle({ id, method, params }) {
+    try {
+      if (!["query", "broadcast_tx_commit", "block", "status"].includes(method)) {
+        throw new RpcError(`Method ${method} not found`, -32601);
+      }
+      return { jsonrpc: "2.0", id, result: this[method](params) };
+    } catch (e) {
+      return { jsonrpc: "2.0", id, error: { code: e.code || -32000, message: e.message, data: e.message } };
+    }
+  }
+
+  // Serves the RPC over HTTP, e.g. `new MockRpc(accounts).listen(3030)`.
+  listen(port) {
+    const server = http.createServer((req, res) => {
+      let body = "";
+      req.on("data", chunk => body += chunk);
+      req.on("end", () => {
+        let response;
+        try {
+          response = this.handle(JSON.parse(body));
+        } catch (e) {
+          response = { jsonrpc: "2.0", id: null, error: { code: -32700, message: "Parse error" } };
+        }
+        res.writeHead(200, { "Content-Type": "application/json" });
+        res.end(JSON.stringify(response));
+      });
+    });
+    return server.listen(port);
+  }
+}
+
+module.exports = { MockRpc, decodeTransaction, base58 };
@@ -1,4 +1,4 @@
-mod mock;
+pub mod mock;
 mod utils;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
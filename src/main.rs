@@ -0,0 +1,215 @@
+//! Command line interface for running contracts against the mock VM, e.g. to reproduce bug
+//! reports outside of as-pect.
+
+use parity_wasm::elements::{External, FunctionType, Internal, Module, Type};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::{env, fs, process};
+use wasm_mock_vm::mock::context::parse_context_json;
+use wasm_mock_vm::mock::context_builder::ContextBuilder;
+use wasm_mock_vm::mock::replay::ReplayBundle;
+use wasm_mock_vm::mock::snapshot;
+use wasm_mock_vm::mock::vm_logic::{CallOptions, VM};
+
+const USAGE: &str = "Usage:
+    wasm-mock-vm run <contract.wasm> <method> [--args <json>] [--context <context.json>] [--state <state.json>]
+                     [--snapshot <snapshot.json> [--update] [--gas-tolerance <fraction>]] [--coverage <lcov.info>]
+                     [--check-determinism]
+    wasm-mock-vm view <contract.wasm> <method> [--args <json>] [--context <context.json>] [--state <state.json>]
+                      [--check-determinism]
+    wasm-mock-vm estimate <contract.wasm> <method> [--args <json>] [--context <context.json>] [--state <state.json>]
+    wasm-mock-vm inspect <contract.wasm>
+    wasm-mock-vm replay <bundle.json>
+
+The context defaults to the one of `ContextBuilder`, which is also in the `context.json` shipped
+with the package. The state is a JSON object mapping storage keys to values. With --snapshot, the
+outcome is compared with the snapshot file, which is written instead if it doesn't exist or
+--update is given. With --coverage, the code coverage of the method is written to the given file
+in the lcov format. With --check-determinism, the method runs twice and fails if the runs differ.";
+
+/// Options that don't take a value.
+const FLAGS: &[&str] = &["update", "check-determinism"];
+
+/// Positional arguments and `--name value` options of a command.
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut res = Args { positional: vec![], options: HashMap::new() };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") && FLAGS.contains(&&arg[2..]) {
+                res.options.insert(arg[2..].to_string(), String::new());
+            } else if arg.starts_with("--") {
+                let value = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                res.options.insert(arg[2..].to_string(), value.clone());
+            } else {
+                res.positional.push(arg.clone());
+            }
+        }
+        Ok(res)
+    }
+
+    fn positional(&self, index: usize, name: &str) -> Result<&str, String> {
+        self.positional.get(index).map(String::as_str).ok_or_else(|| format!("Missing <{}>", name))
+    }
+}
+
+fn read_json(path: &str) -> Result<Value, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("Invalid JSON in {}: {}", path, e))
+}
+
+/// Sets up a VM with the contract, context and state given to `run`, `view` and `estimate`, and
+/// returns it with the method to call and its input.
+fn load(args: &Args) -> Result<(VM, String, Vec<u8>), String> {
+    let code_path = args.positional(0, "contract.wasm")?;
+    let method = args.positional(1, "method")?;
+    let code = fs::read(code_path).map_err(|e| format!("Can't read {}: {}", code_path, e))?;
+    let mut context = match args.options.get("context") {
+        Some(path) => parse_context_json(&read_json(path)?)?,
+        None => ContextBuilder::default().build_context()?,
+    };
+    let input = match args.options.get("args") {
+        Some(json) => json.clone().into_bytes(),
+        None => context.input.clone(),
+    };
+
+    let mut state = vec![];
+    if let Some(path) = args.options.get("state") {
+        let json = read_json(path)?;
+        for (key, value) in json.as_object().ok_or("The state must be an object")? {
+            let value = value.as_str().ok_or_else(|| format!("The value of {} must be a string", key))?;
+            state.push((key.clone().into_bytes(), value.as_bytes().to_vec()));
+        }
+    }
+    // The loaded state counts towards the storage usage, like the contract's own writes.
+    context.storage_usage += state.iter().map(|(key, value)| (key.len() + value.len()) as u64).sum::<u64>();
+
+    let mut vm = VM::from_context(context);
+    vm.ext_mut().fake_trie.extend(state);
+    vm.load_contract(code);
+    Ok((vm, method.to_string(), input))
+}
+
+fn run(args: &Args, is_view: bool) -> Result<Value, String> {
+    let (mut vm, method, input) = load(args)?;
+    if args.options.contains_key("coverage") {
+        vm.instrument_contract(args.positional(0, "contract.wasm")?)?;
+    }
+    vm.set_determinism_check(args.options.contains_key("check-determinism"));
+    vm.checkpoint("before".to_string());
+    let res = vm.execute(&method, input, CallOptions::default(), is_view)?;
+    if let (Some(path), Some(coverage)) = (args.options.get("coverage"), vm.coverage()) {
+        fs::write(path, coverage.to_lcov(".ts")).map_err(|e| format!("Can't write {}: {}", path, e))?;
+    }
+    let mut output = res.to_json();
+
+    if let Some(path) = args.options.get("snapshot") {
+        let gas_tolerance = match args.options.get("gas-tolerance") {
+            Some(t) => t.parse().map_err(|_| format!("Invalid gas tolerance {}", t))?,
+            None => 0.0,
+        };
+        let actual = vm.snapshot_value(output.clone(), "before")?;
+        let diffs = snapshot::check_file(Path::new(path), &actual, args.options.contains_key("update"), gas_tolerance)?;
+        if !diffs.is_empty() {
+            let lines: Vec<String> = diffs.iter()
+                .map(|d| format!("  {}: expected {}, found {}", d.path, d.expected, d.actual))
+                .collect();
+            return Err(format!("Outcome doesn't match snapshot {}:\n{}", path, lines.join("\n")));
+        }
+    }
+    output["receipts"] = serde_json::to_value(vm.ext().get_receipt_create_calls()).map_err(|e| e.to_string())?;
+    Ok(output)
+}
+
+fn estimate(args: &Args) -> Result<Value, String> {
+    let (mut vm, method, input) = load(args)?;
+    let context = vm.context().clone();
+    let estimate = vm.estimate_method_gas(&method, input, context)?;
+    serde_json::to_value(estimate).map_err(|e| e.to_string())
+}
+
+fn signature(ty: &FunctionType) -> String {
+    let params: Vec<String> = ty.params().iter().map(|p| p.to_string()).collect();
+    match ty.return_type() {
+        Some(ret) => format!("({}) -> {}", params.join(", "), ret),
+        None => format!("({})", params.join(", ")),
+    }
+}
+
+fn inspect(args: &Args) -> Result<Value, String> {
+    let path = args.positional(0, "contract.wasm")?;
+    let module: Module = parity_wasm::deserialize_file(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+    let types: Vec<&FunctionType> = module.type_section()
+        .map(|s| s.types().iter().map(|Type::Function(ty)| ty).collect())
+        .unwrap_or_default();
+    // Imported functions come first in the function index space.
+    let mut functions: Vec<u32> = vec![];
+    let mut imports = vec![];
+    for entry in module.import_section().map(|s| s.entries()).unwrap_or_default() {
+        let (kind, signature) = match entry.external() {
+            External::Function(ty) => {
+                functions.push(*ty);
+                ("function", types.get(*ty as usize).map(|ty| signature(ty)))
+            }
+            External::Table(_) => ("table", None),
+            External::Memory(_) => ("memory", None),
+            External::Global(_) => ("global", None),
+        };
+        imports.push(json!({ "module": entry.module(), "name": entry.field(), "kind": kind, "signature": signature }));
+    }
+    functions.extend(module.function_section().map(|s| s.entries()).unwrap_or_default().iter().map(|f| f.type_ref()));
+    let mut exports = vec![];
+    for entry in module.export_section().map(|s| s.entries()).unwrap_or_default() {
+        let (kind, signature) = match entry.internal() {
+            Internal::Function(index) => {
+                let ty = functions.get(*index as usize).and_then(|ty| types.get(*ty as usize));
+                ("function", ty.map(|ty| signature(ty)))
+            }
+            Internal::Table(_) => ("table", None),
+            Internal::Memory(_) => ("memory", None),
+            Internal::Global(_) => ("global", None),
+        };
+        exports.push(json!({ "name": entry.field(), "kind": kind, "signature": signature }));
+    }
+    Ok(json!({ "imports": imports, "exports": exports }))
+}
+
+/// Exits with 2 when the replay doesn't match the recorded outcome.
+fn replay(args: &Args) -> Result<Value, String> {
+    let (bundle, code) = ReplayBundle::load(Path::new(args.positional(0, "bundle.json")?))?;
+    let replay = VM::replay(&bundle, code)?;
+    let output = json!({ "outcome": replay.result, "mismatches": replay.mismatches });
+    if !replay.mismatches.is_empty() {
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+        process::exit(2);
+    }
+    Ok(output)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let res = match args.split_first() {
+        Some((command, rest)) => Args::parse(rest).and_then(|rest| match command.as_str() {
+            "run" => run(&rest, false),
+            "view" => run(&rest, true),
+            "estimate" => estimate(&rest),
+            "inspect" => inspect(&rest),
+            "replay" => replay(&rest),
+            _ => Err(format!("Unknown command {}", command)),
+        }),
+        None => Err("Missing command".to_string()),
+    };
+    match res {
+        Ok(output) => println!("{}", serde_json::to_string_pretty(&output).unwrap()),
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use near_vm_logic::{HostError, MemoryLike, VMLogicError};
+
+pub const MIN_ACCOUNT_ID_LEN: usize = 2;
+pub const MAX_ACCOUNT_ID_LEN: usize = 64;
+
+/// Host functions that take an account id, with the indices of its length and pointer arguments.
+const ACCOUNT_ID_ARGS: &[(&str, usize, usize)] = &[
+    ("promise_create", 0, 1),
+    ("promise_then", 1, 2),
+    ("promise_batch_create", 0, 1),
+    ("promise_batch_then", 1, 2),
+    ("promise_batch_action_add_key_with_function_call", 5, 6),
+    ("promise_batch_action_delete_account", 1, 2),
+];
+
+/// Whether `account_id` follows nearcore's rules: 2 to 64 characters, made of lowercase letters
+/// and digits separated by single `.`, `-` or `_`, i.e. it matches
+/// `^(([a-z\d]+[\-_])*[a-z\d]+\.)*([a-z\d]+[\-_])*[a-z\d]+$`.
+pub fn is_valid_account_id(account_id: &str) -> bool {
+    if account_id.len() < MIN_ACCOUNT_ID_LEN || account_id.len() > MAX_ACCOUNT_ID_LEN {
+        return false;
+    }
+    let mut last_was_separator = true;
+    for c in account_id.chars() {
+        match c {
+            'a'..='z' | '0'..='9' => last_was_separator = false,
+            '.' | '-' | '_' if !last_was_separator => last_was_separator = true,
+            _ => return false,
+        }
+    }
+    !last_was_separator
+}
+
+pub fn validate_account_id(account_id: &str) -> Result<(), String> {
+    if is_valid_account_id(account_id) {
+        Ok(())
+    } else {
+        Err(format!(
+            "InvalidAccountId: {:?} must be {} to {} lowercase letters and digits, separated by single '.', '-' or '_'",
+            account_id, MIN_ACCOUNT_ID_LEN, MAX_ACCOUNT_ID_LEN,
+        ))
+    }
+}
+
+/// Checks the account ids the host function `name` is called with, since `VMLogic` only checks
+/// that they are UTF-8. Ids outside of `memory` are left for `VMLogic` to reject.
+pub fn check_host_call(name: &str, args: &[u64], memory: &dyn MemoryLike) -> Option<VMLogicError> {
+    let &(_, len, ptr) = ACCOUNT_ID_ARGS.iter().find(|(function, _, _)| *function == name)?;
+    let (len, ptr) = (*args.get(len)?, *args.get(ptr)?);
+    if !memory.fits_memory(ptr, len) {
+        return None;
+    }
+    let valid = len as usize <= MAX_ACCOUNT_ID_LEN && {
+        let mut account_id = vec![0; len as usize];
+        memory.read_memory(ptr, &mut account_id);
+        std::str::from_utf8(&account_id).map_or(false, is_valid_account_id)
+    };
+    if valid {
+        None
+    } else {
+        Some(VMLogicError::HostError(HostError::InvalidAccountId))
+    }
+}
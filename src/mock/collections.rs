@@ -0,0 +1,157 @@
+use crate::utils::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// What near-runtime-ts puts between the prefix of a collection and the key or index of an element.
+pub const ELEMENT_SEPARATOR: &str = "::";
+/// Suffix of the key holding the length of a `PersistentVector`.
+pub const LENGTH_SUFFIX: &str = ":len";
+/// Suffixes of the keys holding the indices of the first and last elements of a `PersistentDeque`.
+pub const FRONT_SUFFIX: &str = ":front";
+pub const BACK_SUFFIX: &str = ":back";
+
+/// How values are rendered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderMode {
+    /// UTF-8 strings, or `0x` prefixed hex for values that aren't UTF-8.
+    Utf8,
+    /// Parsed JSON, falling back to `Utf8` for values that aren't JSON.
+    Json,
+    /// `0x` prefixed hex.
+    Hex,
+}
+
+impl RenderMode {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "utf8" => Ok(RenderMode::Utf8),
+            "json" => Ok(RenderMode::Json),
+            "hex" => Ok(RenderMode::Hex),
+            _ => Err(format!("Unknown render mode {}, expected utf8, json or hex", s)),
+        }
+    }
+
+    pub fn render(self, bytes: &[u8]) -> Value {
+        match self {
+            RenderMode::Utf8 => Value::String(display_bytes(bytes)),
+            RenderMode::Json => serde_json::from_slice(bytes).unwrap_or_else(|_| Value::String(display_bytes(bytes))),
+            RenderMode::Hex => Value::String(to_hex(bytes)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+}
+
+#[derive(Serialize)]
+pub struct Item {
+    pub index: i64,
+    pub value: Value,
+}
+
+/// The elements stored under a prefix, as near-runtime-ts lays them out.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Collection {
+    /// `PersistentMap`, and any other prefix without a length or indices: `prefix::key`.
+    Map { prefix: String, entries: Vec<Entry> },
+    /// `PersistentVector`: `prefix:len`, and the elements at `prefix::index`.
+    Vector { prefix: String, length: i64, items: Vec<Item> },
+    /// `PersistentDeque`: `prefix:front` and `prefix:back`, which can be negative, and the elements
+    /// at `prefix::index`.
+    Deque { prefix: String, front: Option<i64>, back: Option<i64>, items: Vec<Item> },
+}
+
+/// The storage of a contract grouped by the collections the keys belong to, see `StorageView::of`.
+#[derive(Serialize)]
+pub struct StorageView {
+    pub collections: Vec<Collection>,
+    /// Keys that aren't part of a collection, or that a collection can't account for, like
+    /// elements of a vector that aren't indices.
+    pub other: Vec<Entry>,
+}
+
+/// What was found under a prefix.
+#[derive(Default)]
+struct Group<'a> {
+    length: Option<i64>,
+    front: Option<i64>,
+    back: Option<i64>,
+    /// Element keys without the prefix and separator, with their values.
+    elements: Vec<(&'a str, &'a [u8])>,
+}
+
+/// Reads the length or an index, which near-runtime-ts stores as decimal strings.
+fn parse_number(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.trim().parse().ok()
+}
+
+impl StorageView {
+    /// Groups the keys of `trie` by prefix. Keys are read as UTF-8; the others go to `other`, like
+    /// the keys without a `::`. A prefix is a deque if it has a front or a back, a vector if it has
+    /// a length, and a map otherwise.
+    pub fn of(trie: &HashMap<Vec<u8>, Vec<u8>>, mode: RenderMode) -> Self {
+        let mut entries: Vec<_> = trie.iter().collect();
+        entries.sort();
+        let mut groups: BTreeMap<&str, Group> = BTreeMap::new();
+        let mut other = vec![];
+        for (key, value) in entries {
+            let text = match std::str::from_utf8(key) {
+                Ok(text) => text,
+                Err(_) => {
+                    other.push(Entry { key: display_bytes(key), value: mode.render(value) });
+                    continue;
+                }
+            };
+            if let Some(at) = text.find(ELEMENT_SEPARATOR) {
+                let group = groups.entry(&text[..at]).or_default();
+                group.elements.push((&text[at + ELEMENT_SEPARATOR.len()..], value));
+                continue;
+            }
+            let meta = [LENGTH_SUFFIX, FRONT_SUFFIX, BACK_SUFFIX].iter()
+                .find(|suffix| text.len() > suffix.len() && text.ends_with(*suffix))
+                .and_then(|suffix| Some((*suffix, parse_number(value)?)));
+            match meta {
+                Some((suffix, number)) => {
+                    let group = groups.entry(&text[..text.len() - suffix.len()]).or_default();
+                    match suffix {
+                        LENGTH_SUFFIX => group.length = Some(number),
+                        FRONT_SUFFIX => group.front = Some(number),
+                        _ => group.back = Some(number),
+                    }
+                }
+                None => other.push(Entry { key: text.to_string(), value: mode.render(value) }),
+            }
+        }
+
+        let mut collections = vec![];
+        for (prefix, group) in groups {
+            let element_key = |key: &str| format!("{}{}{}", prefix, ELEMENT_SEPARATOR, key);
+            if group.front.is_none() && group.back.is_none() && group.length.is_none() {
+                let entries = group.elements.iter()
+                    .map(|(key, value)| Entry { key: key.to_string(), value: mode.render(value) })
+                    .collect();
+                collections.push(Collection::Map { prefix: prefix.to_string(), entries });
+                continue;
+            }
+            let mut items = vec![];
+            for (key, value) in group.elements {
+                match key.parse::<i64>() {
+                    Ok(index) => items.push(Item { index, value: mode.render(value) }),
+                    Err(_) => other.push(Entry { key: element_key(key), value: mode.render(value) }),
+                }
+            }
+            items.sort_by_key(|item| item.index);
+            let prefix = prefix.to_string();
+            collections.push(match group.length {
+                Some(length) if group.front.is_none() && group.back.is_none() => Collection::Vector { prefix, length, items },
+                _ => Collection::Deque { prefix, front: group.front, back: group.back, items },
+            });
+        }
+        StorageView { collections, other }
+    }
+}
@@ -0,0 +1,99 @@
+use crate::mock::account_id::validate_account_id;
+use crate::mock::public_key::PublicKey;
+use crate::utils::*;
+use js_sys::{Array, Object, Reflect, Uint8Array};
+use near_vm_logic::types::{AccountId, PromiseResult};
+use near_vm_logic::VMContext;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+/// Fields of `VMContext` that hold a `u128`.
+const BALANCE_FIELDS: [&str; 3] = ["account_balance", "account_locked_balance", "attached_deposit"];
+
+/// Rewrites a public key given in any form `PublicKey::parse` accepts as the base58 of the
+/// serialized key, which is what `VMContext` reads.
+fn normalize_public_key(key: &str) -> Result<String, String> {
+    Ok(bs58::encode(PublicKey::parse(key)?.to_bytes()).into_string())
+}
+
+/// Reads a `VMContext` from a JS object, e.g. the contents of `context.json`.
+///
+/// Balances can be given as decimal strings, numbers or `BigInt`s, and `signer_account_pk` as
+/// `ed25519:<base58>` or in the other forms `PublicKey::parse` accepts.
+pub fn parse_context(value: JsValue) -> Result<VMContext, JsValue> {
+    let obj = Object::assign(&Object::new(), value.dyn_ref::<Object>().ok_or("Context must be an object")?);
+    let key = JsValue::from_str("signer_account_pk");
+    if let Some(pk) = Reflect::get(&obj, &key)?.as_string() {
+        let pk = normalize_public_key(&pk).map_err(|e| js_sys::Error::new(&e))?;
+        Reflect::set(&obj, &key, &JsValue::from_str(&pk))?;
+    }
+    let mut balances = [0u128; 3];
+    for (field, balance) in BALANCE_FIELDS.iter().zip(balances.iter_mut()) {
+        let key = JsValue::from_str(field);
+        let value = Reflect::get(&obj, &key)?;
+        if !value.is_undefined() {
+            *balance = u128_from_js(&value)?;
+        }
+        Reflect::set(&obj, &key, &JsValue::from_f64(0.0))?;
+    }
+    let mut context: VMContext = serde_wasm_bindgen::from_value(obj.into())?;
+    context.account_balance = balances[0];
+    context.account_locked_balance = balances[1];
+    context.attached_deposit = balances[2];
+    validate_context(&context).map_err(|e| js_sys::Error::new(&e))?;
+    Ok(context)
+}
+
+/// Checks the account ids of a context.
+pub fn validate_context(context: &VMContext) -> Result<(), String> {
+    validate_account_id(&context.current_account_id)?;
+    validate_account_id(&context.signer_account_id)?;
+    validate_account_id(&context.predecessor_account_id)?;
+    context.output_data_receivers.iter().try_for_each(|receiver| validate_account_id(receiver))
+}
+
+/// Reads an account id from a JS string, and checks it with `validate_account_id`.
+pub fn parse_account_id(value: JsValue) -> Result<AccountId, JsValue> {
+    let account_id = value.as_string().ok_or("Account ids must be strings")?;
+    validate_account_id(&account_id).map_err(|e| js_sys::Error::new(&e))?;
+    Ok(account_id)
+}
+
+/// Reads a `VMContext` from parsed JSON, in the same format as `parse_context`.
+pub fn parse_context_json(value: &serde_json::Value) -> Result<VMContext, String> {
+    let mut obj = value.as_object().ok_or("Context must be an object")?.clone();
+    if let Some(serde_json::Value::String(pk)) = obj.get("signer_account_pk") {
+        let pk = normalize_public_key(pk)?;
+        obj.insert("signer_account_pk".to_string(), pk.into());
+    }
+    let mut balances = [0u128; 3];
+    for (field, balance) in BALANCE_FIELDS.iter().zip(balances.iter_mut()) {
+        if let Some(value) = obj.insert(field.to_string(), 0.into()) {
+            let s = value.as_str().map(String::from).unwrap_or_else(|| value.to_string());
+            *balance = s.trim().parse().map_err(|_| format!("Invalid u128: {}", s))?;
+        }
+    }
+    let mut context: VMContext = serde_json::from_value(obj.into()).map_err(|e| e.to_string())?;
+    context.account_balance = balances[0];
+    context.account_locked_balance = balances[1];
+    context.attached_deposit = balances[2];
+    validate_context(&context)?;
+    Ok(context)
+}
+
+/// Reads the results of the promises a callback depends on. Each entry is the value of a
+/// successful promise, as a string or `Uint8Array`, or `null` for a failed one.
+pub fn parse_promise_results(value: JsValue) -> Result<Vec<PromiseResult>, JsValue> {
+    let results = value.dyn_ref::<Array>().ok_or("Promise results must be an array")?;
+    results.iter().map(|result| {
+        if result.is_null() || result.is_undefined() {
+            Ok(PromiseResult::Failed)
+        } else if let Some(s) = result.as_string() {
+            Ok(PromiseResult::Successful(s.into_bytes()))
+        } else if let Some(bytes) = result.dyn_ref::<Uint8Array>() {
+            Ok(PromiseResult::Successful(bytes.to_vec()))
+        } else {
+            Err(js_sys::Error::new("Promise results must be strings, Uint8Arrays or null").into())
+        }
+    }).collect()
+}
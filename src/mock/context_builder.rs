@@ -0,0 +1,306 @@
+use crate::mock::context::validate_context;
+use crate::mock::public_key::PublicKey;
+use crate::utils::*;
+use near_vm_logic::types::{AccountId, Balance, BlockIndex, Gas, StorageUsage};
+use near_vm_logic::VMContext;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+/// A context in the format of `context.json`, which `parse_context` and `parse_context_json` read.
+#[derive(Serialize)]
+struct ContextJson<'a> {
+    current_account_id: &'a str,
+    signer_account_id: &'a str,
+    signer_account_pk: &'a str,
+    predecessor_account_id: &'a str,
+    input: &'a str,
+    block_index: BlockIndex,
+    block_timestamp: u64,
+    account_balance: String,
+    account_locked_balance: String,
+    storage_usage: StorageUsage,
+    attached_deposit: String,
+    prepaid_gas: Gas,
+    random_seed: &'a str,
+    is_view: bool,
+    output_data_receivers: &'a [AccountId],
+}
+
+/// Builds the `VMContext` of a call, starting from the defaults shipped in `context.json`.
+///
+/// The setters take the values as they are given and return the builder, so that they can be
+/// chained. Everything is checked when the context is built: account ids, the public key, and that
+/// view calls don't attach deposits. Values that can't be read, like balances that aren't numbers,
+/// are reported then too.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct ContextBuilder {
+    current_account_id: AccountId,
+    signer_account_id: AccountId,
+    /// In any form `PublicKey::parse` accepts.
+    signer_account_pk: String,
+    predecessor_account_id: AccountId,
+    input: String,
+    block_index: BlockIndex,
+    block_timestamp: u64,
+    account_balance: Balance,
+    account_locked_balance: Balance,
+    storage_usage: StorageUsage,
+    attached_deposit: Balance,
+    prepaid_gas: Gas,
+    /// Base58.
+    random_seed: String,
+    is_view: bool,
+    output_data_receivers: Vec<AccountId>,
+    /// Values the setters couldn't read.
+    errors: Vec<String>,
+}
+
+impl Default for ContextBuilder {
+    fn default() -> Self {
+        ContextBuilder {
+            current_account_id: "alice".to_string(),
+            signer_account_id: "bob".to_string(),
+            signer_account_pk: "ed25519:BvQELMs7F63G1fUzUTVewsTfKvRrrJM5ZJBRXbdXhenp".to_string(),
+            predecessor_account_id: "carol".to_string(),
+            input: r#"{ "arg1": 1 }"#.to_string(),
+            block_index: 10,
+            block_timestamp: 42,
+            account_balance: 2,
+            account_locked_balance: 1,
+            storage_usage: 12,
+            attached_deposit: 2,
+            prepaid_gas: 100_000_000_000_000,
+            random_seed: "HuxUynD5GdrcZ5MauxJuu74sGHgS6wLfCqqhQkLWK".to_string(),
+            is_view: false,
+            output_data_receivers: vec![],
+            errors: vec![],
+        }
+    }
+}
+
+impl ContextBuilder {
+    fn balance(&mut self, field: &str, value: &JsValue) -> Balance {
+        u128_from_js(value).unwrap_or_else(|e| {
+            let message: String = js_sys::Error::from(e).message().into();
+            self.errors.push(format!("{}: {}", field, message));
+            0
+        })
+    }
+
+    /// Lists what's wrong with the context, if anything.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = self.errors.clone();
+        let mut context = self.unchecked_context();
+        match PublicKey::parse(&self.signer_account_pk) {
+            Ok(key) => context.signer_account_pk = key.to_bytes(),
+            Err(e) => problems.push(format!("signer_account_pk: {}", e)),
+        }
+        if let Err(e) = bs58::decode(&self.random_seed).into_vec() {
+            problems.push(format!("random_seed: {} isn't base58: {}", self.random_seed, e));
+        }
+        if let Err(e) = validate_context(&context) {
+            problems.push(e);
+        }
+        if self.is_view && self.attached_deposit > 0 {
+            problems.push("View calls can't attach a deposit".to_string());
+        }
+        problems
+    }
+
+    /// The context without the public key and the random seed, which need checking.
+    fn unchecked_context(&self) -> VMContext {
+        VMContext {
+            current_account_id: self.current_account_id.clone(),
+            signer_account_id: self.signer_account_id.clone(),
+            signer_account_pk: vec![],
+            predecessor_account_id: self.predecessor_account_id.clone(),
+            input: self.input.clone().into_bytes(),
+            block_index: self.block_index,
+            block_timestamp: self.block_timestamp,
+            account_balance: self.account_balance,
+            account_locked_balance: self.account_locked_balance,
+            storage_usage: self.storage_usage,
+            attached_deposit: self.attached_deposit,
+            prepaid_gas: self.prepaid_gas,
+            random_seed: vec![],
+            is_view: self.is_view,
+            output_data_receivers: self.output_data_receivers.clone(),
+        }
+    }
+
+    /// Checks the context, see `problems`, and builds it.
+    pub fn build_context(&self) -> Result<VMContext, String> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            return Err(format!("Invalid context: {}", problems.join("; ")));
+        }
+        let mut context = self.unchecked_context();
+        context.signer_account_pk = PublicKey::parse(&self.signer_account_pk)?.to_bytes();
+        context.random_seed = bs58::decode(&self.random_seed).into_vec().map_err(|e| e.to_string())?;
+        Ok(context)
+    }
+
+    fn to_context_json(&self) -> ContextJson {
+        ContextJson {
+            current_account_id: &self.current_account_id,
+            signer_account_id: &self.signer_account_id,
+            signer_account_pk: &self.signer_account_pk,
+            predecessor_account_id: &self.predecessor_account_id,
+            input: &self.input,
+            block_index: self.block_index,
+            block_timestamp: self.block_timestamp,
+            account_balance: self.account_balance.to_string(),
+            account_locked_balance: self.account_locked_balance.to_string(),
+            storage_usage: self.storage_usage,
+            attached_deposit: self.attached_deposit.to_string(),
+            prepaid_gas: self.prepaid_gas,
+            random_seed: &self.random_seed,
+            is_view: self.is_view,
+            output_data_receivers: &self.output_data_receivers,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl ContextBuilder {
+    /// Starts from the defaults, the same as `context.json`.
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> ContextBuilder {
+        ContextBuilder::default()
+    }
+
+    /// A call of a view method: nothing attached, and the contract calls itself, like nearcore
+    /// does for `query` requests.
+    pub fn view_call(mut self) -> ContextBuilder {
+        self.is_view = true;
+        self.attached_deposit = 0;
+        self.signer_account_id = self.current_account_id.clone();
+        self.predecessor_account_id = self.current_account_id.clone();
+        self
+    }
+
+    /// A callback the contract scheduled with `promise_then`: the contract is its own predecessor,
+    /// and nothing is attached. The signer is still the one of the original transaction. Set the
+    /// results it depends on with `VM::set_promise_results`.
+    pub fn cross_contract_callback(mut self) -> ContextBuilder {
+        self.is_view = false;
+        self.attached_deposit = 0;
+        self.predecessor_account_id = self.current_account_id.clone();
+        self
+    }
+
+    /// A transaction sent from a wallet: the signer calls the contract directly.
+    pub fn wallet_transaction(mut self) -> ContextBuilder {
+        self.is_view = false;
+        self.predecessor_account_id = self.signer_account_id.clone();
+        self
+    }
+
+    pub fn current_account_id(mut self, account_id: String) -> ContextBuilder {
+        self.current_account_id = account_id;
+        self
+    }
+
+    pub fn signer_account_id(mut self, account_id: String) -> ContextBuilder {
+        self.signer_account_id = account_id;
+        self
+    }
+
+    /// Accepts the forms `PublicKey::parse` does, e.g. `ed25519:<base58>`.
+    pub fn signer_account_pk(mut self, key: String) -> ContextBuilder {
+        self.signer_account_pk = key;
+        self
+    }
+
+    pub fn predecessor_account_id(mut self, account_id: String) -> ContextBuilder {
+        self.predecessor_account_id = account_id;
+        self
+    }
+
+    /// Usually the JSON of the method's arguments.
+    pub fn input(mut self, input: String) -> ContextBuilder {
+        self.input = input;
+        self
+    }
+
+    pub fn block_index(mut self, block_index: u64) -> ContextBuilder {
+        self.block_index = block_index;
+        self
+    }
+
+    pub fn block_timestamp(mut self, block_timestamp: u64) -> ContextBuilder {
+        self.block_timestamp = block_timestamp;
+        self
+    }
+
+    /// Accepts a decimal string, a number or a `BigInt`.
+    pub fn account_balance(mut self, balance: JsValue) -> ContextBuilder {
+        self.account_balance = self.balance("account_balance", &balance);
+        self
+    }
+
+    /// Accepts a decimal string, a number or a `BigInt`.
+    pub fn account_locked_balance(mut self, balance: JsValue) -> ContextBuilder {
+        self.account_locked_balance = self.balance("account_locked_balance", &balance);
+        self
+    }
+
+    pub fn storage_usage(mut self, storage_usage: u64) -> ContextBuilder {
+        self.storage_usage = storage_usage;
+        self
+    }
+
+    /// Accepts a decimal string, a number or a `BigInt`.
+    pub fn attached_deposit(mut self, deposit: JsValue) -> ContextBuilder {
+        self.attached_deposit = self.balance("attached_deposit", &deposit);
+        self
+    }
+
+    pub fn prepaid_gas(mut self, gas: u64) -> ContextBuilder {
+        self.prepaid_gas = gas;
+        self
+    }
+
+    /// Base58.
+    pub fn random_seed(mut self, seed: String) -> ContextBuilder {
+        self.random_seed = seed;
+        self
+    }
+
+    pub fn is_view(mut self, is_view: bool) -> ContextBuilder {
+        self.is_view = is_view;
+        self
+    }
+
+    /// Takes an array of account ids.
+    pub fn output_data_receivers(mut self, receivers: Box<[JsValue]>) -> ContextBuilder {
+        self.output_data_receivers = receivers.iter()
+            .filter_map(|receiver| {
+                let account_id = receiver.as_string();
+                if account_id.is_none() {
+                    self.errors.push("output_data_receivers: account ids must be strings".to_string());
+                }
+                account_id
+            })
+            .collect();
+        self
+    }
+
+    /// Lists what's wrong with the context, or returns an empty array.
+    pub fn validate(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.problems())?)
+    }
+
+    /// Checks the context and returns it as an object in the format of `context.json`, which
+    /// `new VM(context)` and `set_context` accept. The builder can be used again afterwards.
+    pub fn build(&self) -> Result<JsValue, JsValue> {
+        self.build_context().map_err(|e| js_sys::Error::new(&e))?;
+        Ok(serde_wasm_bindgen::to_value(&self.to_context_json())?)
+    }
+
+    /// The context as the JSON of `context.json`, without checking it.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.to_context_json()).expect("Contexts can be serialized")
+    }
+}
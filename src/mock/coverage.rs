@@ -0,0 +1,421 @@
+use parity_wasm::elements::{
+    External, FunctionType, ImportCountType, ImportEntry, ImportSection, Instruction, Internal, Module,
+    Section, Type, TypeSection, ValueType,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use wasm_bindgen::prelude::*;
+
+/// Module and name of the function instrumented contracts call with the id of each block they
+/// enter.
+pub const HIT_MODULE: &str = "coverage";
+pub const HIT_FUNCTION: &str = "hit";
+
+/// Where a counter sits in a function.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockKind {
+    /// The start of the function.
+    Entry,
+    /// The start of the `then` branch of an `if`.
+    If,
+    /// The start of an `else` branch.
+    Else,
+    /// The start of a loop body, counted at every iteration.
+    Loop,
+    /// The code after a `block`, `if` or `loop`.
+    Join,
+    /// The code after a `br_if` that wasn't taken.
+    Fallthrough,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Block {
+    pub kind: BlockKind,
+    /// Position of the instruction that starts the block in the original function body.
+    pub instruction: u32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FunctionInfo {
+    /// Index of the function in the original module.
+    pub index: u32,
+    /// The name from the name section, or `func[index]` when there is none.
+    pub name: String,
+    pub blocks: Vec<Block>,
+    /// The id of the first block's counter. The counters of a function are consecutive.
+    first_counter: u32,
+}
+
+#[derive(Serialize)]
+pub struct FunctionSummary {
+    pub name: String,
+    pub calls: u64,
+    pub blocks: usize,
+    pub covered_blocks: usize,
+}
+
+/// A contract instrumented with block counters, along with the counts collected while running it.
+///
+/// Every function body gets a counter at its start, at the start of each branch and loop body,
+/// after each structured block and after each `br_if`. A counter is a call to `coverage.hit` with
+/// its id, which the host forwards to `hit`. The function indices of the module shift by one to
+/// make room for the import, and the name section is updated to match.
+#[wasm_bindgen]
+pub struct Coverage {
+    name: String,
+    binary: Vec<u8>,
+    functions: Vec<FunctionInfo>,
+    hits: Vec<u64>,
+}
+
+fn function_names(module: &Module) -> BTreeMap<u32, String> {
+    module.names_section()
+        .and_then(|names| names.functions())
+        .map(|functions| functions.names().iter().map(|(index, name)| (index, name.clone())).collect())
+        .unwrap_or_default()
+}
+
+/// Returns the index of the `(i32) -> ()` type, adding it if needed.
+fn hit_type(module: &mut Module) -> u32 {
+    let hit = Type::Function(FunctionType::new(vec![ValueType::I32], None));
+    if module.type_section().is_none() {
+        let sections = module.sections_mut();
+        let position = sections.iter()
+            .position(|s| !matches!(s, Section::Custom(_) | Section::Name(_)))
+            .unwrap_or_else(|| sections.len());
+        sections.insert(position, Section::Type(TypeSection::with_types(vec![])));
+    }
+    let types = module.type_section_mut().expect("The type section was just added").types_mut();
+    match types.iter().position(|ty| *ty == hit) {
+        Some(index) => index as u32,
+        None => {
+            types.push(hit);
+            types.len() as u32 - 1
+        }
+    }
+}
+
+/// Adds the `coverage.hit` import after the other imports, and returns its function index.
+fn add_hit_import(module: &mut Module) -> u32 {
+    let type_index = hit_type(module);
+    let index = module.import_count(ImportCountType::Function) as u32;
+    let entry = ImportEntry::new(HIT_MODULE.to_string(), HIT_FUNCTION.to_string(), External::Function(type_index));
+    match module.import_section_mut() {
+        Some(imports) => imports.entries_mut().push(entry),
+        None => {
+            let sections = module.sections_mut();
+            let position = sections.iter()
+                .position(|s| !matches!(s, Section::Custom(_) | Section::Name(_) | Section::Type(_)))
+                .unwrap_or_else(|| sections.len());
+            sections.insert(position, Section::Import(ImportSection::with_entries(vec![entry])));
+        }
+    }
+    index
+}
+
+/// Shifts the indices of the functions defined by the module, which come after the imported
+/// ones, to make room for the new import `hit`.
+fn shift_function_indices(module: &mut Module, hit: u32) {
+    let shift = |index: &mut u32| {
+        if *index >= hit {
+            *index += 1;
+        }
+    };
+    if let Some(exports) = module.export_section_mut() {
+        for entry in exports.entries_mut() {
+            if let Internal::Function(index) = entry.internal_mut() {
+                shift(index);
+            }
+        }
+    }
+    if let Some(elements) = module.elements_section_mut() {
+        for segment in elements.entries_mut() {
+            segment.members_mut().iter_mut().for_each(shift);
+        }
+    }
+    for section in module.sections_mut() {
+        match section {
+            Section::Start(index) => shift(index),
+            Section::Name(names) => {
+                // Local names are keyed by function too. They are dropped rather than renumbered.
+                *names.locals_mut() = None;
+                if let Some(functions) = names.functions_mut() {
+                    let renamed: Vec<(u32, String)> = functions.names().iter()
+                        .map(|(index, name)| (if index >= hit { index + 1 } else { index }, name.clone()))
+                        .collect();
+                    let map = functions.names_mut();
+                    *map = Default::default();
+                    for (index, name) in renamed {
+                        map.insert(index, name);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Coverage {
+    /// Instruments the contract `binary`. `name` identifies the module in reports.
+    pub fn instrument(name: &str, binary: &[u8]) -> Result<Self, String> {
+        let module: Module = parity_wasm::deserialize_buffer(binary).map_err(|e| format!("Invalid wasm: {}", e))?;
+        // A malformed name section only costs the names.
+        let mut module = module.parse_names().unwrap_or_else(|(_, module)| module);
+        let names = function_names(&module);
+        let imported = module.import_count(ImportCountType::Function) as u32;
+        let hit = add_hit_import(&mut module);
+        debug_assert_eq!(hit, imported);
+        shift_function_indices(&mut module, hit);
+
+        let mut functions = vec![];
+        let mut counters = 0;
+        let bodies = module.code_section_mut().map(|code| code.bodies_mut().as_mut_slice()).unwrap_or_default();
+        for (i, body) in bodies.iter_mut().enumerate() {
+            let index = imported + i as u32;
+            let mut function = FunctionInfo {
+                index,
+                name: names.get(&index).cloned().unwrap_or_else(|| format!("func[{}]", index)),
+                blocks: vec![],
+                first_counter: counters,
+            };
+            let code = body.code_mut().elements_mut();
+            let original = std::mem::replace(code, Vec::with_capacity(code.len() * 2));
+            let mut counter = |kind, instruction: usize, code: &mut Vec<Instruction>| {
+                function.blocks.push(Block { kind, instruction: instruction as u32 });
+                code.push(Instruction::I32Const(counters as i32));
+                code.push(Instruction::Call(hit));
+                counters += 1;
+            };
+            counter(BlockKind::Entry, 0, code);
+            // Nesting of the structured blocks, to tell their `end` from the end of the function.
+            let mut depth = 0;
+            for (position, instruction) in original.into_iter().enumerate() {
+                let kind = match &instruction {
+                    Instruction::Block(_) => {
+                        depth += 1;
+                        None
+                    }
+                    Instruction::Loop(_) => {
+                        depth += 1;
+                        Some(BlockKind::Loop)
+                    }
+                    Instruction::If(_) => {
+                        depth += 1;
+                        Some(BlockKind::If)
+                    }
+                    Instruction::Else => Some(BlockKind::Else),
+                    Instruction::End if depth > 0 => {
+                        depth -= 1;
+                        Some(BlockKind::Join)
+                    }
+                    Instruction::BrIf(_) => Some(BlockKind::Fallthrough),
+                    _ => None,
+                };
+                code.push(match instruction {
+                    Instruction::Call(index) if index >= hit => Instruction::Call(index + 1),
+                    instruction => instruction,
+                });
+                if let Some(kind) = kind {
+                    counter(kind, position, code);
+                }
+            }
+            functions.push(function);
+        }
+
+        let binary = parity_wasm::serialize(module).map_err(|e| format!("Can't serialize the instrumented module: {}", e))?;
+        Ok(Coverage { name: name.to_string(), binary, functions, hits: vec![0; counters as usize] })
+    }
+
+    pub fn functions(&self) -> &[FunctionInfo] {
+        &self.functions
+    }
+
+    fn block_hits(&self, function: &FunctionInfo) -> &[u64] {
+        let first = function.first_counter as usize;
+        &self.hits[first..first + function.blocks.len()]
+    }
+
+    pub fn summaries(&self) -> Vec<FunctionSummary> {
+        self.functions.iter()
+            .map(|function| {
+                let hits = self.block_hits(function);
+                FunctionSummary {
+                    name: function.name.clone(),
+                    calls: hits[0],
+                    blocks: hits.len(),
+                    covered_blocks: hits.iter().filter(|&&count| count > 0).count(),
+                }
+            })
+            .collect()
+    }
+
+    /// The source file of a function. AssemblyScript names functions after the path of the file
+    /// they are defined in, e.g. `assembly/main/setGreeting`, so the path with `source_suffix`
+    /// appended gives the file. Other functions are attributed to the module itself.
+    fn source_of(&self, name: &str, source_suffix: &str) -> String {
+        match name.rfind('/') {
+            Some(end) => format!("{}{}", &name[..end], source_suffix),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Renders the counts in the lcov format. Wasm carries no line numbers, so each function is
+    /// reported on the line of its index plus one, and its blocks as the branches of that line.
+    pub fn to_lcov(&self, source_suffix: &str) -> String {
+        let mut files: BTreeMap<String, Vec<&FunctionInfo>> = BTreeMap::new();
+        for function in &self.functions {
+            files.entry(self.source_of(&function.name, source_suffix)).or_default().push(function);
+        }
+        let mut out = String::new();
+        for (file, functions) in files {
+            out.push_str(&format!("TN:\nSF:{}\n", file));
+            for function in &functions {
+                out.push_str(&format!("FN:{},{}\n", function.index + 1, function.name));
+            }
+            for function in &functions {
+                out.push_str(&format!("FNDA:{},{}\n", self.block_hits(function)[0], function.name));
+            }
+            let called = functions.iter().filter(|f| self.block_hits(f)[0] > 0).count();
+            out.push_str(&format!("FNF:{}\nFNH:{}\n", functions.len(), called));
+            let (mut branches, mut taken) = (0, 0);
+            for function in &functions {
+                for (i, count) in self.block_hits(function).iter().enumerate() {
+                    out.push_str(&format!("BRDA:{},0,{},{}\n", function.index + 1, i, count));
+                    branches += 1;
+                    if *count > 0 {
+                        taken += 1;
+                    }
+                }
+            }
+            out.push_str(&format!("BRF:{}\nBRH:{}\n", branches, taken));
+            for function in &functions {
+                out.push_str(&format!("DA:{},{}\n", function.index + 1, self.block_hits(function)[0]));
+            }
+            out.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", functions.len(), called));
+        }
+        out
+    }
+}
+
+#[wasm_bindgen]
+impl Coverage {
+    /// Instruments the contract `binary`, see `Coverage::instrument`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(name: String, binary: &[u8]) -> Result<Coverage, JsValue> {
+        Coverage::instrument(&name, binary).map_err(|e| js_sys::Error::new(&e).into())
+    }
+
+    /// The instrumented module, to instantiate instead of the original one.
+    pub fn binary(&self) -> Vec<u8> {
+        self.binary.clone()
+    }
+
+    /// Counts a run of the block `id`. Ids outside of the module are ignored.
+    pub fn hit(&mut self, id: u32) {
+        if let Some(count) = self.hits.get_mut(id as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.hits.iter_mut().for_each(|count| *count = 0);
+    }
+
+    /// Lists for each function how often it was called and how many of its blocks ran.
+    pub fn summary(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.summaries())?)
+    }
+
+    /// The counts in the lcov format, see `to_lcov`.
+    pub fn lcov(&self, source_suffix: String) -> String {
+        self.to_lcov(&source_suffix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `main` calls the import `log` and the functions `count`, with a loop and a `br_if`, and
+    /// `branch`, with an `if` and an `else`.
+    const MODULE: &str = r#"(module
+      (import "env" "log" (func $log (param i32)))
+      (func $branch (export "branch") (param $x i32) (result i32)
+        (if (result i32) (local.get $x)
+          (then (i32.const 1))
+          (else (i32.const 2))))
+      (func $count (export "count") (param $n i32)
+        (block
+          (loop
+            (br_if 1 (i32.eqz (local.get $n)))
+            (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+            (br 0))))
+      (func (export "main") (param $x i32) (result i32)
+        (call $count (local.get $x))
+        (call $log (local.get $x))
+        (call $branch (local.get $x))))
+    "#;
+
+    fn instrument() -> Coverage {
+        Coverage::instrument("module.wasm", &wat::parse_str(MODULE).unwrap()).unwrap()
+    }
+
+    fn blocks(function: &FunctionInfo) -> Vec<(BlockKind, u32)> {
+        function.blocks.iter().map(|block| (block.kind, block.instruction)).collect()
+    }
+
+    #[test]
+    fn counts_the_blocks_of_each_function() {
+        let coverage = instrument();
+        let functions = coverage.functions();
+        let names: Vec<(u32, &str)> = functions.iter().map(|f| (f.index, f.name.as_str())).collect();
+        assert_eq!(names, [(1, "branch"), (2, "count"), (3, "func[3]")]);
+        assert_eq!(blocks(&functions[0]), [(BlockKind::Entry, 0), (BlockKind::If, 1), (BlockKind::Else, 3), (BlockKind::Join, 5)]);
+        assert_eq!(
+            blocks(&functions[1]),
+            [(BlockKind::Entry, 0), (BlockKind::Loop, 1), (BlockKind::Fallthrough, 4), (BlockKind::Join, 10), (BlockKind::Join, 11)],
+        );
+        assert_eq!(blocks(&functions[2]), [(BlockKind::Entry, 0)]);
+    }
+
+    #[test]
+    fn rewrites_the_calls_after_the_hit_import() {
+        let coverage = instrument();
+        let module: Module = parity_wasm::deserialize_buffer(&coverage.binary()).unwrap();
+        let imports: Vec<(&str, &str)> = module.import_section().unwrap().entries().iter()
+            .map(|entry| (entry.module(), entry.field()))
+            .collect();
+        assert_eq!(imports, [("env", "log"), (HIT_MODULE, HIT_FUNCTION)]);
+        let exports: Vec<(&str, &Internal)> = module.export_section().unwrap().entries().iter()
+            .map(|entry| (entry.field(), entry.internal()))
+            .collect();
+        assert_eq!(exports, [("branch", &Internal::Function(2)), ("count", &Internal::Function(3)), ("main", &Internal::Function(4))]);
+        // The counters call `hit`, 1, and `main` calls `count`, `log` and `branch`.
+        let main = &module.code_section().unwrap().bodies()[2];
+        let calls: Vec<u32> = main.code().elements().iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Call(index) => Some(*index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(calls, [1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn summarizes_the_hits() {
+        let mut coverage = instrument();
+        // `branch` ran its `then` branch, and `count` wasn't called.
+        for id in &[0, 1, 3, 9] {
+            coverage.hit(*id);
+        }
+        coverage.hit(100);
+        let summaries: Vec<(u64, usize, usize)> = coverage.summaries().iter()
+            .map(|s| (s.calls, s.blocks, s.covered_blocks))
+            .collect();
+        assert_eq!(summaries, [(1, 4, 3), (0, 5, 0), (1, 1, 1)]);
+        assert!(coverage.to_lcov(".ts").contains("FNDA:0,count\n"));
+        coverage.reset();
+        assert!(coverage.summaries().iter().all(|s| s.covered_blocks == 0));
+    }
+}
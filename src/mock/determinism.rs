@@ -0,0 +1,104 @@
+use crate::mock::faults::FiredFault;
+use crate::mock::hooks::HostCall;
+use near_vm_logic::mocks::mock_external::MockedExternal;
+use near_vm_logic::VMOutcome;
+use serde::Serialize;
+use std::fmt;
+
+/// What a run of a method left behind, to compare with another run from the same checkpoint.
+pub struct RunCapture {
+    outcome: String,
+    storage: Vec<(Vec<u8>, Vec<u8>)>,
+    receipts: String,
+    error: Option<String>,
+    faults: String,
+    trace: Vec<HostCall>,
+}
+
+impl RunCapture {
+    pub fn new(outcome: &VMOutcome, error: &Option<String>, ext: &MockedExternal, faults: &[FiredFault], trace: &[HostCall]) -> Self {
+        let mut storage: Vec<_> = ext.fake_trie.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        storage.sort();
+        RunCapture {
+            outcome: format!("{:?}", outcome),
+            storage,
+            receipts: serde_json::to_string(ext.get_receipt_create_calls()).unwrap_or_default(),
+            error: error.clone(),
+            faults: serde_json::to_string(faults).unwrap_or_default(),
+            trace: trace.to_vec(),
+        }
+    }
+}
+
+/// The first host call two runs made differently. One of them is missing when a run stopped
+/// before the other.
+#[derive(Serialize, Clone)]
+pub struct DivergingCall {
+    /// Position of the call in the traces.
+    pub index: usize,
+    pub first: Option<HostCall>,
+    pub second: Option<HostCall>,
+}
+
+/// Two runs of a method from the same checkpoint that ended differently.
+#[derive(Serialize, Clone)]
+pub struct Nondeterminism {
+    pub method: String,
+    /// What differed, among `outcome` (return value, logs, gas and balance), `storage`,
+    /// `receipts`, `error` and `faults`.
+    pub differences: Vec<String>,
+    /// `None` when both runs made the same host calls.
+    pub diverging_call: Option<DivergingCall>,
+}
+
+impl Nondeterminism {
+    /// Compares two runs of `method`, and describes how they differ, if they do.
+    pub fn between(method: &str, first: &RunCapture, second: &RunCapture) -> Option<Self> {
+        let differences: Vec<String> = [
+            ("outcome", first.outcome == second.outcome),
+            ("storage", first.storage == second.storage),
+            ("receipts", first.receipts == second.receipts),
+            ("error", first.error == second.error),
+            ("faults", first.faults == second.faults),
+        ]
+        .iter()
+        .filter(|(_, same)| !same)
+        .map(|(what, _)| what.to_string())
+        .collect();
+        if differences.is_empty() {
+            return None;
+        }
+        let length = first.trace.len().max(second.trace.len());
+        let diverging_call = (0..length)
+            .find(|&i| first.trace.get(i) != second.trace.get(i))
+            .map(|index| DivergingCall {
+                index,
+                first: first.trace.get(index).cloned(),
+                second: second.trace.get(index).cloned(),
+            });
+        Some(Nondeterminism { method: method.to_string(), differences, diverging_call })
+    }
+}
+
+fn describe_call(call: &Option<HostCall>) -> String {
+    match call {
+        Some(call) => format!("{}({:?})", call.name, call.args),
+        None => "nothing".to_string(),
+    }
+}
+
+impl fmt::Display for Nondeterminism {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Nondeterminism in {}: the {} differed", self.method, self.differences.join(", "))?;
+        if let Some(call) = &self.diverging_call {
+            write!(
+                f,
+                "; host call {} was {} in the first run and {} in the second",
+                call.index,
+                describe_call(&call.first),
+                describe_call(&call.second),
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use crate::utils::*;
+use near_vm_logic::mocks::mock_external::MockedExternal;
+use near_vm_logic::types::*;
+use near_vm_logic::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A snapshot of the VM to compare against or go back to.
+#[derive(Clone)]
+pub struct Checkpoint {
+    pub ext: MockedExternal,
+    pub internal_state: Option<InternalVMState>,
+    pub context: VMContext,
+    pub storage_usage: StorageUsage,
+    pub balance: Balance,
+}
+
+/// The state of an account, as given to invariants.
+#[derive(Serialize)]
+pub struct AccountView {
+    pub balance: String,
+    pub storage_usage: StorageUsage,
+    /// Keys and values rendered with `display_bytes`.
+    pub storage: BTreeMap<String, String>,
+}
+
+impl AccountView {
+    pub fn new(balance: Balance, storage_usage: StorageUsage, ext: &MockedExternal) -> Self {
+        AccountView {
+            balance: balance.to_string(),
+            storage_usage,
+            storage: ext.fake_trie.iter().map(|(k, v)| (display_bytes(k), display_bytes(v))).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct KeyValue {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Serialize)]
+pub struct ModifiedKey {
+    pub key: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// What changed in the storage of the contract between two checkpoints. Keys and values are
+/// rendered with `display_bytes`.
+#[derive(Serialize)]
+pub struct StorageDiff {
+    pub added: Vec<KeyValue>,
+    pub removed: Vec<KeyValue>,
+    pub modified: Vec<ModifiedKey>,
+    pub storage_usage_delta: i64,
+    /// Signed decimal string, since JS numbers can't hold a `u128`.
+    pub balance_delta: String,
+}
+
+impl StorageDiff {
+    pub fn between(from: &Checkpoint, to: &Checkpoint) -> Self {
+        let before = &from.ext.fake_trie;
+        let after = &to.ext.fake_trie;
+        let keys: BTreeSet<&Vec<u8>> = before.keys().chain(after.keys()).collect();
+        let mut diff = StorageDiff {
+            added: vec![],
+            removed: vec![],
+            modified: vec![],
+            storage_usage_delta: to.storage_usage as i64 - from.storage_usage as i64,
+            balance_delta: balance_delta(from.balance, to.balance),
+        };
+        for key in keys {
+            match (before.get(key), after.get(key)) {
+                (None, Some(value)) => diff.added.push(KeyValue { key: display_bytes(key), value: display_bytes(value) }),
+                (Some(value), None) => diff.removed.push(KeyValue { key: display_bytes(key), value: display_bytes(value) }),
+                (Some(old), Some(new)) if old != new => diff.modified.push(ModifiedKey {
+                    key: display_bytes(key),
+                    old: display_bytes(old),
+                    new: display_bytes(new),
+                }),
+                _ => {}
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+fn balance_delta(from: Balance, to: Balance) -> String {
+    if to >= from {
+        (to - from).to_string()
+    } else {
+        format!("-{}", from - to)
+    }
+}
@@ -0,0 +1,135 @@
+use crate::mock::hooks::host_error_from_name;
+use crate::utils::Rng;
+use near_vm_logic::types::PromiseResult;
+use near_vm_logic::{HostError, VMLogicError};
+use serde::{Deserialize, Serialize};
+
+/// Faults to inject while a contract runs, to test how it handles partial failures.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct FaultPlan {
+    /// Fail `storage_write` once the keys and values written add up to more than this many bytes.
+    /// Keys and values read from registers aren't counted.
+    pub storage_write_limit: Option<u64>,
+    /// The host error failed storage writes return, `GasExceeded` by default.
+    pub storage_write_error: Option<String>,
+    /// Run out of gas at this host call, counting from 1 since the plan was set.
+    pub out_of_gas_at: Option<u64>,
+    /// Probability of turning each successful promise result into a failed one.
+    pub promise_failure_rate: Option<f64>,
+    /// Seed for the random promise failures.
+    pub seed: u64,
+}
+
+/// A fault that was injected, reported in the outcome.
+#[derive(Serialize, Clone)]
+pub struct FiredFault {
+    pub kind: &'static str,
+    /// The host function that failed, or `promise_result` for failed promise results.
+    pub host_function: String,
+    /// The host call the fault fired at, or the index of the failed promise result.
+    pub index: u64,
+    pub error: String,
+}
+
+#[derive(Default, Clone)]
+pub struct Faults {
+    plan: Option<FaultPlan>,
+    rng: Option<Rng>,
+    host_calls: u64,
+    bytes_written: u64,
+    pub fired: Vec<FiredFault>,
+}
+
+impl Faults {
+    pub fn set_plan(&mut self, plan: FaultPlan) -> Result<(), String> {
+        if let Some(name) = &plan.storage_write_error {
+            host_error_from_name(name).ok_or_else(|| format!("Unknown host error: {}", name))?;
+        }
+        self.rng = Some(Rng::new(plan.seed));
+        self.plan = Some(plan);
+        self.host_calls = 0;
+        self.bytes_written = 0;
+        self.fired.clear();
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        *self = Faults::default();
+    }
+
+    /// Counts a call to the host function `name` and returns the error to fail it with, if the
+    /// plan says it should fail.
+    pub fn check(&mut self, name: &str, args: &[u64]) -> Option<VMLogicError> {
+        let plan = self.plan.as_ref()?;
+        self.host_calls += 1;
+        let (kind, error) = if plan.out_of_gas_at == Some(self.host_calls) {
+            ("out_of_gas", HostError::GasExceeded)
+        } else if name == "storage_write" {
+            // `storage_write(key_len, key_ptr, value_len, value_ptr, register_id)`, where a length
+            // of `u64::MAX` means the data is in the register whose id is in place of the pointer.
+            let written = [args[0], args[2]].iter()
+                .filter(|&&len| len != u64::MAX)
+                .fold(0u64, |total, &len| total.saturating_add(len));
+            self.bytes_written = self.bytes_written.saturating_add(written);
+            match plan.storage_write_limit {
+                Some(limit) if self.bytes_written > limit => {
+                    let error = plan.storage_write_error.as_ref()
+                        .and_then(|name| host_error_from_name(name))
+                        .unwrap_or(HostError::GasExceeded);
+                    ("storage_write_limit", error)
+                }
+                _ => return None,
+            }
+        } else {
+            return None;
+        };
+        self.fired.push(FiredFault {
+            kind,
+            host_function: name.to_string(),
+            index: self.host_calls,
+            error: format!("{:?}", error),
+        });
+        Some(VMLogicError::HostError(error))
+    }
+
+    /// Fails successful promise results at random, as often as the plan says.
+    pub fn apply_to_promise_results(&mut self, results: &mut [PromiseResult]) {
+        let rate = match self.plan.as_ref().and_then(|plan| plan.promise_failure_rate) {
+            Some(rate) => rate,
+            None => return,
+        };
+        let rng = self.rng.as_mut().unwrap();
+        for (i, result) in results.iter_mut().enumerate() {
+            if let PromiseResult::Successful(_) = result {
+                if rng.next_f64() < rate {
+                    *result = PromiseResult::Failed;
+                    self.fired.push(FiredFault {
+                        kind: "promise_failure",
+                        host_function: "promise_result".to_string(),
+                        index: i as u64,
+                        error: "Failed".to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FaultPlan, Faults};
+
+    #[test]
+    fn counts_the_bytes_written_from_memory() {
+        let mut faults = Faults::default();
+        faults.set_plan(FaultPlan { storage_write_limit: Some(8), ..FaultPlan::default() }).unwrap();
+        assert!(faults.check("storage_write", &[3, 0, 5, 0, 0]).is_none());
+        // The value is in register 1.
+        assert!(faults.check("storage_write", &[0, 0, u64::MAX, 1, 0]).is_none());
+        assert!(faults.check("storage_write", &[1, 0, 0, 0, 0]).is_some());
+        assert_eq!(faults.fired.len(), 1);
+        assert_eq!(faults.fired[0].kind, "storage_write_limit");
+        assert_eq!(faults.fired[0].index, 3);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::mock::seed::SeedDerivation;
+use crate::utils::*;
+use js_sys::{Array, Function, Object, Reflect};
+use near_vm_logic::{HostError, VMLogicError};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use wasm_bindgen::prelude::*;
+
+type VMResult<T> = Result<T, VMLogicError>;
+
+/// JS callbacks registered to run before and after named host functions.
+///
+/// A callback receives an object with the `name` of the host function, its `args` and `count`,
+/// the number of times it has been called since the hook was registered. After hooks also get the
+/// `result` or the `error` of the call. A callback can return:
+/// * nothing, to let the call go on as usual;
+/// * `{ return: value }`, to skip the host function and return `value` instead;
+/// * `{ error: "GasExceeded" }`, to make the host function fail with the given `HostError`.
+#[derive(Default)]
+pub struct HostHooks {
+    before: HashMap<String, Function>,
+    after: HashMap<String, Function>,
+    counts: HashMap<String, u64>,
+}
+
+/// A call to a host function, as recorded in traces.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HostCall {
+    pub name: String,
+    pub args: Vec<u64>,
+    /// How the seed `random_seed` read was derived, when the VM derives seeds per receipt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<SeedDerivation>,
+}
+
+/// What a hook asked the VM to do.
+pub enum HookAction {
+    Continue,
+    Return(JsValue),
+    Fail(VMLogicError),
+}
+
+impl HostHooks {
+    pub fn set_before(&mut self, name: String, callback: Function) {
+        self.counts.remove(&name);
+        self.before.insert(name, callback);
+    }
+
+    pub fn set_after(&mut self, name: String, callback: Function) {
+        self.counts.remove(&name);
+        self.after.insert(name, callback);
+    }
+
+    pub fn clear(&mut self) {
+        *self = HostHooks::default();
+    }
+
+    /// How many times each hooked host function was called.
+    pub fn counts(&self) -> HashMap<String, u64> {
+        self.counts.clone()
+    }
+
+    /// Goes back to `counts`, as returned by `counts`, e.g. to run a call again.
+    pub fn restore_counts(&mut self, counts: HashMap<String, u64>) {
+        self.counts = counts;
+    }
+
+    /// Counts a call to the host function `name` and returns how many calls there have been.
+    pub fn record(&mut self, name: &str) -> u64 {
+        let count = self.counts.entry(name.to_string()).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    pub fn before(&self, name: &str, args: &[u64], count: u64) -> Result<HookAction, JsValue> {
+        match self.before.get(name) {
+            Some(callback) => call_hook(callback, &hook_call(name, args, count)),
+            None => Ok(HookAction::Continue),
+        }
+    }
+
+    pub fn after<T: HookValue>(&self, name: &str, args: &[u64], count: u64, res: &VMResult<T>) -> Result<HookAction, JsValue> {
+        let callback = match self.after.get(name) {
+            Some(callback) => callback,
+            None => return Ok(HookAction::Continue),
+        };
+        let call = hook_call(name, args, count);
+        match res {
+            Ok(value) => Reflect::set(&call, &"result".into(), &value.to_js())?,
+            Err(e) => Reflect::set(&call, &"error".into(), &format!("{:?}", e).into())?,
+        };
+        call_hook(callback, &call)
+    }
+}
+
+fn hook_call(name: &str, args: &[u64], count: u64) -> Object {
+    let call = Object::new();
+    let js_args: Array = args.iter().map(|arg| JsValue::from_f64(*arg as f64)).collect();
+    Reflect::set(&call, &"name".into(), &name.into()).unwrap();
+    Reflect::set(&call, &"args".into(), &js_args).unwrap();
+    Reflect::set(&call, &"count".into(), &JsValue::from_f64(count as f64)).unwrap();
+    call
+}
+
+fn call_hook(callback: &Function, call: &Object) -> Result<HookAction, JsValue> {
+    let res = callback.call1(&JsValue::NULL, call)?;
+    if res.is_undefined() || res.is_null() {
+        return Ok(HookAction::Continue);
+    }
+    let error = Reflect::get(&res, &"error".into())?;
+    if let Some(name) = error.as_string() {
+        return host_error_from_name(&name)
+            .map(|e| HookAction::Fail(VMLogicError::HostError(e)))
+            .ok_or_else(|| js_sys::Error::new(&format!("Unknown host error: {}", name)).into());
+    }
+    if Reflect::has(&res, &"return".into())? {
+        return Ok(HookAction::Return(Reflect::get(&res, &"return".into())?));
+    }
+    Ok(HookAction::Continue)
+}
+
+/// Returns the `HostError` called `name`, for the errors that don't carry any data.
+pub fn host_error_from_name(name: &str) -> Option<HostError> {
+    Some(match name {
+        "BadUTF16" => HostError::BadUTF16,
+        "BadUTF8" => HostError::BadUTF8,
+        "GasExceeded" => HostError::GasExceeded,
+        "GasLimitExceeded" => HostError::GasLimitExceeded,
+        "BalanceExceeded" => HostError::BalanceExceeded,
+        "EmptyMethodName" => HostError::EmptyMethodName,
+        "IntegerOverflow" => HostError::IntegerOverflow,
+        "CannotAppendActionToJointPromise" => HostError::CannotAppendActionToJointPromise,
+        "CannotReturnJointPromise" => HostError::CannotReturnJointPromise,
+        "MemoryAccessViolation" => HostError::MemoryAccessViolation,
+        "InvalidAccountId" => HostError::InvalidAccountId,
+        "InvalidMethodName" => HostError::InvalidMethodName,
+        "InvalidPublicKey" => HostError::InvalidPublicKey,
+        _ => return None,
+    })
+}
+
+/// Values returned by host functions, which hooks can inspect and override.
+pub trait HookValue: Sized {
+    fn from_js(value: &JsValue) -> Result<Self, JsValue>;
+    fn to_js(&self) -> JsValue;
+}
+
+impl HookValue for () {
+    fn from_js(_value: &JsValue) -> Result<Self, JsValue> {
+        Ok(())
+    }
+
+    fn to_js(&self) -> JsValue {
+        JsValue::UNDEFINED
+    }
+}
+
+impl HookValue for u64 {
+    fn from_js(value: &JsValue) -> Result<Self, JsValue> {
+        u64::try_from(u128_from_js(value)?).map_err(|_| js_sys::Error::new("Hook returned a value larger than u64").into())
+    }
+
+    fn to_js(&self) -> JsValue {
+        JsValue::from_f64(*self as f64)
+    }
+}
@@ -0,0 +1,418 @@
+use crate::mock::account_id;
+use crate::mock::coverage::{self, Coverage};
+use crate::mock::faults::Faults;
+use crate::mock::hooks::HostCall;
+use crate::mock::public_key;
+use near_runtime_fees::RuntimeFeesConfig;
+use near_vm_logic::types::PromiseResult;
+use near_vm_logic::*;
+use std::cell::RefCell;
+use std::fmt;
+use wasmi::memory_units::{Bytes, Pages};
+use wasmi::{
+    Externals, FuncInstance, FuncRef, ImportsBuilder, MemoryDescriptor, MemoryInstance, MemoryRef,
+    Module, ModuleImportResolver, ModuleInstance, RuntimeArgs, RuntimeValue, Signature, Trap,
+    TrapKind, ValueType,
+};
+
+use wasmi::ValueType::{I32, I64};
+
+/// The `env` imports a contract can link against, with their parameters and return type. The
+/// position of a function in this table is the index wasmi dispatches it with.
+const HOST_FUNCTIONS: &[(&str, &[ValueType], Option<ValueType>)] = &[
+    ("read_register", &[I64, I64], None),
+    ("register_len", &[I64], Some(I64)),
+    ("write_register", &[I64, I64, I64], None),
+    ("current_account_id", &[I64], None),
+    ("signer_account_id", &[I64], None),
+    ("signer_account_pk", &[I64], None),
+    ("predecessor_account_id", &[I64], None),
+    ("input", &[I64], None),
+    ("block_index", &[], Some(I64)),
+    ("block_timestamp", &[], Some(I64)),
+    ("storage_usage", &[], Some(I64)),
+    ("account_balance", &[I64], None),
+    ("account_locked_balance", &[I64], None),
+    ("attached_deposit", &[I64], None),
+    ("prepaid_gas", &[], Some(I64)),
+    ("used_gas", &[], Some(I64)),
+    ("random_seed", &[I64], None),
+    ("sha256", &[I64, I64, I64], None),
+    ("gas", &[I32], None),
+    ("promise_create", &[I64, I64, I64, I64, I64, I64, I64, I64], Some(I64)),
+    ("promise_then", &[I64, I64, I64, I64, I64, I64, I64, I64, I64], Some(I64)),
+    ("promise_and", &[I64, I64], Some(I64)),
+    ("promise_batch_create", &[I64, I64], Some(I64)),
+    ("promise_batch_then", &[I64, I64, I64], Some(I64)),
+    ("promise_batch_action_create_account", &[I64], None),
+    ("promise_batch_action_deploy_contract", &[I64, I64, I64], None),
+    ("promise_batch_action_function_call", &[I64, I64, I64, I64, I64, I64, I64], None),
+    ("promise_batch_action_transfer", &[I64, I64], None),
+    ("promise_batch_action_stake", &[I64, I64, I64, I64], None),
+    ("promise_batch_action_add_key_with_full_access", &[I64, I64, I64, I64], None),
+    ("promise_batch_action_add_key_with_function_call", &[I64, I64, I64, I64, I64, I64, I64, I64, I64], None),
+    ("promise_batch_action_delete_key", &[I64, I64, I64], None),
+    ("promise_batch_action_delete_account", &[I64, I64, I64], None),
+    ("promise_results_count", &[], Some(I64)),
+    ("promise_result", &[I64, I64], Some(I64)),
+    ("promise_return", &[I64], None),
+    ("value_return", &[I64, I64], None),
+    ("panic", &[], None),
+    ("panic_utf8", &[I64, I64], None),
+    ("log_utf8", &[I64, I64], None),
+    ("log_utf16", &[I64, I64], None),
+    ("abort", &[I32, I32, I32, I32], None),
+    ("storage_write", &[I64, I64, I64, I64, I64], Some(I64)),
+    ("storage_read", &[I64, I64, I64], Some(I64)),
+    ("storage_remove", &[I64, I64, I64], Some(I64)),
+    ("storage_has_key", &[I64, I64], Some(I64)),
+    ("storage_iter_prefix", &[I64, I64], Some(I64)),
+    ("storage_iter_range", &[I64, I64, I64, I64], Some(I64)),
+    ("storage_iter_next", &[I64, I64, I64], Some(I64)),
+];
+
+/// The memory of a contract instantiated by wasmi.
+pub struct WasmiMemory(pub MemoryRef);
+
+impl MemoryLike for WasmiMemory {
+    fn fits_memory(&self, offset: u64, len: u64) -> bool {
+        let size: Bytes = self.0.current_size().into();
+        match offset.checked_add(len) {
+            Some(end) => end <= size.0 as u64,
+            None => false,
+        }
+    }
+
+    fn read_memory(&self, offset: u64, buffer: &mut [u8]) {
+        self.0.get_into(offset as u32, buffer).expect("Memory access must be checked with fits_memory")
+    }
+
+    fn read_memory_u8(&self, offset: u64) -> u8 {
+        let mut byte = [0u8];
+        self.read_memory(offset, &mut byte);
+        byte[0]
+    }
+
+    fn write_memory(&mut self, offset: u64, buffer: &[u8]) {
+        self.0.set(offset as u32, buffer).expect("Memory access must be checked with fits_memory")
+    }
+}
+
+/// Resolves the `env` imports of a contract to `HOST_FUNCTIONS` and to a memory of the size the
+/// contract declares, up to the `VMConfig`'s maximum.
+struct EnvResolver<'a> {
+    config: &'a VMConfig,
+    memory: RefCell<Option<MemoryRef>>,
+}
+
+impl<'a> ModuleImportResolver for EnvResolver<'a> {
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, wasmi::Error> {
+        let index = HOST_FUNCTIONS.iter()
+            .position(|(name, _, _)| *name == field_name)
+            .ok_or_else(|| wasmi::Error::Instantiation(format!("Unknown host function env.{}", field_name)))?;
+        let (_, params, return_type) = HOST_FUNCTIONS[index];
+        if signature.params() != params || signature.return_type() != return_type {
+            return Err(wasmi::Error::Instantiation(format!(
+                "env.{} is imported as {:?} but the host function is {:?}",
+                field_name,
+                signature,
+                Signature::new(params, return_type),
+            )));
+        }
+        Ok(FuncInstance::alloc_host(Signature::new(params, return_type), index))
+    }
+
+    fn resolve_memory(&self, field_name: &str, descriptor: &MemoryDescriptor) -> Result<MemoryRef, wasmi::Error> {
+        if field_name != "memory" {
+            return Err(wasmi::Error::Instantiation(format!("Unknown memory env.{}", field_name)));
+        }
+        let cap = self.config.max_memory_pages;
+        let maximum = descriptor.maximum().map_or(cap, |maximum| std::cmp::min(maximum, cap));
+        let initial = std::cmp::min(descriptor.initial(), maximum);
+        let memory = MemoryInstance::alloc(Pages(initial as usize), Some(Pages(maximum as usize)))?;
+        *self.memory.borrow_mut() = Some(memory.clone());
+        Ok(memory)
+    }
+}
+
+/// The index wasmi dispatches `coverage.hit` with, past the end of `HOST_FUNCTIONS`.
+const COVERAGE_HIT: usize = HOST_FUNCTIONS.len();
+
+/// Resolves the `coverage.hit` import of contracts instrumented by `Coverage`.
+struct CoverageResolver;
+
+impl ModuleImportResolver for CoverageResolver {
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, wasmi::Error> {
+        let hit = Signature::new(&[I32] as &[ValueType], None);
+        if field_name != coverage::HIT_FUNCTION || *signature != hit {
+            return Err(wasmi::Error::Instantiation(format!("Unknown host function {}.{}", coverage::HIT_MODULE, field_name)));
+        }
+        Ok(FuncInstance::alloc_host(hit, COVERAGE_HIT))
+    }
+}
+
+/// A host function error, carried through wasmi as a trap.
+#[derive(Debug)]
+pub struct HostTrap(pub VMLogicError);
+
+impl fmt::Display for HostTrap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl wasmi::HostError for HostTrap {}
+
+/// Why an interpreted method didn't finish.
+#[derive(Debug)]
+pub enum ExecutionError {
+    /// The contract couldn't be parsed or linked against the host functions.
+    Link(String),
+    /// A host function failed.
+    Host(VMLogicError),
+    /// The contract trapped, or doesn't export the method.
+    Trap(String),
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecutionError::Link(e) => write!(f, "LinkError: {}", e),
+            ExecutionError::Host(e) => write!(f, "{:?}", e),
+            ExecutionError::Trap(e) => write!(f, "Trap: {}", e),
+        }
+    }
+}
+
+impl From<wasmi::Error> for ExecutionError {
+    fn from(e: wasmi::Error) -> Self {
+        match e {
+            wasmi::Error::Trap(trap) => trap.into(),
+            e => ExecutionError::Trap(e.to_string()),
+        }
+    }
+}
+
+impl From<Trap> for ExecutionError {
+    fn from(trap: Trap) -> Self {
+        if let TrapKind::Host(e) = trap.kind() {
+            if let Some(HostTrap(e)) = e.downcast_ref::<HostTrap>() {
+                return ExecutionError::Host(e.clone());
+            }
+        }
+        ExecutionError::Trap(trap.to_string())
+    }
+}
+
+/// The result of running a method with `run_method`.
+pub struct Execution {
+    pub outcome: VMOutcome,
+    pub error: Option<ExecutionError>,
+    /// The state of `VMLogic` when the method stopped, so that the mock VM can pick up from it.
+    pub state: InternalVMState,
+    /// The host functions the method called, in order.
+    pub trace: Vec<HostCall>,
+}
+
+/// Dispatches host calls from wasmi to `VMLogic`.
+struct Runtime<'a, 'b> {
+    logic: VMLogic<'a>,
+    /// The same memory as `logic`'s, to check the arguments of host calls.
+    memory: WasmiMemory,
+    faults: &'b mut Faults,
+    coverage: Option<&'b mut Coverage>,
+    trace: Vec<HostCall>,
+}
+
+impl<'a, 'b> Externals for Runtime<'a, 'b> {
+    fn invoke_index(&mut self, index: usize, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        if index == COVERAGE_HIT {
+            if let Some(coverage) = &mut self.coverage {
+                coverage.hit(args.nth_checked(0)?);
+            }
+            return Ok(None);
+        }
+        let (name, _, _) = HOST_FUNCTIONS[index];
+        let values: Vec<u64> = args.as_ref().iter()
+            .map(|arg| match *arg {
+                RuntimeValue::I32(v) => u64::from(v as u32),
+                RuntimeValue::I64(v) => v as u64,
+                _ => unreachable!("Host functions only take integers"),
+            })
+            .collect();
+        self.trace.push(HostCall { name: name.to_string(), args: values.clone(), seed: None });
+        if let Some(e) = self.faults.check(name, &values)
+            .or_else(|| account_id::check_host_call(name, &values, &self.memory))
+            .or_else(|| public_key::check_host_call(name, &values, &self.memory))
+        {
+            return Err(HostTrap(e).into());
+        }
+        let a = |i: usize| values[i];
+        let n = |i: usize| values[i] as u32;
+        let logic = &mut self.logic;
+        let res: Result<Option<u64>, VMLogicError> = match name {
+            "read_register" => logic.read_register(a(0), a(1)).map(|_| None),
+            "register_len" => logic.register_len(a(0)).map(Some),
+            "write_register" => logic.write_register(a(0), a(1), a(2)).map(|_| None),
+            "current_account_id" => logic.current_account_id(a(0)).map(|_| None),
+            "signer_account_id" => logic.signer_account_id(a(0)).map(|_| None),
+            "signer_account_pk" => logic.signer_account_pk(a(0)).map(|_| None),
+            "predecessor_account_id" => logic.predecessor_account_id(a(0)).map(|_| None),
+            "input" => logic.input(a(0)).map(|_| None),
+            "block_index" => logic.block_index().map(Some),
+            "block_timestamp" => logic.block_timestamp().map(Some),
+            "storage_usage" => logic.storage_usage().map(Some),
+            "account_balance" => logic.account_balance(a(0)).map(|_| None),
+            "account_locked_balance" => logic.account_locked_balance(a(0)).map(|_| None),
+            "attached_deposit" => logic.attached_deposit(a(0)).map(|_| None),
+            "prepaid_gas" => logic.prepaid_gas().map(Some),
+            "used_gas" => logic.used_gas().map(Some),
+            "random_seed" => logic.random_seed(a(0)).map(|_| None),
+            "sha256" => logic.sha256(a(0), a(1), a(2)).map(|_| None),
+            "gas" => logic.gas(n(0)).map(|_| None),
+            "promise_create" => logic.promise_create(a(0), a(1), a(2), a(3), a(4), a(5), a(6), a(7)).map(Some),
+            "promise_then" => logic.promise_then(a(0), a(1), a(2), a(3), a(4), a(5), a(6), a(7), a(8)).map(Some),
+            "promise_and" => logic.promise_and(a(0), a(1)).map(Some),
+            "promise_batch_create" => logic.promise_batch_create(a(0), a(1)).map(Some),
+            "promise_batch_then" => logic.promise_batch_then(a(0), a(1), a(2)).map(Some),
+            "promise_batch_action_create_account" => logic.promise_batch_action_create_account(a(0)).map(|_| None),
+            "promise_batch_action_deploy_contract" => {
+                logic.promise_batch_action_deploy_contract(a(0), a(1), a(2)).map(|_| None)
+            }
+            "promise_batch_action_function_call" => {
+                logic.promise_batch_action_function_call(a(0), a(1), a(2), a(3), a(4), a(5), a(6)).map(|_| None)
+            }
+            "promise_batch_action_transfer" => logic.promise_batch_action_transfer(a(0), a(1)).map(|_| None),
+            "promise_batch_action_stake" => logic.promise_batch_action_stake(a(0), a(1), a(2), a(3)).map(|_| None),
+            "promise_batch_action_add_key_with_full_access" => {
+                logic.promise_batch_action_add_key_with_full_access(a(0), a(1), a(2), a(3)).map(|_| None)
+            }
+            "promise_batch_action_add_key_with_function_call" => logic
+                .promise_batch_action_add_key_with_function_call(a(0), a(1), a(2), a(3), a(4), a(5), a(6), a(7), a(8))
+                .map(|_| None),
+            "promise_batch_action_delete_key" => logic.promise_batch_action_delete_key(a(0), a(1), a(2)).map(|_| None),
+            "promise_batch_action_delete_account" => {
+                logic.promise_batch_action_delete_account(a(0), a(1), a(2)).map(|_| None)
+            }
+            "promise_results_count" => logic.promise_results_count().map(Some),
+            "promise_result" => logic.promise_result(a(0), a(1)).map(Some),
+            "promise_return" => logic.promise_return(a(0)).map(|_| None),
+            "value_return" => logic.value_return(a(0), a(1)).map(|_| None),
+            "panic" => logic.panic().map(|_| None),
+            "panic_utf8" => logic.panic_utf8(a(0), a(1)).map(|_| None),
+            "log_utf8" => logic.log_utf8(a(0), a(1)).map(|_| None),
+            "log_utf16" => logic.log_utf16(a(0), a(1)).map(|_| None),
+            "abort" => logic.abort(n(0), n(1), n(2), n(3)).map(|_| None),
+            "storage_write" => logic.storage_write(a(0), a(1), a(2), a(3), a(4)).map(Some),
+            "storage_read" => logic.storage_read(a(0), a(1), a(2)).map(Some),
+            "storage_remove" => logic.storage_remove(a(0), a(1), a(2)).map(Some),
+            "storage_has_key" => logic.storage_has_key(a(0), a(1)).map(Some),
+            "storage_iter_prefix" => logic.storage_iter_prefix(a(0), a(1)).map(Some),
+            "storage_iter_range" => logic.storage_iter_range(a(0), a(1), a(2), a(3)).map(Some),
+            "storage_iter_next" => logic.storage_iter_next(a(0), a(1), a(2)).map(Some),
+            _ => unreachable!("Every host function in HOST_FUNCTIONS is dispatched"),
+        };
+        match res {
+            Ok(value) => Ok(value.map(|v| RuntimeValue::I64(v as i64))),
+            Err(e) => Err(HostTrap(e).into()),
+        }
+    }
+}
+
+/// Instantiates `code` and runs its export `method`, with the host functions answered by a
+/// `VMLogic` built from the given state. Faults from `faults` are injected into the host calls, and
+/// the blocks of contracts instrumented by `Coverage` are counted in `coverage`.
+#[allow(clippy::too_many_arguments)]
+pub fn run_method(
+    code: &[u8],
+    method: &str,
+    ext: &mut dyn External,
+    context: VMContext,
+    config: &VMConfig,
+    fees_config: &RuntimeFeesConfig,
+    promise_results: &[PromiseResult],
+    faults: &mut Faults,
+    coverage: Option<&mut Coverage>,
+) -> Execution {
+    let resolver = EnvResolver { config, memory: RefCell::new(None) };
+    let instance = Module::from_buffer(code)
+        .and_then(|module| {
+            let imports = ImportsBuilder::new()
+                .with_resolver("env", &resolver)
+                .with_resolver(coverage::HIT_MODULE, &CoverageResolver);
+            ModuleInstance::new(&module, &imports)
+        })
+        .map_err(|e| ExecutionError::Link(e.to_string()));
+    // Contracts usually import their memory, but they may also define and export it.
+    let memory = instance.as_ref().ok()
+        .and_then(|instance| instance.not_started_instance().export_by_name("memory"))
+        .and_then(|export| export.as_memory().cloned())
+        .or_else(|| resolver.memory.borrow().clone())
+        .unwrap_or_else(|| MemoryInstance::alloc(Pages(0), Some(Pages(0))).expect("Empty memory is valid"));
+    let mut memory = WasmiMemory(memory);
+    let mut runtime = Runtime {
+        memory: WasmiMemory(memory.0.clone()),
+        logic: VMLogic::new(ext, context, config, fees_config, promise_results, &mut memory),
+        faults,
+        coverage,
+        trace: vec![],
+    };
+    let error = instance
+        .and_then(|instance| Ok(instance.run_start(&mut runtime)?))
+        .and_then(|instance| Ok(instance.invoke_export(method, &[], &mut runtime)?))
+        .err();
+    let state = runtime.logic.save_state();
+    Execution { outcome: runtime.logic.outcome(), error, state, trace: runtime.trace }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::test_contracts::{context, wasm, CONTRACT};
+    use crate::mock::vm_logic::{CallOptions, VM};
+    use near_vm_logic::ReturnData;
+
+    fn vm(code: Vec<u8>) -> VM {
+        let mut vm = VM::from_context(context());
+        vm.load_contract(code);
+        vm
+    }
+
+    #[test]
+    fn returns_the_value_of_the_method() {
+        let mut vm = vm(wasm(CONTRACT));
+        let res = vm.execute("echo", b"ping".to_vec(), CallOptions::default(), false).unwrap();
+        assert_eq!(res.error, None);
+        assert_eq!(res.outcome.return_data, ReturnData::Value(b"ping".to_vec()));
+        assert!(res.outcome.burnt_gas > 0);
+    }
+
+    #[test]
+    fn surfaces_host_errors() {
+        let mut vm = vm(wasm(CONTRACT));
+        let res = vm.execute("bad_register", vec![], CallOptions::default(), false).unwrap();
+        assert!(res.error.unwrap().contains("InvalidRegisterId"));
+        assert_eq!(vm.trace().last().unwrap().name, "read_register");
+    }
+
+    #[test]
+    fn reports_methods_that_take_arguments() {
+        // `add(a, b)` can't be called as a contract method, which takes no arguments.
+        let mut vm = vm(include_bytes!("add.wasm").to_vec());
+        let res = vm.execute("add", vec![], CallOptions::default(), false).unwrap();
+        assert!(res.error.unwrap().starts_with("Trap: "));
+        let res = vm.execute("missing", vec![], CallOptions::default(), false).unwrap();
+        assert!(res.error.is_some());
+    }
+
+    #[test]
+    fn imports_the_memory_the_contract_declares() {
+        let mut vm = vm(wasm(r#"(module
+          (import "env" "memory" (memory 1 2))
+          (func (export "grow")
+            (if (i32.ne (memory.size) (i32.const 1)) (then unreachable))
+            (if (i32.ne (memory.grow (i32.const 1)) (i32.const 1)) (then unreachable))
+            (if (i32.ne (memory.grow (i32.const 1)) (i32.const -1)) (then unreachable))))"#));
+        let res = vm.execute("grow", vec![], CallOptions::default(), false).unwrap();
+        assert_eq!(res.error, None);
+    }
+}
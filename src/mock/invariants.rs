@@ -0,0 +1,125 @@
+use crate::mock::hooks::HostCall;
+use js_sys::{Function, Object, Reflect};
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+/// An invariant that failed, with the call or receipt after which it did.
+#[derive(Serialize, Clone)]
+pub struct InvariantFailure {
+    pub invariant: String,
+    /// The method that was called, or the receipt that ran, e.g. `receipt 3 on token`.
+    pub call: String,
+    pub receipt_id: Option<u64>,
+    pub error: String,
+    /// The host functions the call made, in order, with their arguments.
+    pub trace: Vec<HostCall>,
+}
+
+/// JS callbacks that check the state of the VM after every method call and every receipt.
+///
+/// A callback gets a frozen object with the `call`, the `storage` of the account that ran it, the
+/// `accounts` of the ledger and the `outcome`, and breaks the invariant by throwing or returning
+/// `false`. The objects are copies, so an invariant can't change the state of the VM.
+///
+/// Invariants written in AssemblyScript read the state through the host functions of the VM
+/// instead. They can't run while the VM is busy, so they are only checked between the calls made
+/// from JS, by `runMethod` in `imports.js`.
+#[derive(Default)]
+pub struct Invariants {
+    checks: Vec<Invariant>,
+    pub failures: Vec<InvariantFailure>,
+}
+
+struct Invariant {
+    name: String,
+    check: Function,
+    /// Whether it reads the state through the host functions.
+    via_host: bool,
+}
+
+impl Invariants {
+    /// Adds an invariant, replacing the one with the same name.
+    pub fn add(&mut self, name: String, check: Function, via_host: bool) {
+        self.remove(&name);
+        self.checks.push(Invariant { name, check, via_host });
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.checks.retain(|invariant| invariant.name != name);
+    }
+
+    pub fn clear(&mut self) {
+        *self = Invariants::default();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checks.is_empty()
+    }
+
+    pub fn has_via_host(&self) -> bool {
+        self.checks.iter().any(|invariant| invariant.via_host)
+    }
+
+    /// Lists the invariants that read the state through the host functions as `{ name, check }`
+    /// objects, for JS to run.
+    pub fn via_host_to_js(&self) -> Result<JsValue, JsValue> {
+        let list = js_sys::Array::new();
+        for invariant in self.checks.iter().filter(|invariant| invariant.via_host) {
+            let entry = Object::new();
+            Reflect::set(&entry, &"name".into(), &invariant.name.as_str().into())?;
+            Reflect::set(&entry, &"check".into(), &invariant.check)?;
+            list.push(&entry);
+        }
+        Ok(list.into())
+    }
+
+    /// Runs the invariants that don't read the state through the host functions against `state`,
+    /// records the ones that fail and returns how many did.
+    pub fn check(&mut self, state: &Object, call: &str, receipt_id: Option<u64>, trace: &[HostCall]) -> usize {
+        let mut failed = 0;
+        for invariant in self.checks.iter().filter(|invariant| !invariant.via_host) {
+            if let Err(error) = run_check(&invariant.check, state) {
+                self.failures.push(InvariantFailure {
+                    invariant: invariant.name.clone(),
+                    call: call.to_string(),
+                    receipt_id,
+                    error,
+                    trace: trace.to_vec(),
+                });
+                failed += 1;
+            }
+        }
+        failed
+    }
+
+    /// Describes the last `count` failures, to throw them.
+    pub fn describe_last(&self, count: usize) -> String {
+        self.failures[self.failures.len() - count..].iter()
+            .map(|f| format!("Invariant {} broken by {}: {}", f.invariant, f.call, f.error))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Builds the frozen object invariants get.
+pub fn state<S: Serialize, A: Serialize>(call: &str, storage: &S, accounts: &A, outcome: &JsValue) -> Result<Object, JsValue> {
+    let state = Object::new();
+    Reflect::set(&state, &"call".into(), &call.into())?;
+    Reflect::set(&state, &"storage".into(), &serde_wasm_bindgen::to_value(storage)?)?;
+    Reflect::set(&state, &"accounts".into(), &serde_wasm_bindgen::to_value(accounts)?)?;
+    Reflect::set(&state, &"outcome".into(), outcome)?;
+    Ok(Object::freeze(&state))
+}
+
+/// Calls an invariant with `state`. It fails if it throws or returns `false`.
+pub fn run_check(check: &Function, state: &JsValue) -> Result<(), String> {
+    match check.call1(&JsValue::NULL, state) {
+        Ok(res) if res == JsValue::FALSE => Err("Invariant returned false".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(match e.dyn_ref::<js_sys::Error>() {
+            Some(e) => String::from(e.message()),
+            None => format!("{:?}", e),
+        }),
+    }
+}
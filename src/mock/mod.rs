@@ -1,3 +1,25 @@
+pub mod account_id;
+pub mod collections;
+pub mod context;
+pub mod context_builder;
+pub mod determinism;
+#[cfg(feature = "coverage")]
+pub mod coverage;
+pub mod diff;
+pub mod faults;
+pub mod hooks;
+#[cfg(feature = "interpreter")]
+pub mod interpreter;
+pub mod invariants;
 pub mod memory;
+pub mod public_key;
+#[cfg(feature = "interpreter")]
+pub mod replay;
+#[cfg(feature = "interpreter")]
+pub mod scheduler;
+pub mod seed;
+pub mod snapshot;
+#[cfg(all(test, feature = "interpreter"))]
+pub mod test_contracts;
 pub mod vm_logic;
-pub mod runner;
\ No newline at end of file
+pub mod runner;
@@ -0,0 +1,157 @@
+use near_vm_logic::{HostError, MemoryLike, VMLogicError};
+use serde::Serialize;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+/// Curves nearcore supports, numbered like the first byte of a borsh serialized key.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    Ed25519 = 0,
+    Secp256k1 = 1,
+}
+
+impl KeyType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(KeyType::Ed25519),
+            1 => Some(KeyType::Secp256k1),
+            _ => None,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ed25519" => Some(KeyType::Ed25519),
+            "secp256k1" => Some(KeyType::Secp256k1),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyType::Ed25519 => "ed25519",
+            KeyType::Secp256k1 => "secp256k1",
+        }
+    }
+
+    /// Length of the key without its type.
+    pub fn data_len(self) -> usize {
+        match self {
+            KeyType::Ed25519 => 32,
+            KeyType::Secp256k1 => 64,
+        }
+    }
+}
+
+/// Host functions that take a public key, with the indices of its length and pointer arguments.
+const PUBLIC_KEY_ARGS: &[(&str, usize, usize)] = &[
+    ("promise_batch_action_stake", 2, 3),
+    ("promise_batch_action_add_key_with_full_access", 1, 2),
+    ("promise_batch_action_add_key_with_function_call", 1, 2),
+    ("promise_batch_action_delete_key", 1, 2),
+];
+
+/// A public key, as nearcore stores it in contexts and key actions: a byte with the `KeyType`,
+/// followed by the key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublicKey {
+    pub key_type: KeyType,
+    pub data: Vec<u8>,
+}
+
+fn invalid(reason: String) -> String {
+    format!("InvalidPublicKey: {}", reason)
+}
+
+impl PublicKey {
+    /// Reads a serialized key: the `KeyType` byte, then 32 bytes for ed25519 or 64 for secp256k1.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let (&first, data) = bytes.split_first().ok_or_else(|| invalid("the key is empty".to_string()))?;
+        let key_type = KeyType::from_byte(first).ok_or_else(|| invalid(format!("unknown key type {}", first)))?;
+        if data.len() != key_type.data_len() {
+            return Err(invalid(format!(
+                "{} keys have {} bytes, found {}",
+                key_type.name(),
+                key_type.data_len(),
+                data.len(),
+            )));
+        }
+        Ok(PublicKey { key_type, data: data.to_vec() })
+    }
+
+    /// Parses `ed25519:<base58>` and `secp256k1:<base58>` keys, and base58 keys without a curve:
+    /// either a bare ed25519 key of 32 bytes, or a serialized key, see `from_bytes`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (key_type, data) = match s.find(':') {
+            Some(colon) => {
+                let key_type = KeyType::from_name(&s[..colon]).ok_or_else(|| invalid(format!("unknown curve {}", &s[..colon])))?;
+                (Some(key_type), &s[colon + 1..])
+            }
+            None => (None, s),
+        };
+        let bytes = bs58::decode(data).into_vec().map_err(|e| invalid(format!("{} isn't base58: {}", data, e)))?;
+        match key_type {
+            Some(key_type) => {
+                let mut serialized = vec![key_type as u8];
+                serialized.extend(bytes);
+                PublicKey::from_bytes(&serialized)
+            }
+            None if bytes.len() == KeyType::Ed25519.data_len() => Ok(PublicKey { key_type: KeyType::Ed25519, data: bytes }),
+            None => PublicKey::from_bytes(&bytes),
+        }
+    }
+
+    /// The serialized key, which contracts get from `signer_account_pk`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.key_type as u8];
+        bytes.extend(&self.data);
+        bytes
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.key_type.name(), bs58::encode(&self.data).into_string())
+    }
+}
+
+/// Checks the public keys the host function `name` is called with, like nearcore does when it
+/// applies the action. Keys outside of `memory`, or in registers, are left for `VMLogic`.
+pub fn check_host_call(name: &str, args: &[u64], memory: &dyn MemoryLike) -> Option<VMLogicError> {
+    let &(_, len, ptr) = PUBLIC_KEY_ARGS.iter().find(|(function, _, _)| *function == name)?;
+    let (len, ptr) = (*args.get(len)?, *args.get(ptr)?);
+    if len == u64::max_value() || !memory.fits_memory(ptr, len) {
+        return None;
+    }
+    let valid = len as usize <= 1 + KeyType::Secp256k1.data_len() && {
+        let mut key = vec![0; len as usize];
+        memory.read_memory(ptr, &mut key);
+        PublicKey::from_bytes(&key).is_ok()
+    };
+    if valid {
+        None
+    } else {
+        Some(VMLogicError::HostError(HostError::InvalidPublicKey))
+    }
+}
+
+/// A public key decoded for JS by `parse_public_key`.
+#[derive(Serialize)]
+struct PublicKeyView {
+    key_type: KeyType,
+    /// The key without its type, as an array of bytes.
+    data: Vec<u8>,
+    /// The serialized key, as passed to key actions.
+    bytes: Vec<u8>,
+    /// The key as `<curve>:<base58>`.
+    string: String,
+}
+
+/// Decodes a public key in any of the forms `PublicKey::parse` accepts.
+#[wasm_bindgen]
+pub fn parse_public_key(s: String) -> Result<JsValue, JsValue> {
+    let key = PublicKey::parse(&s).map_err(|e| js_sys::Error::new(&e))?;
+    let view = PublicKeyView { key_type: key.key_type, bytes: key.to_bytes(), string: key.to_string(), data: key.data };
+    Ok(serde_wasm_bindgen::to_value(&view)?)
+}
@@ -0,0 +1,238 @@
+use crate::mock::public_key::PublicKey as ParsedPublicKey;
+use crate::mock::vm_logic::{CallOptions, VM};
+use near_vm_logic::types::*;
+use near_vm_logic::{ReturnData, VMContext};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A receipt recorded on chain, with everything needed to run it again locally.
+///
+/// Bundles are JSON files. Byte strings are base64 encoded, like in the RPC responses they are
+/// usually captured from, except for the public key and random seed, which are base58.
+#[derive(Deserialize)]
+pub struct ReplayBundle {
+    pub receipt: RecordedReceipt,
+    /// The contract state before the receipt, mapping base64 keys to base64 values.
+    #[serde(default)]
+    pub state: BTreeMap<String, String>,
+    /// Path of the contract's wasm, relative to the bundle.
+    pub code: String,
+    /// The outcome recorded on chain, to compare the replay with.
+    #[serde(default)]
+    pub outcome: Option<RecordedOutcome>,
+}
+
+#[derive(Deserialize)]
+pub struct RecordedReceipt {
+    pub receiver_id: AccountId,
+    pub predecessor_id: AccountId,
+    pub signer_id: AccountId,
+    #[serde(default)]
+    pub signer_public_key: String,
+    pub method: String,
+    #[serde(default)]
+    pub args: String,
+    /// Decimal `u128`s.
+    #[serde(default)]
+    pub deposit: Option<String>,
+    pub account_balance: String,
+    #[serde(default)]
+    pub account_locked_balance: Option<String>,
+    pub storage_usage: StorageUsage,
+    pub gas: Gas,
+    pub block_index: BlockIndex,
+    pub block_timestamp: u64,
+    #[serde(default)]
+    pub random_seed: String,
+    /// Results of the promises a callback depends on, `null` for failed ones.
+    #[serde(default)]
+    pub promise_results: Vec<Option<String>>,
+}
+
+/// The parts of an outcome to compare. Fields that weren't recorded aren't compared.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct RecordedOutcome {
+    /// Base64 encoded.
+    pub return_value: Option<String>,
+    pub logs: Option<Vec<String>>,
+    pub burnt_gas: Option<Gas>,
+    pub used_gas: Option<Gas>,
+    pub storage_usage: Option<StorageUsage>,
+    pub balance: Option<String>,
+    /// Whether the receipt failed.
+    pub failed: Option<bool>,
+}
+
+/// A field of the replayed outcome that differs from the recorded one.
+#[derive(Serialize)]
+pub struct Mismatch {
+    pub field: &'static str,
+    pub expected: Value,
+    pub actual: Value,
+}
+
+pub struct Replay {
+    /// The VM after the replay, to inspect its state.
+    pub vm: VM,
+    /// The replayed outcome, as returned by `CallResult::to_json`.
+    pub result: Value,
+    pub mismatches: Vec<Mismatch>,
+}
+
+fn decode_base64(field: &str, s: &str) -> Result<Vec<u8>, String> {
+    base64::decode(s).map_err(|e| format!("Invalid base64 in {}: {}", field, e))
+}
+
+fn decode_base58(field: &str, s: &str) -> Result<Vec<u8>, String> {
+    bs58::decode(s).into_vec().map_err(|e| format!("Invalid base58 in {}: {}", field, e))
+}
+
+fn balance(field: &str, s: Option<&String>) -> Result<Balance, String> {
+    match s {
+        Some(s) => s.trim().parse().map_err(|_| format!("Invalid u128 in {}: {}", field, s)),
+        None => Ok(0),
+    }
+}
+
+impl ReplayBundle {
+    /// Reads a bundle and the contract it points to.
+    pub fn load(path: &Path) -> Result<(Self, Vec<u8>), String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let bundle: ReplayBundle = serde_json::from_str(&contents).map_err(|e| format!("Invalid bundle: {}", e))?;
+        let code_path = path.parent().unwrap_or_else(|| Path::new(".")).join(&bundle.code);
+        let code = std::fs::read(&code_path).map_err(|e| format!("Can't read {}: {}", code_path.display(), e))?;
+        Ok((bundle, code))
+    }
+
+    fn context(&self) -> Result<VMContext, String> {
+        let r = &self.receipt;
+        Ok(VMContext {
+            current_account_id: r.receiver_id.clone(),
+            signer_account_id: r.signer_id.clone(),
+            signer_account_pk: match r.signer_public_key.as_str() {
+                "" => vec![],
+                key => ParsedPublicKey::parse(key)?.to_bytes(),
+            },
+            predecessor_account_id: r.predecessor_id.clone(),
+            input: vec![],
+            block_index: r.block_index,
+            block_timestamp: r.block_timestamp,
+            account_balance: balance("account_balance", Some(&r.account_balance))?,
+            account_locked_balance: balance("account_locked_balance", r.account_locked_balance.as_ref())?,
+            storage_usage: r.storage_usage,
+            attached_deposit: 0,
+            prepaid_gas: r.gas,
+            random_seed: decode_base58("random_seed", &r.random_seed)?,
+            is_view: false,
+            output_data_receivers: vec![],
+        })
+    }
+}
+
+fn compare<T: Serialize + PartialEq>(mismatches: &mut Vec<Mismatch>, field: &'static str, expected: Option<T>, actual: T) {
+    if let Some(expected) = expected {
+        if expected != actual {
+            mismatches.push(Mismatch {
+                field,
+                expected: serde_json::to_value(expected).unwrap_or(Value::Null),
+                actual: serde_json::to_value(actual).unwrap_or(Value::Null),
+            });
+        }
+    }
+}
+
+impl VM {
+    /// Runs a recorded receipt again on the state it was recorded with, and compares the result
+    /// with the recorded outcome, if there is one.
+    pub fn replay(bundle: &ReplayBundle, code: Vec<u8>) -> Result<Replay, String> {
+        let r = &bundle.receipt;
+        let mut vm = VM::from_context(bundle.context()?);
+        for (key, value) in &bundle.state {
+            vm.ext_mut().fake_trie.insert(decode_base64("state", key)?, decode_base64("state", value)?);
+        }
+        vm.builder.promise_results = r.promise_results.iter()
+            .map(|result| match result {
+                Some(value) => decode_base64("promise_results", value).map(PromiseResult::Successful),
+                None => Ok(PromiseResult::Failed),
+            })
+            .collect::<Result<_, _>>()?;
+        vm.load_contract(code);
+
+        let opts = CallOptions {
+            signer: Some(r.signer_id.clone()),
+            predecessor: Some(r.predecessor_id.clone()),
+            deposit: balance("deposit", r.deposit.as_ref())?,
+            gas: Some(r.gas),
+        };
+        let res = vm.execute(&r.method, decode_base64("args", &r.args)?, opts, false)?;
+
+        let mut mismatches = vec![];
+        if let Some(expected) = &bundle.outcome {
+            let outcome = &res.outcome;
+            let return_value = match &outcome.return_data {
+                ReturnData::Value(data) => base64::encode(data),
+                _ => String::new(),
+            };
+            compare(&mut mismatches, "return_value", expected.return_value.clone(), return_value);
+            compare(&mut mismatches, "logs", expected.logs.clone(), outcome.logs.clone());
+            compare(&mut mismatches, "burnt_gas", expected.burnt_gas, outcome.burnt_gas);
+            compare(&mut mismatches, "used_gas", expected.used_gas, outcome.used_gas);
+            compare(&mut mismatches, "storage_usage", expected.storage_usage, outcome.storage_usage);
+            compare(&mut mismatches, "balance", expected.balance.clone(), outcome.balance.to_string());
+            compare(&mut mismatches, "failed", expected.failed, res.error.is_some());
+        }
+        Ok(Replay { vm, result: res.to_json(), mismatches })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplayBundle;
+    use crate::mock::test_contracts::{wasm, CONTRACT};
+    use crate::mock::vm_logic::VM;
+    use serde_json::{json, Value};
+
+    /// A call to `load` on a state where `key` is `value`, recorded with `outcome`.
+    fn bundle(outcome: Value) -> ReplayBundle {
+        serde_json::from_value(json!({
+            "receipt": {
+                "receiver_id": "alice",
+                "predecessor_id": "carol",
+                "signer_id": "bob",
+                "method": "load",
+                "account_balance": "10",
+                "storage_usage": 20,
+                "gas": 100_000_000_000_000u64,
+                "block_index": 1,
+                "block_timestamp": 0
+            },
+            "state": { "a2V5": "dmFsdWU=" },
+            "code": "contract.wasm",
+            "outcome": outcome
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_the_recorded_outcome() {
+        let bundle = bundle(json!({ "return_value": "dmFsdWU=", "logs": [], "storage_usage": 20, "failed": false }));
+        let replay = VM::replay(&bundle, wasm(CONTRACT)).unwrap();
+        assert!(replay.mismatches.is_empty());
+        assert_eq!(replay.result["return_value"], "value");
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        // The recorded value is `other`, where the replay returns `value`.
+        let bundle = bundle(json!({ "return_value": "b3RoZXI=", "storage_usage": 20, "failed": false }));
+        let replay = VM::replay(&bundle, wasm(CONTRACT)).unwrap();
+        assert_eq!(replay.mismatches.len(), 1);
+        let mismatch = &replay.mismatches[0];
+        assert_eq!(mismatch.field, "return_value");
+        assert_eq!(mismatch.expected, "b3RoZXI=");
+        assert_eq!(mismatch.actual, "dmFsdWU=");
+    }
+}
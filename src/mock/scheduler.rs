@@ -0,0 +1,901 @@
+use crate::mock::diff::AccountView;
+use crate::mock::faults::Faults;
+use crate::mock::hooks::HostCall;
+use crate::mock::interpreter;
+use crate::mock::seed::{self, SeedDerivation};
+use crate::utils::{display_bytes, Rng};
+use near_runtime_fees::RuntimeFeesConfig;
+use near_vm_logic::mocks::mock_external::MockedExternal;
+use near_vm_logic::types::*;
+use near_vm_logic::{ReturnData, VMConfig, VMContext};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+pub type ShardId = u64;
+
+/// An account of the ledger the scheduler runs receipts against.
+#[derive(Default, Clone)]
+pub struct Account {
+    pub code: Option<Vec<u8>>,
+    pub ext: MockedExternal,
+    pub balance: Balance,
+    pub storage_usage: StorageUsage,
+    /// How many of the receipts created in `ext` were already scheduled.
+    seen_receipts: usize,
+}
+
+/// Mirror of the actions recorded by `MockedExternal`, whose fields are private. Actions the
+/// scheduler doesn't apply are kept as JSON.
+#[derive(Deserialize, Clone)]
+pub enum Action {
+    CreateAccount,
+    DeployContract { code: Vec<u8> },
+    FunctionCall { method_name: Vec<u8>, args: Vec<u8>, gas: Gas, deposit: Balance },
+    Transfer { deposit: Balance },
+    Stake(serde_json::Value),
+    AddKeyWithFullAccess(serde_json::Value),
+    AddKeyWithFunctionCall(serde_json::Value),
+    DeleteKey(serde_json::Value),
+    DeleteAccount(serde_json::Value),
+}
+
+impl Action {
+    fn describe(&self) -> String {
+        match self {
+            Action::CreateAccount => "CreateAccount".to_string(),
+            Action::DeployContract { .. } => "DeployContract".to_string(),
+            Action::FunctionCall { method_name, .. } => format!("FunctionCall({})", display_bytes(method_name)),
+            Action::Transfer { deposit } => format!("Transfer({})", deposit),
+            Action::Stake(_) => "Stake".to_string(),
+            Action::AddKeyWithFullAccess(_) => "AddKeyWithFullAccess".to_string(),
+            Action::AddKeyWithFunctionCall(_) => "AddKeyWithFunctionCall".to_string(),
+            Action::DeleteKey(_) => "DeleteKey".to_string(),
+            Action::DeleteAccount(_) => "DeleteAccount".to_string(),
+        }
+    }
+}
+
+/// Mirror of `mock_external::Receipt`.
+#[derive(Deserialize)]
+struct CreatedReceipt {
+    receipt_indices: Vec<u64>,
+    receiver_id: AccountId,
+    actions: Vec<Action>,
+}
+
+/// What was paid for a receipt before it ran, and can be refunded.
+#[derive(Clone, Copy, Default)]
+pub struct Paid {
+    /// Whether its deposits were taken from the predecessor's balance.
+    pub deposits: bool,
+    /// Whether the signer bought its prepaid gas, at `gas_price`.
+    pub gas: bool,
+}
+
+/// A receipt waiting to run.
+#[derive(Clone)]
+pub struct PendingReceipt {
+    pub id: u64,
+    pub predecessor_id: AccountId,
+    pub signer_id: AccountId,
+    pub receiver_id: AccountId,
+    pub actions: Vec<Action>,
+    /// The receipts whose results this one waits for, in the order of its promise results.
+    pub dependencies: Vec<u64>,
+    /// The results that were sent, with the first block they can be used in.
+    results: HashMap<u64, (PromiseResult, BlockIndex)>,
+    /// The receipts waiting for the result of this one, with the dependency they receive it as.
+    /// They differ when a receipt returns a promise, which then answers for it.
+    dependents: Vec<(u64, u64)>,
+    /// The block the receipt was created in. It runs in a later block.
+    pub created_at: BlockIndex,
+    /// The first block the receipt can run in, later than the one after `created_at` when it
+    /// crosses shards.
+    pub delivered_at: BlockIndex,
+    pub paid: Paid,
+}
+
+impl PendingReceipt {
+    fn is_ready(&self, block_index: BlockIndex) -> bool {
+        self.delivered_at <= block_index
+            && self.dependencies.iter().all(|d| self.results.get(d).map_or(false, |(_, at)| *at <= block_index))
+    }
+}
+
+/// A summary of a pending receipt, for JS.
+#[derive(Serialize)]
+pub struct PendingReceiptView {
+    pub id: u64,
+    pub predecessor_id: AccountId,
+    pub receiver_id: AccountId,
+    pub actions: Vec<String>,
+    pub dependencies: Vec<u64>,
+    /// The dependencies whose results were sent, including those still crossing shards.
+    pub resolved: Vec<u64>,
+    pub shard_id: ShardId,
+    pub delivered_at: BlockIndex,
+}
+
+impl PendingReceiptView {
+    pub fn new(receipt: &PendingReceipt, sharding: &Sharding) -> Self {
+        PendingReceiptView {
+            id: receipt.id,
+            predecessor_id: receipt.predecessor_id.clone(),
+            receiver_id: receipt.receiver_id.clone(),
+            actions: receipt.actions.iter().map(Action::describe).collect(),
+            dependencies: receipt.dependencies.clone(),
+            resolved: receipt.dependencies.iter().filter(|d| receipt.results.contains_key(d)).cloned().collect(),
+            shard_id: sharding.shard_of(&receipt.receiver_id),
+            delivered_at: receipt.delivered_at,
+        }
+    }
+}
+
+/// A receipt that ran, as reported by `Scheduler::step`.
+#[derive(Serialize, Clone)]
+pub struct ExecutedReceipt {
+    pub id: u64,
+    pub block_index: BlockIndex,
+    /// The shard of the receiver, where the receipt ran.
+    pub shard_id: ShardId,
+    pub predecessor_id: AccountId,
+    pub receiver_id: AccountId,
+    pub actions: Vec<String>,
+    pub logs: Vec<String>,
+    /// The value returned by the last function call, rendered with `display_bytes`.
+    pub return_value: Option<String>,
+    /// The receipt the result was forwarded to, if the last function call returned a promise.
+    pub forwarded_to: Option<u64>,
+    pub error: Option<String>,
+    pub burnt_gas: Gas,
+    /// The gas burnt, and attached to the receipts its function calls created.
+    pub used_gas: Gas,
+    /// The host functions its function calls made, for the reports of broken invariants.
+    #[serde(skip)]
+    pub trace: Vec<HostCall>,
+    /// The refund receipts scheduled after the receipt ran, see `Scheduler::refund`.
+    pub refunds: Vec<u64>,
+    /// The receipts its function calls created.
+    pub created: Vec<u64>,
+    /// How its seed was derived, when the scheduler derives seeds per receipt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<SeedDerivation>,
+}
+
+/// A call and everything it spawned, once finished, see `VM::call_and_wait`.
+#[derive(Serialize)]
+pub struct FinishedCall {
+    pub id: u64,
+    /// The value the call resolved to, after following the promises it returned, rendered with
+    /// `display_bytes`.
+    pub return_value: Option<String>,
+    /// The error of the receipt the call resolved to, if it failed.
+    pub error: Option<String>,
+    /// The receipts that ran for the call, in order, including callbacks and refunds.
+    pub receipts: Vec<ExecutedReceipt>,
+}
+
+impl FinishedCall {
+    pub fn new(id: u64, receipts: Vec<ExecutedReceipt>) -> Self {
+        let mut last = receipts.iter().find(|r| r.id == id);
+        while let Some(forwarded) = last.and_then(|r| r.forwarded_to) {
+            last = receipts.iter().find(|r| r.id == forwarded);
+        }
+        FinishedCall {
+            id,
+            return_value: last.and_then(|r| r.return_value.clone()),
+            error: last.and_then(|r| r.error.clone()),
+            receipts,
+        }
+    }
+}
+
+/// How to pick the order of the receipts that are ready in a block.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Ordering {
+    /// Seed of the random order, so that an ordering can be replayed.
+    pub seed: u64,
+    /// Probability of putting off a ready receipt to the next block, which lets calls from other
+    /// signers land between a call and its callback.
+    pub delay_probability: f64,
+}
+
+/// How accounts are split into shards, and what crossing them costs.
+///
+/// An account is on the shard `accounts` assigns it to, or else on the one its id falls into in
+/// `boundary_accounts`, like the shard layouts of nearcore: shard 0 holds the ids before the
+/// first boundary, shard 1 those from the first boundary to the second, and so on, comparing ids
+/// as strings. With the defaults, every account is on shard 0.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Sharding {
+    pub accounts: BTreeMap<AccountId, ShardId>,
+    /// Sorted by `Scheduler::set_sharding`.
+    pub boundary_accounts: Vec<AccountId>,
+    /// How many blocks a receipt, or the result of one, takes to reach another shard, on top of
+    /// the one it waits for on its own shard.
+    pub cross_shard_delay: u64,
+    /// How much gas the receipts of a shard can burn in a block. Once a shard reached it, the
+    /// other receipts that are ready on the shard wait in the delayed queue, which runs first in
+    /// the next block.
+    pub block_gas_limit: Option<Gas>,
+}
+
+impl Sharding {
+    pub fn shard_of(&self, account_id: &str) -> ShardId {
+        match self.accounts.get(account_id) {
+            Some(shard_id) => *shard_id,
+            None => self.boundary_accounts.iter().filter(|boundary| boundary.as_str() <= account_id).count() as ShardId,
+        }
+    }
+
+    /// The first block a receipt, or a result, sent in `block_index` from `from` to `to` can
+    /// be used in.
+    pub fn delivery(&self, block_index: BlockIndex, from: &str, to: &str) -> BlockIndex {
+        let delay = if self.shard_of(from) == self.shard_of(to) { 0 } else { self.cross_shard_delay };
+        block_index + 1 + delay
+    }
+}
+
+/// An ordering of the receipts that broke an invariant.
+#[derive(Serialize)]
+pub struct Counterexample {
+    pub seed: u64,
+    /// The ids of the receipts in the order they ran.
+    pub order: Vec<u64>,
+    pub executed: Vec<ExecutedReceipt>,
+    /// The block after which the invariant failed.
+    pub block_index: BlockIndex,
+    pub error: String,
+}
+
+/// The predecessor of refund receipts, like on chain.
+pub const SYSTEM_ACCOUNT: &str = "system";
+
+/// What an action resulted in.
+enum ActionResult {
+    Value(Vec<u8>),
+    /// The function call returned the promise of this receipt.
+    Forward(u64),
+}
+
+/// Runs receipts block by block, like the chain does: receipts created in a block run in a later
+/// one, and callbacks run once the results of all the receipts they depend on arrived.
+#[derive(Clone)]
+pub struct Scheduler {
+    pub accounts: BTreeMap<AccountId, Account>,
+    pub pending: Vec<PendingReceipt>,
+    pub block_index: BlockIndex,
+    pub block_timestamp: u64,
+    /// Nanoseconds between blocks.
+    pub block_time: u64,
+    pub signer_account_pk: PublicKey,
+    pub random_seed: Vec<u8>,
+    /// Whether receipts get a seed derived from `random_seed`, the block index and their position
+    /// in the block, see `seed::SCHEME`.
+    pub derive_seeds: bool,
+    pub config: VMConfig,
+    pub fees_config: RuntimeFeesConfig,
+    /// The price of the gas signers buy for the calls they schedule, and get refunded.
+    pub gas_price: Balance,
+    /// The ids of the receipts that ran, in order.
+    pub history: Vec<u64>,
+    pub sharding: Sharding,
+    /// The ids of the receipts put off because their shard reached its gas limit, in the order
+    /// they run.
+    pub delayed: Vec<u64>,
+    ordering: Option<(Ordering, Rng)>,
+    next_id: u64,
+    faults: Faults,
+}
+
+impl Scheduler {
+    pub fn new(context: &VMContext) -> Self {
+        Scheduler {
+            accounts: BTreeMap::new(),
+            pending: vec![],
+            block_index: context.block_index,
+            block_timestamp: context.block_timestamp,
+            block_time: 1_000_000_000,
+            signer_account_pk: context.signer_account_pk.clone(),
+            random_seed: context.random_seed.clone(),
+            derive_seeds: false,
+            config: VMConfig::default(),
+            fees_config: RuntimeFeesConfig::default(),
+            gas_price: 100_000_000,
+            history: vec![],
+            sharding: Sharding::default(),
+            delayed: vec![],
+            ordering: None,
+            next_id: 0,
+            faults: Faults::default(),
+        }
+    }
+
+    pub fn account_mut(&mut self, account_id: &str) -> &mut Account {
+        self.accounts.entry(account_id.to_string()).or_insert_with(Account::default)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Runs the receipts that are ready in a block in a random order, or in the order they were
+    /// created when `ordering` is `None`. Resets the history.
+    pub fn set_ordering(&mut self, ordering: Option<Ordering>) {
+        self.ordering = ordering.map(|ordering| {
+            let rng = Rng::new(ordering.seed);
+            (ordering, rng)
+        });
+        self.history.clear();
+    }
+
+    /// Splits the accounts into shards, see `Sharding`. Fails if the block gas limit is 0, since
+    /// no receipt could ever run.
+    pub fn set_sharding(&mut self, mut sharding: Sharding) -> Result<(), String> {
+        if sharding.block_gas_limit == Some(0) {
+            return Err("The block gas limit must be positive".to_string());
+        }
+        sharding.boundary_accounts.sort();
+        sharding.boundary_accounts.dedup();
+        self.sharding = sharding;
+        Ok(())
+    }
+
+    pub fn account_views(&self) -> BTreeMap<AccountId, AccountView> {
+        self.accounts.iter()
+            .map(|(id, account)| {
+                (id.clone(), AccountView::new(account.balance, account.storage_usage, &account.ext))
+            })
+            .collect()
+    }
+
+    /// Runs the pending receipts in the random orderings of the seeds `seeds`, each on a copy of
+    /// the scheduler, and checks `invariant` after every block. Returns the first ordering that
+    /// breaks it, which `set_ordering` with the same seed replays.
+    pub fn explore<F>(&self, seeds: std::ops::Range<u64>, ordering: &Ordering, max_blocks: u32, mut invariant: F) -> Option<Counterexample>
+    where
+        F: FnMut(&Scheduler, &[ExecutedReceipt]) -> Result<(), String>,
+    {
+        for seed in seeds {
+            let mut run = self.clone();
+            run.set_ordering(Some(Ordering { seed, ..ordering.clone() }));
+            let mut executed = vec![];
+            for _ in 0..max_blocks {
+                if run.is_idle() {
+                    break;
+                }
+                let block = run.step();
+                executed.extend(block.iter().cloned());
+                if let Err(error) = invariant(&run, &block) {
+                    return Some(Counterexample { seed, order: run.history, executed, block_index: run.block_index, error });
+                }
+            }
+        }
+        None
+    }
+
+    /// Adds a receipt sent from the shard of `sender_id`.
+    #[allow(clippy::too_many_arguments)]
+    fn push(&mut self, sender_id: &str, predecessor_id: AccountId, signer_id: AccountId, receiver_id: AccountId, actions: Vec<Action>, dependencies: Vec<u64>, paid: Paid) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        for dependency in &dependencies {
+            if let Some(receipt) = self.pending.iter_mut().find(|r| r.id == *dependency) {
+                receipt.dependents.push((id, *dependency));
+            }
+        }
+        let delivered_at = self.sharding.delivery(self.block_index, sender_id, &receiver_id);
+        self.pending.push(PendingReceipt {
+            id,
+            predecessor_id,
+            signer_id,
+            receiver_id,
+            actions,
+            dependencies,
+            results: HashMap::new(),
+            dependents: vec![],
+            created_at: self.block_index,
+            delivered_at,
+            paid,
+        });
+        id
+    }
+
+    /// Schedules a function call, as if it came from a transaction. It runs in the next block.
+    ///
+    /// The predecessor pays the deposit, and the signer the prepaid gas at `gas_price`, when they
+    /// are in the ledger. Accounts outside of it pay nothing, and get nothing refunded. Fails if
+    /// an account can't pay.
+    #[allow(clippy::too_many_arguments)]
+    pub fn schedule_call(&mut self, signer_id: AccountId, predecessor_id: AccountId, receiver_id: AccountId, method: &str, args: Vec<u8>, deposit: Balance, gas: Gas) -> Result<u64, String> {
+        let paid = Paid {
+            deposits: self.accounts.contains_key(&predecessor_id),
+            gas: self.accounts.contains_key(&signer_id),
+        };
+        let charges = [
+            (&predecessor_id, if paid.deposits { deposit } else { 0 }),
+            (&signer_id, if paid.gas { Balance::from(gas) * self.gas_price } else { 0 }),
+        ];
+        for (account_id, _) in charges.iter() {
+            let owed: Balance = charges.iter().filter(|(id, _)| id == account_id).map(|(_, amount)| amount).sum();
+            let balance = self.accounts.get(*account_id).map_or(0, |account| account.balance);
+            if owed > balance {
+                return Err(format!("Account {} can't pay {} for the call to {}, it has {}", account_id, owed, method, balance));
+            }
+        }
+        for (account_id, amount) in charges.iter() {
+            if let Some(account) = self.accounts.get_mut(*account_id) {
+                account.balance -= amount;
+            }
+        }
+        let action = Action::FunctionCall { method_name: method.as_bytes().to_vec(), args, gas, deposit };
+        let sender_id = signer_id.clone();
+        Ok(self.push(&sender_id, predecessor_id, signer_id, receiver_id, vec![action], vec![], paid))
+    }
+
+    /// Schedules the receipts `account_id` created since the last time, and returns their ids in
+    /// the order they were created. Their deposits were taken from the account's balance, and
+    /// `gas_paid` tells whether the signer bought their gas.
+    pub fn collect_receipts(&mut self, account_id: &str, signer_id: &str, gas_paid: bool) -> Result<Vec<u64>, String> {
+        let account = self.account_mut(account_id);
+        let first = account.seen_receipts;
+        let created: Vec<CreatedReceipt> = account.ext.get_receipt_create_calls()[first..].iter()
+            .map(|receipt| {
+                // The fields of the mock's receipts are private, but they can be serialized.
+                let json = serde_json::to_string(receipt).map_err(|e| e.to_string())?;
+                serde_json::from_str(&json).map_err(|e| e.to_string())
+            })
+            .collect::<Result<_, String>>()?;
+        let base = self.next_id;
+        // Nothing is scheduled unless every receipt is valid.
+        let dependencies = created.iter()
+            .map(|receipt| receipt.receipt_indices.iter()
+                .map(|index| index.checked_sub(first as u64).map(|offset| base + offset))
+                .collect::<Option<Vec<u64>>>()
+                .ok_or("Receipts can only depend on receipts created in the same execution"))
+            .collect::<Result<Vec<_>, _>>()?;
+        account.seen_receipts += created.len();
+        let mut ids = vec![];
+        for (receipt, dependencies) in created.into_iter().zip(dependencies) {
+            let paid = Paid { deposits: true, gas: gas_paid };
+            ids.push(self.push(account_id, account_id.to_string(), signer_id.to_string(), receipt.receiver_id, receipt.actions, dependencies, paid));
+        }
+        Ok(ids)
+    }
+
+    /// Runs the next block: every receipt that was created in an earlier block, or that reached
+    /// its shard, and whose dependencies' results arrived runs, in the order it was created. The
+    /// delayed queue goes first, and receipts go to it once their shard reached its gas limit.
+    pub fn step(&mut self) -> Vec<ExecutedReceipt> {
+        self.step_with(|_, _| {})
+    }
+
+    /// Same as `step`, calling `after_receipt` with the scheduler after each receipt ran.
+    pub fn step_with<F: FnMut(&Scheduler, &ExecutedReceipt)>(&mut self, mut after_receipt: F) -> Vec<ExecutedReceipt> {
+        self.block_index += 1;
+        self.block_timestamp += self.block_time;
+        let block_index = self.block_index;
+        let mut ready: Vec<u64> = self.pending.iter().filter(|r| r.is_ready(block_index)).map(|r| r.id).collect();
+        if let Some((ordering, rng)) = &mut self.ordering {
+            for i in (1..ready.len()).rev() {
+                ready.swap(i, (rng.next_u64() % (i as u64 + 1)) as usize);
+            }
+            ready.retain(|_| rng.next_f64() >= ordering.delay_probability);
+        }
+        let delayed = std::mem::replace(&mut self.delayed, vec![]);
+        // The delayed receipts the ordering put off again keep their place in the queue.
+        self.delayed = delayed.iter().filter(|id| !ready.contains(id)).cloned().collect();
+        ready.sort_by_key(|id| delayed.iter().position(|d| d == id).unwrap_or(delayed.len()));
+        let mut burnt_gas: BTreeMap<ShardId, Gas> = BTreeMap::new();
+        let mut executed = vec![];
+        for id in ready {
+            let index = self.pending.iter().position(|r| r.id == id).expect("Ready receipts are pending");
+            let shard_id = self.sharding.shard_of(&self.pending[index].receiver_id);
+            let burnt = burnt_gas.entry(shard_id).or_insert(0);
+            if self.sharding.block_gas_limit.map_or(false, |limit| *burnt >= limit) {
+                self.delayed.push(id);
+                continue;
+            }
+            let receipt = self.pending.remove(index);
+            let receipt = self.execute(receipt, executed.len() as u64);
+            *burnt_gas.entry(shard_id).or_insert(0) += receipt.burnt_gas;
+            self.history.push(id);
+            after_receipt(self, &receipt);
+            executed.push(receipt);
+        }
+        executed
+    }
+
+    /// Runs `receipt`, the one at `receipt_index` in the block.
+    fn execute(&mut self, receipt: PendingReceipt, receipt_index: u64) -> ExecutedReceipt {
+        let promise_results: Vec<PromiseResult> = receipt.dependencies.iter().map(|d| receipt.results[d].0.clone()).collect();
+        let mut executed = ExecutedReceipt {
+            id: receipt.id,
+            block_index: self.block_index,
+            shard_id: self.sharding.shard_of(&receipt.receiver_id),
+            predecessor_id: receipt.predecessor_id.clone(),
+            receiver_id: receipt.receiver_id.clone(),
+            actions: receipt.actions.iter().map(Action::describe).collect(),
+            logs: vec![],
+            return_value: None,
+            forwarded_to: None,
+            error: None,
+            burnt_gas: 0,
+            used_gas: 0,
+            trace: vec![],
+            refunds: vec![],
+            created: vec![],
+            seed: if self.derive_seeds {
+                Some(SeedDerivation::new(&self.random_seed, self.block_index, receipt_index))
+            } else {
+                None
+            },
+        };
+        // A failed receipt is rolled back as a whole, including the actions that succeeded.
+        let receiver = self.accounts.get(&receipt.receiver_id).cloned();
+        let first_created = self.next_id;
+        let mut result = Ok(ActionResult::Value(vec![]));
+        for action in &receipt.actions {
+            result = self.apply(&receipt, action, &promise_results, &mut executed);
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_err() {
+            match receiver {
+                Some(account) => self.accounts.insert(receipt.receiver_id.clone(), account),
+                None => self.accounts.remove(&receipt.receiver_id),
+            };
+            self.pending.retain(|r| r.id < first_created);
+            executed.created.clear();
+        }
+        executed.refunds = self.refund(&receipt, result.is_err(), &executed);
+        match result {
+            Ok(ActionResult::Value(value)) => {
+                executed.return_value = Some(display_bytes(&value));
+                self.deliver(&receipt.receiver_id, &receipt.dependents, PromiseResult::Successful(value));
+            }
+            Ok(ActionResult::Forward(id)) => {
+                executed.forwarded_to = Some(id);
+                if let Some(forwarded) = self.pending.iter_mut().find(|r| r.id == id) {
+                    forwarded.dependents.extend(receipt.dependents.iter().cloned());
+                }
+            }
+            Err(e) => {
+                executed.error = Some(e);
+                self.deliver(&receipt.receiver_id, &receipt.dependents, PromiseResult::Failed);
+            }
+        }
+        executed
+    }
+
+    /// Schedules the refunds of what was paid for `receipt`, once it ran. If it `failed`, its
+    /// deposits go back to the predecessor, and the gas it didn't burn to the signer. Otherwise the
+    /// gas it didn't use goes back to the signer, since the gas it attached to the receipts it
+    /// created is theirs to refund. Gas is refunded at `gas_price`, and refunds themselves aren't
+    /// refunded.
+    fn refund(&mut self, receipt: &PendingReceipt, failed: bool, executed: &ExecutedReceipt) -> Vec<u64> {
+        if receipt.predecessor_id == SYSTEM_ACCOUNT {
+            return vec![];
+        }
+        let (mut deposit, mut gas): (Balance, Gas) = (0, 0);
+        for action in &receipt.actions {
+            match action {
+                Action::Transfer { deposit: amount } => deposit += amount,
+                Action::FunctionCall { deposit: amount, gas: prepaid, .. } => {
+                    deposit += amount;
+                    gas += prepaid;
+                }
+                _ => {}
+            }
+        }
+        let spent = if failed { executed.burnt_gas } else { executed.used_gas };
+        let deposit = if failed && receipt.paid.deposits { deposit } else { 0 };
+        let gas_refund = if receipt.paid.gas { Balance::from(gas.saturating_sub(spent)) * self.gas_price } else { 0 };
+        let mut ids = vec![];
+        for &(receiver_id, amount) in [(&receipt.predecessor_id, deposit), (&receipt.signer_id, gas_refund)].iter() {
+            if amount > 0 {
+                let action = Action::Transfer { deposit: amount };
+                ids.push(self.push(&receipt.receiver_id, SYSTEM_ACCOUNT.to_string(), SYSTEM_ACCOUNT.to_string(), receiver_id.clone(), vec![action], vec![], Paid::default()));
+            }
+        }
+        ids
+    }
+
+    /// Sends the result of a receipt that ran on the shard of `sender_id` to `dependents`.
+    fn deliver(&mut self, sender_id: &str, dependents: &[(u64, u64)], result: PromiseResult) {
+        for (id, dependency) in dependents {
+            if let Some(receipt) = self.pending.iter_mut().find(|r| r.id == *id) {
+                let at = self.sharding.delivery(self.block_index, sender_id, &receipt.receiver_id);
+                receipt.results.insert(*dependency, (result.clone(), at));
+            }
+        }
+    }
+
+    fn apply(&mut self, receipt: &PendingReceipt, action: &Action, promise_results: &[PromiseResult], executed: &mut ExecutedReceipt) -> Result<ActionResult, String> {
+        let receiver_id = &receipt.receiver_id;
+        if let Action::CreateAccount = action {
+            if self.accounts.contains_key(receiver_id) {
+                return Err(format!("Account {} already exists", receiver_id));
+            }
+            self.accounts.insert(receiver_id.clone(), Account::default());
+            return Ok(ActionResult::Value(vec![]));
+        }
+        let output_data_receivers = receipt.dependents.iter()
+            .filter_map(|(id, _)| self.pending.iter().find(|r| r.id == *id).map(|r| r.receiver_id.clone()))
+            .collect();
+        let account = self.accounts.get_mut(receiver_id).ok_or_else(|| format!("Account {} doesn't exist", receiver_id))?;
+        match action {
+            Action::Transfer { deposit } => {
+                account.balance += deposit;
+                Ok(ActionResult::Value(vec![]))
+            }
+            // The storage stays, so that the function calls after the deploy, e.g. `migrate`, run
+            // the new code against the old state.
+            Action::DeployContract { code } => {
+                let old_len = account.code.as_ref().map_or(0, Vec::len) as StorageUsage;
+                account.storage_usage = (account.storage_usage + code.len() as StorageUsage).saturating_sub(old_len);
+                account.code = Some(code.clone());
+                Ok(ActionResult::Value(vec![]))
+            }
+            Action::FunctionCall { method_name, args, gas, deposit } => {
+                let method = String::from_utf8(method_name.clone()).map_err(|_| "Method names must be UTF-8".to_string())?;
+                let code = account.code.clone().ok_or_else(|| format!("Account {} has no contract", receiver_id))?;
+                let context = VMContext {
+                    current_account_id: receiver_id.clone(),
+                    signer_account_id: receipt.signer_id.clone(),
+                    signer_account_pk: self.signer_account_pk.clone(),
+                    predecessor_account_id: receipt.predecessor_id.clone(),
+                    input: args.clone(),
+                    block_index: self.block_index,
+                    block_timestamp: self.block_timestamp,
+                    account_balance: account.balance,
+                    account_locked_balance: 0,
+                    storage_usage: account.storage_usage,
+                    attached_deposit: *deposit,
+                    prepaid_gas: *gas,
+                    random_seed: match &executed.seed {
+                        Some(derivation) => derivation.seed_bytes(),
+                        None => self.random_seed.clone(),
+                    },
+                    is_view: false,
+                    output_data_receivers,
+                };
+                let execution = interpreter::run_method(
+                    &code,
+                    &method,
+                    &mut account.ext,
+                    context,
+                    &self.config,
+                    &self.fees_config,
+                    promise_results,
+                    &mut self.faults,
+                    None,
+                );
+                executed.logs.extend(execution.outcome.logs.iter().cloned());
+                executed.burnt_gas += execution.outcome.burnt_gas;
+                executed.used_gas += execution.outcome.used_gas;
+                executed.trace.extend(execution.trace);
+                if let Some(derivation) = &executed.seed {
+                    seed::record(&mut executed.trace, derivation);
+                }
+                if let Some(e) = execution.error {
+                    return Err(e.to_string());
+                }
+                account.balance = execution.outcome.balance;
+                account.storage_usage = execution.outcome.storage_usage;
+                let first = account.seen_receipts as u64;
+                let created = self.collect_receipts(receiver_id, &receipt.signer_id, receipt.paid.gas)?;
+                executed.created.extend(created.iter().cloned());
+                match execution.outcome.return_data {
+                    ReturnData::Value(value) => Ok(ActionResult::Value(value)),
+                    ReturnData::None => Ok(ActionResult::Value(vec![])),
+                    ReturnData::ReceiptIndex(index) => index.checked_sub(first)
+                        .and_then(|offset| created.get(offset as usize))
+                        .map(|id| ActionResult::Forward(*id))
+                        .ok_or_else(|| "Returned a promise from another execution".to_string()),
+                }
+            }
+            action => Err(format!("{} is not supported by the scheduler", action.describe())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::test_contracts::{context, wasm, CONTRACT, MIGRATED_CONTRACT};
+
+    const GAS: Gas = 100_000_000_000_000;
+    const BALANCE: Balance = 1_000_000_000_000_000_000_000_000;
+
+    /// A scheduler where `alice` and `bob` both have `CONTRACT`.
+    fn scheduler() -> Scheduler {
+        let mut scheduler = Scheduler::new(&context());
+        for account_id in &["alice", "bob"] {
+            let account = scheduler.account_mut(account_id);
+            account.code = Some(wasm(CONTRACT));
+            account.balance = BALANCE;
+        }
+        scheduler
+    }
+
+    fn run_until_idle(scheduler: &mut Scheduler) -> Vec<ExecutedReceipt> {
+        let mut executed = vec![];
+        for _ in 0..10 {
+            if scheduler.is_idle() {
+                break;
+            }
+            executed.extend(scheduler.step());
+        }
+        assert!(scheduler.is_idle());
+        executed
+    }
+
+    /// Schedules a call from `bob` to `alice`.
+    fn call(scheduler: &mut Scheduler, method: &str, args: &[u8], deposit: Balance) -> u64 {
+        scheduler.schedule_call("bob".to_string(), "bob".to_string(), "alice".to_string(), method, args.to_vec(), deposit, GAS).unwrap()
+    }
+
+    /// The receipts that aren't refunds.
+    fn calls(executed: &[ExecutedReceipt]) -> Vec<&ExecutedReceipt> {
+        executed.iter().filter(|r| r.predecessor_id != SYSTEM_ACCOUNT).collect()
+    }
+
+    #[test]
+    fn callbacks_get_the_results_of_their_dependencies() {
+        let mut scheduler = scheduler();
+        let id = call(&mut scheduler, "call_echo", b"hi", 0);
+        let executed = run_until_idle(&mut scheduler);
+        let calls = calls(&executed);
+        let actions: Vec<&str> = calls.iter().map(|r| r.actions[0].as_str()).collect();
+        assert_eq!(actions, ["FunctionCall(call_echo)", "FunctionCall(echo)", "FunctionCall(on_echo)"]);
+        assert!(executed.iter().all(|r| r.error.is_none()));
+        assert_eq!(calls[0].id, id);
+        assert_eq!(calls[2].return_value.as_deref(), Some("hi"));
+        // Each runs in a later block than the receipt it waits for.
+        assert!(calls[0].block_index < calls[1].block_index && calls[1].block_index < calls[2].block_index);
+        // Once the unused gas was refunded, `bob` paid for the gas that was burnt.
+        let burnt: Gas = executed.iter().map(|r| r.burnt_gas).sum();
+        assert_eq!(scheduler.accounts["bob"].balance, BALANCE - Balance::from(burnt) * scheduler.gas_price);
+    }
+
+    #[test]
+    fn returned_promises_answer_for_the_call() {
+        let mut scheduler = scheduler();
+        let id = call(&mut scheduler, "forward", b"hi", 0);
+        let executed = run_until_idle(&mut scheduler);
+        let calls = calls(&executed);
+        assert_eq!(calls[0].forwarded_to, Some(calls[1].id));
+        assert_eq!(calls[0].return_value, None);
+        let finished = FinishedCall::new(id, executed);
+        assert_eq!(finished.return_value.as_deref(), Some("hi"));
+        assert_eq!(finished.error, None);
+    }
+
+    #[test]
+    fn explores_the_orderings_of_independent_receipts() {
+        let mut scheduler = scheduler();
+        let x = call(&mut scheduler, "store", b"x", 0);
+        let y = call(&mut scheduler, "store", b"y", 0);
+        // In the order they were scheduled, `y` is written last.
+        let counterexample = scheduler.explore(0..20, &Ordering::default(), 10, |scheduler, _| {
+            match scheduler.accounts["alice"].ext.fake_trie.get(&b"key".to_vec()) {
+                Some(value) if value.as_slice() != b"y" => Err(format!("key is {}", display_bytes(value))),
+                _ => Ok(()),
+            }
+        });
+        let counterexample = counterexample.expect("One of the orderings runs `y` first");
+        assert_eq!(counterexample.order, [y, x]);
+        assert_eq!(counterexample.error, "key is x");
+        // The replay of the seed runs the same order.
+        scheduler.set_ordering(Some(Ordering { seed: counterexample.seed, ..Ordering::default() }));
+        scheduler.step();
+        assert_eq!(scheduler.history, [y, x]);
+    }
+
+    #[test]
+    fn refunds_what_failed_receipts_were_charged() {
+        let mut scheduler = scheduler();
+        let id = call(&mut scheduler, "fail", b"", 10);
+        let charged = 10 + Balance::from(GAS) * scheduler.gas_price;
+        assert_eq!(scheduler.accounts["bob"].balance, BALANCE - charged);
+        let executed = run_until_idle(&mut scheduler);
+        let failed = &executed[0];
+        assert_eq!(failed.id, id);
+        assert!(failed.error.is_some());
+        // The write and the call to `bob.echo` made before the panic are gone.
+        assert!(scheduler.accounts["alice"].ext.fake_trie.is_empty());
+        assert!(failed.created.is_empty());
+        assert_eq!(calls(&executed).len(), 1);
+        assert_eq!(scheduler.accounts["alice"].balance, BALANCE);
+        // The deposit and the gas that wasn't burnt come back, so `bob` only paid for the burnt gas.
+        assert_eq!(failed.refunds.len(), 2);
+        let refunds: Vec<&ExecutedReceipt> = executed.iter().filter(|r| failed.refunds.contains(&r.id)).collect();
+        assert_eq!(refunds.len(), 2);
+        assert!(refunds.iter().all(|r| r.error.is_none() && r.receiver_id == "bob"));
+        let burnt = Balance::from(failed.burnt_gas) * scheduler.gas_price;
+        assert_eq!(scheduler.accounts["bob"].balance, BALANCE - burnt);
+    }
+
+    #[test]
+    fn accounts_pay_for_the_calls_they_schedule() {
+        let mut scheduler = scheduler();
+        scheduler.account_mut("carol").balance = 10;
+        let res = scheduler.schedule_call("carol".to_string(), "carol".to_string(), "alice".to_string(), "store", vec![], 0, GAS);
+        assert!(res.unwrap_err().starts_with("Account carol can't pay"));
+        assert_eq!(scheduler.accounts["carol"].balance, 10);
+        assert!(scheduler.is_idle());
+        // Accounts outside of the ledger don't pay, and get nothing refunded.
+        scheduler.schedule_call("dave".to_string(), "dave".to_string(), "alice".to_string(), "fail", vec![], 10, GAS).unwrap();
+        let executed = run_until_idle(&mut scheduler);
+        assert!(executed[0].error.is_some());
+        assert!(executed[0].refunds.is_empty());
+    }
+
+    #[test]
+    fn migrates_the_old_state_with_the_new_code() {
+        let mut scheduler = scheduler();
+        call(&mut scheduler, "store", b"old", 0);
+        call(&mut scheduler, "upgrade", &wasm(MIGRATED_CONTRACT), 0);
+        let executed = run_until_idle(&mut scheduler);
+        let batch = calls(&executed)[2];
+        assert_eq!(batch.actions, ["DeployContract", "FunctionCall(migrate)"]);
+        assert_eq!(batch.error, None);
+        let alice = &scheduler.accounts["alice"];
+        assert_eq!(alice.code, Some(wasm(MIGRATED_CONTRACT)));
+        // `migrate` only exists in the new code, and read what the old code wrote.
+        assert_eq!(alice.ext.fake_trie.get(&b"key".to_vec()), Some(&b"old".to_vec()));
+        assert_eq!(alice.ext.fake_trie.get(&b"migrated".to_vec()), Some(&b"old".to_vec()));
+    }
+
+    #[test]
+    fn sorts_the_boundary_accounts() {
+        let mut scheduler = scheduler();
+        let sharding = Sharding { boundary_accounts: vec!["carol".to_string(), "bob".to_string()], ..Sharding::default() };
+        scheduler.set_sharding(sharding).unwrap();
+        let shards: Vec<ShardId> = ["alice", "bob", "carol"].iter().map(|id| scheduler.sharding.shard_of(id)).collect();
+        assert_eq!(shards, [0, 1, 2]);
+        let res = scheduler.set_sharding(Sharding { block_gas_limit: Some(0), ..Sharding::default() });
+        assert!(res.is_err());
+        assert_eq!(scheduler.sharding.boundary_accounts, ["bob", "carol"]);
+    }
+
+    #[test]
+    fn delays_receipts_and_results_across_shards() {
+        let mut scheduler = scheduler();
+        // `alice` is on shard 0 and `bob` on shard 1.
+        let sharding = Sharding { boundary_accounts: vec!["bob".to_string()], cross_shard_delay: 2, ..Sharding::default() };
+        scheduler.set_sharding(sharding).unwrap();
+        let start = scheduler.block_index;
+        scheduler.schedule_call("alice".to_string(), "alice".to_string(), "alice".to_string(), "call_echo", b"hi".to_vec(), 0, GAS).unwrap();
+        let executed = run_until_idle(&mut scheduler);
+        let calls = calls(&executed);
+        let blocks: Vec<(ShardId, BlockIndex)> = calls.iter().map(|r| (r.shard_id, r.block_index - start)).collect();
+        // `echo` reaches shard 1 two blocks late, and so does its result on the way back.
+        assert_eq!(blocks, [(0, 1), (1, 4), (0, 7)]);
+        assert_eq!(calls[2].return_value.as_deref(), Some("hi"));
+    }
+
+    #[test]
+    fn queues_receipts_once_the_shard_reached_its_gas_limit() {
+        let mut scheduler = scheduler();
+        scheduler.set_sharding(Sharding { block_gas_limit: Some(1), ..Sharding::default() }).unwrap();
+        let ids: Vec<u64> = [b"x", b"y", b"z"].iter().map(|value| call(&mut scheduler, "store", *value, 0)).collect();
+        // The first receipt burns the whole limit, so the others wait, in order.
+        assert_eq!(scheduler.step().iter().map(|r| r.id).collect::<Vec<_>>(), [ids[0]]);
+        assert_eq!(scheduler.delayed, [ids[1], ids[2]]);
+        // Receipts the ordering puts off keep their place in the queue.
+        scheduler.set_ordering(Some(Ordering { seed: 0, delay_probability: 1.0 }));
+        assert!(scheduler.step().is_empty());
+        assert_eq!(scheduler.delayed, [ids[1], ids[2]]);
+        scheduler.set_ordering(None);
+        assert_eq!(scheduler.step().iter().map(|r| r.id).collect::<Vec<_>>(), [ids[1]]);
+        assert_eq!(scheduler.delayed[0], ids[2]);
+        let executed = run_until_idle(&mut scheduler);
+        assert_eq!(calls(&executed)[0].id, ids[2]);
+        assert_eq!(scheduler.accounts["alice"].ext.fake_trie.get(&b"key".to_vec()), Some(&b"z".to_vec()));
+    }
+}
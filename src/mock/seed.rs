@@ -0,0 +1,52 @@
+use crate::mock::hooks::HostCall;
+use near_vm_logic::types::BlockIndex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// How seeds are derived, as recorded in traces.
+pub const SCHEME: &str = "sha256(base_seed || block_index as u64 LE || receipt_index as u64 LE)";
+
+/// A seed derived for a call or a receipt, with everything needed to derive it again.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SeedDerivation {
+    pub scheme: &'static str,
+    /// Base58, like `random_seed` in `context.json`.
+    pub base_seed: String,
+    pub block_index: BlockIndex,
+    /// Position of the call or receipt in its block, from 0.
+    pub receipt_index: u64,
+    /// Base58.
+    pub seed: String,
+}
+
+impl SeedDerivation {
+    pub fn new(base_seed: &[u8], block_index: BlockIndex, receipt_index: u64) -> Self {
+        SeedDerivation {
+            scheme: SCHEME,
+            base_seed: bs58::encode(base_seed).into_string(),
+            block_index,
+            receipt_index,
+            seed: bs58::encode(derive_seed(base_seed, block_index, receipt_index)).into_string(),
+        }
+    }
+
+    pub fn seed_bytes(&self) -> Vec<u8> {
+        bs58::decode(&self.seed).into_vec().expect("Derived seeds are base58")
+    }
+}
+
+/// The seed of the receipt at `receipt_index` in block `block_index`, see `SCHEME`.
+pub fn derive_seed(base_seed: &[u8], block_index: BlockIndex, receipt_index: u64) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(base_seed);
+    hasher.input(&block_index.to_le_bytes());
+    hasher.input(&receipt_index.to_le_bytes());
+    hasher.result().to_vec()
+}
+
+/// Records `derivation` on the calls to `random_seed` of `trace`, which read the seed it derived.
+pub fn record(trace: &mut [HostCall], derivation: &SeedDerivation) {
+    for call in trace.iter_mut().filter(|call| call.name == "random_seed") {
+        call.seed = Some(derivation.clone());
+    }
+}
@@ -0,0 +1,90 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+use wasm_bindgen::prelude::*;
+
+/// A value in a snapshot that differs from the expected one.
+#[derive(Serialize, Debug)]
+pub struct SnapshotDifference {
+    /// Where the value is, e.g. `outcome.logs[2]`.
+    pub path: String,
+    pub expected: Value,
+    pub actual: Value,
+}
+
+/// Renders a snapshot as canonical JSON: keys are sorted, since `serde_json` maps are ordered, and
+/// the output is indented and ends with a newline so that snapshot files diff nicely.
+pub fn to_canonical_string(snapshot: &Value) -> String {
+    let mut s = serde_json::to_string_pretty(snapshot).expect("Snapshots are valid JSON");
+    s.push('\n');
+    s
+}
+
+fn is_gas(path: &str) -> bool {
+    path.rsplit('.').next().map_or(false, |key| key.ends_with("gas"))
+}
+
+fn within_tolerance(expected: &Value, actual: &Value, tolerance: f64) -> bool {
+    match (expected.as_f64(), actual.as_f64()) {
+        (Some(expected), Some(actual)) => (actual - expected).abs() <= expected.abs() * tolerance,
+        _ => false,
+    }
+}
+
+fn compare_at(path: String, expected: &Value, actual: &Value, gas_tolerance: f64, diffs: &mut Vec<SnapshotDifference>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            let keys: std::collections::BTreeSet<&String> = expected.keys().chain(actual.keys()).collect();
+            for key in keys {
+                let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                compare_at(
+                    path,
+                    expected.get(key).unwrap_or(&Value::Null),
+                    actual.get(key).unwrap_or(&Value::Null),
+                    gas_tolerance,
+                    diffs,
+                );
+            }
+        }
+        (Value::Array(e), Value::Array(a)) if e.len() == a.len() => {
+            for (i, (expected, actual)) in e.iter().zip(a).enumerate() {
+                compare_at(format!("{}[{}]", path, i), expected, actual, gas_tolerance, diffs);
+            }
+        }
+        _ if expected == actual => {}
+        _ if is_gas(&path) && within_tolerance(expected, actual, gas_tolerance) => {}
+        _ => diffs.push(SnapshotDifference { path, expected: expected.clone(), actual: actual.clone() }),
+    }
+}
+
+/// Lists the values that differ between two snapshots. Gas amounts, i.e. fields whose name ends
+/// with `gas`, may differ by `gas_tolerance` times the expected amount.
+pub fn compare(expected: &Value, actual: &Value, gas_tolerance: f64) -> Vec<SnapshotDifference> {
+    let mut diffs = vec![];
+    compare_at(String::new(), expected, actual, gas_tolerance, &mut diffs);
+    diffs
+}
+
+/// Compares `actual` with the snapshot stored at `path`. When `update` is set, or there is no
+/// snapshot yet, writes `actual` to `path` instead.
+pub fn check_file(path: &Path, actual: &Value, update: bool, gas_tolerance: f64) -> Result<Vec<SnapshotDifference>, String> {
+    if update || !path.exists() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+        }
+        std::fs::write(path, to_canonical_string(actual)).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+        return Ok(vec![]);
+    }
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let expected: Value = serde_json::from_str(&contents).map_err(|e| format!("Invalid snapshot {}: {}", path.display(), e))?;
+    Ok(compare(&expected, actual, gas_tolerance))
+}
+
+/// Same as `compare`, for snapshots rendered by `VM.snapshot`. The files are read and written on
+/// the JS side, see `matchSnapshot` in `imports.js`.
+#[wasm_bindgen]
+pub fn compare_snapshots(expected: String, actual: String, gas_tolerance: f64) -> Result<JsValue, JsValue> {
+    let expected: Value = serde_json::from_str(&expected).map_err(|e| js_sys::Error::new(&format!("Invalid snapshot: {}", e)))?;
+    let actual: Value = serde_json::from_str(&actual).map_err(|e| js_sys::Error::new(&format!("Invalid snapshot: {}", e)))?;
+    Ok(serde_wasm_bindgen::to_value(&compare(&expected, &actual, gas_tolerance))?)
+}
@@ -0,0 +1,45 @@
+//! Contracts and contexts shared by the tests of the interpreter, the scheduler and the VM.
+
+use crate::mock::context_builder::ContextBuilder;
+use near_vm_logic::types::Gas;
+use near_vm_logic::VMContext;
+
+/// The gas `CONTRACT` attaches to the receipts it creates.
+pub const ATTACHED_GAS: Gas = 10_000_000_000_000;
+
+/// A contract for the accounts `alice` and `bob`, with the methods:
+/// * `store`: writes its input under `key`;
+/// * `load`: returns the value under `key`;
+/// * `echo`: returns its input;
+/// * `call_echo`: calls `bob.echo` with its input, then `alice.on_echo` with the result;
+/// * `on_echo`: returns the result of the promise it is a callback of;
+/// * `forward`: calls `bob.echo` with its input, and returns the promise;
+/// * `fail`: writes `key`, calls `bob.echo`, then panics;
+/// * `upgrade`: deploys the code in its input to `alice`, and calls `migrate` in the same batch;
+/// * `bad_register`: reads a register that was never written.
+pub const CONTRACT: &str = include_str!("../../tests/fixtures/contract.wat");
+
+/// The code `CONTRACT` upgrades to in the tests: `migrate` copies the value under `key` to
+/// `migrated`.
+pub const MIGRATED_CONTRACT: &str = r#"(module
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "storage_write" (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "storage_read" (func $storage_read (param i64 i64 i64) (result i64)))
+  (memory (export "memory") 1)
+  (data (i32.const 16) "key")
+  (data (i32.const 32) "migrated")
+  (func (export "migrate")
+    (drop (call $storage_read (i64.const 3) (i64.const 16) (i64.const 0)))
+    (call $read_register (i64.const 0) (i64.const 1024))
+    (drop (call $storage_write (i64.const 8) (i64.const 32) (call $register_len (i64.const 0)) (i64.const 1024) (i64.const 1)))))
+"#;
+
+pub fn wasm(wat: &str) -> Vec<u8> {
+    wat::parse_str(wat).expect("Test contracts are valid")
+}
+
+/// The default context, of `alice` called by `carol` on behalf of `bob`.
+pub fn context() -> VMContext {
+    ContextBuilder::default().build_context().expect("The default context is valid")
+}
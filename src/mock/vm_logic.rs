@@ -1,12 +1,33 @@
-use serde::{Serialize};
+use serde::{Deserialize, Serialize};
 use near_vm_logic::mocks::mock_external::MockedExternal;
+use crate::mock::account_id::{self, validate_account_id};
+use crate::mock::collections::{RenderMode, StorageView};
+use crate::mock::context::*;
+use crate::mock::determinism::{Nondeterminism, RunCapture};
+use crate::mock::diff::*;
+use crate::mock::faults::*;
+use crate::mock::hooks::*;
+use crate::mock::invariants::{self, InvariantFailure, Invariants};
+#[cfg(feature = "interpreter")]
+use crate::mock::coverage::Coverage;
+#[cfg(feature = "interpreter")]
+use crate::mock::interpreter::{self, ExecutionError};
+#[cfg(feature = "interpreter")]
+use crate::mock::scheduler::*;
 use crate::mock::memory::*;
+use crate::mock::public_key::{self, PublicKey as ParsedPublicKey};
+use crate::mock::seed::{self, SeedDerivation};
+use crate::mock::snapshot::to_canonical_string;
 use near_vm_logic::types::*;
 use near_vm_logic::*;
 use wasm_bindgen::prelude::*;
 
 use near_runtime_fees::RuntimeFeesConfig;
 use crate::utils::*;
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "interpreter")]
+use std::collections::HashSet;
+use wasm_bindgen_futures::future_to_promise;
 // lifted from the `console_log` example
 #[wasm_bindgen]
 extern "C" {
@@ -70,12 +91,153 @@ impl VMLogicBuilder {
 
 #[wasm_bindgen]
 pub struct VM {
-    builder: VMLogicBuilder,
+    pub(crate) builder: VMLogicBuilder,
     context: VMContext,
     internal_state: Option<InternalVMState>,
-    saved_state: Option<InternalVMState>,
+    saved_state: Option<Checkpoint>,
     saved_context: Option<VMContext>,
-    saved_ext: Option<MockedExternal>
+    checkpoints: HashMap<String, Checkpoint>,
+    pending_call: Option<PendingCall>,
+    last_error: Option<VMLogicError>,
+    balance_includes_deposit: bool,
+    hooks: HostHooks,
+    faults: Faults,
+    /// The host functions called since the last call started.
+    trace: Vec<HostCall>,
+    invariants: Invariants,
+    /// The state to go back to once JS ran the invariants of a call, see `begin_invariants`.
+    pending_invariants: Option<PendingInvariants>,
+    /// Whether calls run twice, see `set_determinism_check`.
+    check_determinism: bool,
+    nondeterminism: Vec<Nondeterminism>,
+    /// Whether calls get a seed derived from `random_seed`, see `set_seed_derivation`.
+    derive_seeds: bool,
+    /// The block of the last call that wasn't rolled back, and how many of those ran in it.
+    calls_in_block: (BlockIndex, u64),
+    /// The seed derived for the current or last call.
+    seed_derivation: Option<SeedDerivation>,
+    #[cfg(feature = "interpreter")]
+    contract: Option<Vec<u8>>,
+    /// Block counts of the loaded contract, once instrumented by `instrument_contract`.
+    #[cfg(feature = "interpreter")]
+    coverage: Option<Coverage>,
+    #[cfg(feature = "interpreter")]
+    scheduler: Scheduler,
+}
+
+/// Everything needed to undo a method call started by `VM::begin_call`.
+struct PendingCall {
+    method: String,
+    is_view: bool,
+    context: VMContext,
+    internal_state: Option<InternalVMState>,
+    ext: MockedExternal,
+    /// Set when the call runs twice to check its determinism.
+    rerun: Option<Rerun>,
+}
+
+/// What a call needs to run again from the same checkpoint, see `VM::restart_call`.
+struct Rerun {
+    /// The context of the call, as set up by `start_call`.
+    context: VMContext,
+    faults: Faults,
+    /// The counts of the hooks, which count-based hooks see again in the second run.
+    hook_counts: HashMap<String, u64>,
+    first: Option<RunCapture>,
+}
+
+/// The call whose invariants JS is running, and the state they may not change.
+struct PendingInvariants {
+    call: String,
+    ext: MockedExternal,
+    internal_state: Option<InternalVMState>,
+    trace: Vec<HostCall>,
+}
+
+/// Optional receipt fields for `VM::call` and `VM::view`. `deposit` is read separately from JS,
+/// since it can be a `BigInt` or a decimal string.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct CallOptions {
+    pub signer: Option<AccountId>,
+    pub predecessor: Option<AccountId>,
+    #[serde(skip)]
+    pub deposit: Balance,
+    pub gas: Option<Gas>,
+}
+
+impl CallOptions {
+    pub fn from_js(opts: JsValue) -> Result<Self, JsValue> {
+        if opts.is_undefined() || opts.is_null() {
+            return Ok(CallOptions::default());
+        }
+        let deposit = js_sys::Reflect::get(&opts, &"deposit".into())?;
+        let mut res: CallOptions = serde_wasm_bindgen::from_value(opts)?;
+        if !deposit.is_undefined() {
+            res.deposit = u128_from_js(&deposit)?;
+        }
+        Ok(res)
+    }
+}
+
+/// The least prepaid gas a method succeeds with, as found by `VM::estimate_method_gas`.
+#[cfg(feature = "interpreter")]
+#[derive(Serialize)]
+pub struct GasEstimate {
+    pub prepaid_gas: Gas,
+    /// The gas the method burnt with `prepaid_gas`.
+    pub burnt_gas: Gas,
+    /// `burnt_gas` plus `promise_gas`.
+    pub used_gas: Gas,
+    /// The gas attached to the promises the method created.
+    pub promise_gas: Gas,
+    /// How many times the method ran.
+    pub attempts: u32,
+}
+
+/// The result of a method call.
+pub struct CallResult {
+    pub outcome: VMOutcome,
+    pub error: Option<String>,
+    pub faults: Vec<FiredFault>,
+}
+
+impl CallResult {
+    /// The outcome of the call, with its return value parsed as JSON.
+    pub fn to_js(self) -> Result<JsValue, JsValue> {
+        let return_value = match &self.outcome.return_data {
+            ReturnData::Value(data) => {
+                let s = String::from_utf8_lossy(data);
+                js_sys::JSON::parse(&s).unwrap_or_else(|_| JsValue::from_str(&s))
+            }
+            _ => JsValue::NULL,
+        };
+        let mut outcome = _VMOutcome::from(self.outcome);
+        outcome.faults = self.faults;
+        let outcome = serde_wasm_bindgen::to_value(&outcome)?;
+        js_sys::Reflect::set(&outcome, &"return_value".into(), &return_value)?;
+        js_sys::Reflect::set(&outcome, &"error".into(), &self.error.map(JsValue::from).unwrap_or(JsValue::NULL))?;
+        Ok(outcome)
+    }
+
+    /// Same as `to_js`, for native callers.
+    pub fn to_json(self) -> serde_json::Value {
+        let return_value = match &self.outcome.return_data {
+            ReturnData::Value(data) => serde_json::from_slice(data)
+                .unwrap_or_else(|_| String::from_utf8_lossy(data).into_owned().into()),
+            _ => serde_json::Value::Null,
+        };
+        let mut outcome = _VMOutcome::from(self.outcome);
+        outcome.faults = self.faults;
+        let mut res = serde_json::to_value(&outcome).expect("Outcomes are valid JSON");
+        res["return_value"] = return_value;
+        res["error"] = self.error.into();
+        res
+    }
+}
+
+fn host_error(e: &VMLogicError) -> JsValue {
+    js_sys::Error::new(&format!("{:?}", e)).into()
 }
 
 #[allow(dead_code)]
@@ -84,20 +246,706 @@ fn print_str(s: String) {
 }
 
 
-#[wasm_bindgen]
 impl VM {
-    #[wasm_bindgen(constructor)]
-    pub fn new(context: JsValue) -> Self {
-        set_panic_hook();
-        let c: VMContext = serde_wasm_bindgen::from_value(context).unwrap();
+    pub fn from_context(context: VMContext) -> Self {
+        #[cfg(feature = "interpreter")]
+        let scheduler = Scheduler::new(&context);
         Self {
             builder: VMLogicBuilder::default(),
-            context: c,
+            context,
             internal_state: None,
             saved_state: None,
             saved_context: None,
-            saved_ext: None
+            checkpoints: HashMap::new(),
+            pending_call: None,
+            last_error: None,
+            balance_includes_deposit: false,
+            hooks: HostHooks::default(),
+            faults: Faults::default(),
+            trace: vec![],
+            invariants: Invariants::default(),
+            pending_invariants: None,
+            check_determinism: false,
+            nondeterminism: vec![],
+            derive_seeds: false,
+            calls_in_block: (0, 0),
+            seed_derivation: None,
+            #[cfg(feature = "interpreter")]
+            contract: None,
+            #[cfg(feature = "interpreter")]
+            coverage: None,
+            #[cfg(feature = "interpreter")]
+            scheduler,
+        }
+    }
+
+    pub fn ext(&self) -> &MockedExternal {
+        &self.builder.ext
+    }
+
+    pub fn context(&self) -> &VMContext {
+        &self.context
+    }
+
+    pub fn ext_mut(&mut self) -> &mut MockedExternal {
+        &mut self.builder.ext
+    }
+
+    /// Collects what a call did into a snapshot: its `outcome`, as returned by
+    /// `CallResult::to_json`, the receipts created and the storage diff since the checkpoint `since`.
+    pub fn snapshot_value(&mut self, outcome: serde_json::Value, since: &str) -> Result<serde_json::Value, String> {
+        let now = self.checkpoint_now();
+        let from = self.checkpoints.get(since).ok_or_else(|| format!("No checkpoint named {}", since))?;
+        let receipts = now.ext.get_receipt_create_calls();
+        let receipts = receipts.get(from.ext.get_receipt_create_calls().len()..).unwrap_or(&[]);
+        Ok(serde_json::json!({
+            "outcome": outcome,
+            "receipts": receipts,
+            "storage_diff": StorageDiff::between(from, &now),
+        }))
+    }
+
+    /// Prepares the VM to execute `method` as a new receipt with the input `args`.
+    pub fn start_call(&mut self, method: String, args: Vec<u8>, opts: CallOptions, is_view: bool) -> Result<(), String> {
+        if let Some(pending) = &self.pending_call {
+            return Err(format!("Method {} is already being called", pending.method));
+        }
+        for account_id in opts.signer.iter().chain(&opts.predecessor) {
+            validate_account_id(account_id)?;
+        }
+        let current = self.current_outcome();
+        self.pending_call = Some(PendingCall {
+            method,
+            is_view,
+            context: self.context.clone(),
+            internal_state: self.internal_state.take(),
+            ext: self.builder.ext.clone(),
+            rerun: None,
+        });
+        self.last_error = None;
+        self.trace.clear();
+        // The receipt starts from whatever the previous execution left behind.
+        self.context.storage_usage = current.storage_usage;
+        self.context.account_balance = current.balance;
+        self.context.input = args;
+        self.context.is_view = is_view;
+        self.context.attached_deposit = opts.deposit;
+        if let Some(signer) = opts.signer {
+            self.context.signer_account_id = signer;
+        }
+        if let Some(predecessor) = opts.predecessor {
+            self.context.predecessor_account_id = predecessor;
+        }
+        if let Some(gas) = opts.gas {
+            self.context.prepaid_gas = gas;
+        }
+        self.seed_derivation = None;
+        if self.derive_seeds {
+            let block_index = self.context.block_index;
+            let index = if self.calls_in_block.0 == block_index { self.calls_in_block.1 } else { 0 };
+            let derivation = SeedDerivation::new(&self.context.random_seed, block_index, index);
+            self.context.random_seed = derivation.seed_bytes();
+            self.seed_derivation = Some(derivation);
+        }
+        if self.check_determinism {
+            let rerun = Rerun {
+                context: self.context.clone(),
+                faults: self.faults.clone(),
+                hook_counts: self.hooks.counts(),
+                first: None,
+            };
+            self.pending_call.as_mut().expect("The call was just started").rerun = Some(rerun);
+        }
+        Ok(())
+    }
+
+    /// Keeps what the first run of the call left behind, and goes back to the checkpoint the call
+    /// started from, to run it again. `finish_call` then compares the two runs. `error` is the
+    /// reason the first run failed, as for `finish_call`.
+    pub fn restart_call(&mut self, error: Option<String>) -> Result<(), String> {
+        let outcome = self.current_outcome();
+        let error = self.last_error.take().map(|e| format!("{:?}", e)).or(error);
+        let pending = self.pending_call.as_mut().ok_or("No method is being called")?;
+        let rerun = pending.rerun.as_mut().ok_or("The determinism check is off")?;
+        if rerun.first.is_some() {
+            return Err(format!("Method {} already ran twice", pending.method));
+        }
+        rerun.first = Some(RunCapture::new(&outcome, &error, &self.builder.ext, &self.faults.fired, &self.trace));
+        self.builder.ext = pending.ext.clone();
+        self.internal_state = None;
+        self.context = rerun.context.clone();
+        self.faults = rerun.faults.clone();
+        self.hooks.restore_counts(rerun.hook_counts.clone());
+        self.trace.clear();
+        Ok(())
+    }
+
+    /// Finishes the call started by `start_call`. `error` is the reason the contract failed, if
+    /// it failed outside of a host function.
+    ///
+    /// View calls never change the state of the VM. Like on chain, a failed call is rolled back as
+    /// a whole: the storage writes and receipts it made before failing are discarded along with
+    /// its registers, logs and gas, and the attached deposit stays with the predecessor.
+    ///
+    /// If the call ran twice, see `restart_call`, and the runs differ, the call is rolled back too,
+    /// and fails with the `Nondeterminism`, which is kept in `nondeterminism`.
+    pub fn finish_call(&mut self, error: Option<String>) -> Result<CallResult, String> {
+        let pending = self.pending_call.take().ok_or("No method is being called")?;
+        let outcome = self.current_outcome();
+        let error = self.last_error.take().map(|e| format!("{:?}", e)).or(error);
+        let nondeterminism = pending.rerun.as_ref().and_then(|rerun| rerun.first.as_ref()).and_then(|first| {
+            let second = RunCapture::new(&outcome, &error, &self.builder.ext, &self.faults.fired, &self.trace);
+            Nondeterminism::between(&pending.method, first, &second)
+        });
+        if pending.is_view || error.is_some() || nondeterminism.is_some() {
+            self.builder.ext = pending.ext;
+            self.internal_state = pending.internal_state;
+            self.context = pending.context;
+        } else {
+            self.internal_state = None;
+            self.context = pending.context;
+            self.context.storage_usage = outcome.storage_usage;
+            // `VMLogic` adds the attached deposit on top of the account balance.
+            self.context.account_balance = outcome.balance.saturating_sub(self.context.attached_deposit);
+            // Calls that were rolled back don't take a position in the block, so retrying one
+            // gets the same seed.
+            if let Some(derivation) = &self.seed_derivation {
+                self.calls_in_block = (derivation.block_index, derivation.receipt_index + 1);
+            }
+        }
+        let faults = std::mem::replace(&mut self.faults.fired, vec![]);
+        if let Some(nondeterminism) = nondeterminism {
+            let message = nondeterminism.to_string();
+            self.nondeterminism.push(nondeterminism);
+            return Err(message);
+        }
+        Ok(CallResult { outcome, error, faults })
+    }
+
+    /// The calls whose two runs differed, see `set_determinism_check`.
+    pub fn nondeterminism(&self) -> &[Nondeterminism] {
+        &self.nondeterminism
+    }
+
+    pub fn trace(&self) -> &[HostCall] {
+        &self.trace
+    }
+
+    pub fn broken_invariants(&self) -> &[InvariantFailure] {
+        &self.invariants.failures
+    }
+
+    /// The state of the VM's own account, along with the other accounts of the ledger with the
+    /// `interpreter` feature.
+    fn account_views(&mut self) -> BTreeMap<AccountId, AccountView> {
+        #[cfg(feature = "interpreter")]
+        let mut views = self.scheduler.account_views();
+        #[cfg(not(feature = "interpreter"))]
+        let mut views = BTreeMap::new();
+        let outcome = self.current_outcome();
+        let own = AccountView::new(outcome.balance, outcome.storage_usage, &self.builder.ext);
+        views.insert(self.context.current_account_id.clone(), own);
+        views
+    }
+
+    /// The object invariants get after `call`, which returned `outcome`.
+    fn invariant_state(&mut self, call: &str, outcome: &JsValue) -> Result<js_sys::Object, JsValue> {
+        let accounts = self.account_views();
+        let storage = &accounts[&self.context.current_account_id].storage;
+        invariants::state(call, storage, &accounts, outcome)
+    }
+
+    /// Checks the invariants after `call`, which returned `outcome`, and fails with the ones that
+    /// broke, if any.
+    fn check_invariants(&mut self, call: &str, outcome: &JsValue) -> Result<(), JsValue> {
+        if self.invariants.is_empty() {
+            return Ok(());
+        }
+        let state = self.invariant_state(call, outcome)?;
+        match self.invariants.check(&state, call, None, &self.trace) {
+            0 => Ok(()),
+            failed => Err(js_sys::Error::new(&self.invariants.describe_last(failed)).into()),
+        }
+    }
+}
+
+#[cfg(feature = "interpreter")]
+impl VM {
+    /// Runs `method` of the loaded contract with the embedded interpreter, as a receipt with the
+    /// input `args`. Host calls go straight to `VMLogic`, so hooks don't apply but faults do.
+    pub fn execute(&mut self, method: &str, args: Vec<u8>, opts: CallOptions, is_view: bool) -> Result<CallResult, String> {
+        if self.contract.is_none() {
+            return Err("No contract is loaded".to_string());
+        }
+        self.start_call(method.to_string(), args, opts, is_view)?;
+        if self.check_determinism {
+            // Only the second run counts towards the coverage.
+            let error = self.run_interpreter(method, false);
+            self.restart_call(error)?;
+        }
+        let error = self.run_interpreter(method, true);
+        self.finish_call(error)
+    }
+
+    /// Runs `method` of the loaded contract in the interpreter, for the call started by `execute`,
+    /// and returns the error to finish the call with.
+    fn run_interpreter(&mut self, method: &str, with_coverage: bool) -> Option<String> {
+        let coverage = if with_coverage { self.coverage.as_mut() } else { None };
+        let execution = interpreter::run_method(
+            self.contract.as_ref().unwrap(),
+            method,
+            &mut self.builder.ext,
+            self.context.clone(),
+            &self.builder.config,
+            &self.builder.fees_config,
+            &self.builder.promise_results,
+            &mut self.faults,
+            coverage,
+        );
+        self.internal_state = Some(execution.state);
+        self.trace = execution.trace;
+        if let Some(derivation) = &self.seed_derivation {
+            seed::record(&mut self.trace, derivation);
+        }
+        match execution.error {
+            Some(ExecutionError::Host(e)) => {
+                self.last_error = Some(e);
+                None
+            }
+            Some(e) => Some(e.to_string()),
+            None => None,
+        }
+    }
+
+    /// Replaces the loaded contract with one instrumented with block counters, so that `execute`
+    /// collects its coverage. `name` identifies the contract in reports.
+    pub fn instrument_contract(&mut self, name: &str) -> Result<(), String> {
+        let code = self.contract.as_ref().ok_or("No contract is loaded")?;
+        let coverage = Coverage::instrument(name, code)?;
+        self.contract = Some(coverage.binary());
+        self.coverage = Some(coverage);
+        Ok(())
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    pub fn scheduler_mut(&mut self) -> &mut Scheduler {
+        &mut self.scheduler
+    }
+
+    /// Hands the state of the VM's own account over to the scheduler's ledger, and schedules the
+    /// receipts it created.
+    fn sync_to_scheduler(&mut self) -> Result<(), String> {
+        let outcome = self.current_outcome();
+        let account_id = self.context.current_account_id.clone();
+        let account = self.scheduler.account_mut(&account_id);
+        std::mem::swap(&mut account.ext, &mut self.builder.ext);
+        account.balance = outcome.balance;
+        account.storage_usage = outcome.storage_usage;
+        if self.contract.is_some() {
+            account.code = self.contract.clone();
+        }
+        // Calls of the VM itself don't buy their gas from the ledger.
+        if let Err(e) = self.scheduler.collect_receipts(&account_id, &self.context.signer_account_id, false) {
+            // The VM keeps its storage, since no block went by.
+            std::mem::swap(&mut self.scheduler.account_mut(&account_id).ext, &mut self.builder.ext);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Takes the state of the VM's own account back from the ledger, including its code if a
+    /// receipt deployed a new one. The execution in progress, if any, ends, since a block went by.
+    fn sync_from_scheduler(&mut self) {
+        let account = self.scheduler.account_mut(&self.context.current_account_id);
+        std::mem::swap(&mut account.ext, &mut self.builder.ext);
+        if account.code.is_some() && account.code != self.contract {
+            self.contract = account.code.clone();
+            self.coverage = None;
+        }
+        // `VMLogic` adds the attached deposit on top of the account balance.
+        self.context.account_balance = account.balance.saturating_sub(self.context.attached_deposit);
+        self.context.storage_usage = account.storage_usage;
+        self.context.block_index = self.scheduler.block_index;
+        self.context.block_timestamp = self.scheduler.block_timestamp;
+        self.internal_state = None;
+    }
+
+    /// Runs the next block of the scheduler, see `Scheduler::step`, and checks the invariants
+    /// after each receipt. Fails with the invariants that broke, once the block ran.
+    pub fn step_block(&mut self) -> Result<Vec<ExecutedReceipt>, String> {
+        self.sync_to_scheduler()?;
+        let checks = &mut self.invariants;
+        let mut failed = 0;
+        let executed = self.scheduler.step_with(|scheduler, receipt| {
+            if checks.is_empty() {
+                return;
+            }
+            let call = format!("receipt {} on {}", receipt.id, receipt.receiver_id);
+            let accounts = scheduler.account_views();
+            let storage = accounts.get(&receipt.receiver_id).map(|account| &account.storage);
+            let state = serde_wasm_bindgen::to_value(receipt)
+                .map_err(JsValue::from)
+                .and_then(|outcome| invariants::state(&call, &storage, &accounts, &outcome));
+            match state {
+                Ok(state) => failed += checks.check(&state, &call, Some(receipt.id), &receipt.trace),
+                Err(e) => {
+                    checks.failures.push(InvariantFailure {
+                        invariant: "*".to_string(),
+                        call,
+                        receipt_id: Some(receipt.id),
+                        error: format!("Can't build the state of the invariants: {:?}", e),
+                        trace: receipt.trace.clone(),
+                    });
+                    failed += 1;
+                }
+            }
+        });
+        self.sync_from_scheduler();
+        if failed > 0 {
+            return Err(self.invariants.describe_last(failed));
+        }
+        Ok(executed)
+    }
+
+    /// Finds the least prepaid gas `method` succeeds with, by running it from the current state
+    /// in `context` with different amounts of gas. `args` replace the input of the context. The
+    /// state of the VM is restored after each run, and the runs don't count towards the coverage.
+    /// Assumes that a method which succeeds with some gas also succeeds with more.
+    pub fn estimate_method_gas(&mut self, method: &str, args: Vec<u8>, context: VMContext) -> Result<GasEstimate, String> {
+        let coverage = self.coverage.take();
+        let estimate = self.search_prepaid_gas(method, args, context);
+        self.coverage = coverage;
+        estimate
+    }
+
+    fn search_prepaid_gas(&mut self, method: &str, args: Vec<u8>, context: VMContext) -> Result<GasEstimate, String> {
+        let start = self.checkpoint_now();
+        let faults = self.faults.clone();
+        let calls_in_block = self.calls_in_block;
+        let opts = CallOptions {
+            signer: Some(context.signer_account_id.clone()),
+            predecessor: Some(context.predecessor_account_id.clone()),
+            deposit: context.attached_deposit,
+            gas: None,
+        };
+        let mut attempts = 0;
+        let mut attempt = |vm: &mut VM, gas: Gas| -> Result<CallResult, String> {
+            attempts += 1;
+            // The account is the one `context` describes.
+            vm.context = context.clone();
+            vm.internal_state = None;
+            let res = vm.execute(method, args.clone(), CallOptions { gas: Some(gas), ..opts.clone() }, false);
+            vm.builder.ext = start.ext.clone();
+            vm.internal_state = start.internal_state.clone();
+            vm.context = start.context.clone();
+            vm.faults = faults.clone();
+            vm.calls_in_block = calls_in_block;
+            res
+        };
+        // The upper bound runs first: there is nothing to search for if the method fails with it.
+        let max_gas = self.builder.config.max_gas_burnt;
+        let mut best = attempt(self, max_gas)?;
+        if let Some(error) = best.error {
+            return Err(format!("{} fails even with {} gas: {}", method, max_gas, error));
+        }
+        // `lo` fails, `hi` succeeds. Less than the gas the method burnt with the upper bound
+        // can't be enough, so the search starts from there rather than from 0.
+        let (mut lo, mut hi) = (best.outcome.burnt_gas.saturating_sub(1), max_gas);
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            let res = attempt(self, mid)?;
+            if res.error.is_none() {
+                hi = mid;
+                best = res;
+            } else {
+                lo = mid;
+            }
         }
+        let outcome = best.outcome;
+        Ok(GasEstimate {
+            prepaid_gas: hi,
+            burnt_gas: outcome.burnt_gas,
+            used_gas: outcome.used_gas,
+            promise_gas: outcome.used_gas - outcome.burnt_gas,
+            attempts,
+        })
+    }
+
+    /// Schedules a call to `method` of `receiver_id`, as if sent in a transaction, and returns the
+    /// id of its receipt. The signer defaults to the context's, and the predecessor to the signer.
+    /// They pay for it from the ledger, see `Scheduler::schedule_call`. When the VM's own account
+    /// pays, its state is handed over to the ledger first, as for a block.
+    pub fn schedule(&mut self, receiver_id: AccountId, method: &str, args: Vec<u8>, opts: CallOptions) -> Result<u64, String> {
+        let signer = opts.signer.unwrap_or_else(|| self.context.signer_account_id.clone());
+        let predecessor = opts.predecessor.unwrap_or_else(|| signer.clone());
+        for account_id in &[&signer, &predecessor, &receiver_id] {
+            validate_account_id(account_id)?;
+        }
+        let gas = opts.gas.unwrap_or(self.context.prepaid_gas);
+        let own_account_pays = [&signer, &predecessor].contains(&&self.context.current_account_id);
+        if own_account_pays {
+            self.sync_to_scheduler()?;
+        }
+        let id = self.scheduler.schedule_call(signer, predecessor, receiver_id, method, args, opts.deposit, gas);
+        if own_account_pays {
+            self.sync_from_scheduler();
+        }
+        id
+    }
+
+    /// Schedules a call like `schedule`, and runs blocks until it and every receipt it spawned,
+    /// including callbacks and refunds, finished. Fails if that takes more than `max_blocks`.
+    pub fn call_and_wait(&mut self, receiver_id: AccountId, method: &str, args: Vec<u8>, opts: CallOptions, max_blocks: u32) -> Result<FinishedCall, String> {
+        let id = self.schedule(receiver_id, method, args, opts)?;
+        let mut spawned: HashSet<u64> = vec![id].into_iter().collect();
+        let mut receipts = vec![];
+        for _ in 0..max_blocks {
+            if !self.scheduler.pending.iter().any(|r| spawned.contains(&r.id)) {
+                return Ok(FinishedCall::new(id, receipts));
+            }
+            for receipt in self.step_block()? {
+                if spawned.contains(&receipt.id) {
+                    spawned.extend(receipt.created.iter().chain(&receipt.refunds).cloned());
+                    receipts.push(receipt);
+                }
+            }
+        }
+        if self.scheduler.pending.iter().any(|r| spawned.contains(&r.id)) {
+            return Err(format!("{} didn't finish within {} blocks", method, max_blocks));
+        }
+        Ok(FinishedCall::new(id, receipts))
+    }
+
+    /// Runs blocks until no receipts are pending, or until `max_blocks` blocks went by.
+    pub fn run_blocks_until_idle(&mut self, max_blocks: u32) -> Result<Vec<ExecutedReceipt>, String> {
+        self.sync_to_scheduler()?;
+        let mut executed = vec![];
+        for _ in 0..max_blocks {
+            if self.scheduler.is_idle() {
+                break;
+            }
+            executed.extend(self.scheduler.step());
+        }
+        self.sync_from_scheduler();
+        Ok(executed)
+    }
+}
+
+#[cfg(feature = "interpreter")]
+#[wasm_bindgen]
+impl VM {
+    /// Loads the contract that `run_method` executes.
+    pub fn load_contract(&mut self, code: Vec<u8>) {
+        self.contract = Some(code);
+        self.coverage = None;
+    }
+
+    /// Collects the coverage of the loaded contract from now on, see `VM::instrument_contract`.
+    pub fn enable_coverage(&mut self, name: String) -> Result<(), JsValue> {
+        self.instrument_contract(&name).map_err(|e| js_sys::Error::new(&e).into())
+    }
+
+    /// The coverage of the loaded contract in the lcov format, see `Coverage::lcov`.
+    pub fn coverage_lcov(&self, source_suffix: String) -> Option<String> {
+        self.coverage.as_ref().map(|coverage| coverage.to_lcov(&source_suffix))
+    }
+
+    /// Like `call` and `view` in `imports.js`, but the contract runs inside the VM.
+    pub fn run_method(&mut self, method: String, args: String, opts: JsValue, is_view: bool) -> Result<JsValue, JsValue> {
+        let opts = CallOptions::from_js(opts)?;
+        let outcome = self.execute(&method, args.into_bytes(), opts, is_view)
+            .map_err(|e| JsValue::from(js_sys::Error::new(&e)))?
+            .to_js()?;
+        self.check_invariants(&method, &outcome)?;
+        Ok(outcome)
+    }
+
+    /// Deploys `code` to `account_id` in the scheduler's ledger, creating the account if needed.
+    pub fn set_account_code(&mut self, account_id: String, code: Vec<u8>) -> Result<(), JsValue> {
+        validate_account_id(&account_id).map_err(|e| js_sys::Error::new(&e))?;
+        self.scheduler.account_mut(&account_id).code = Some(code);
+        Ok(())
+    }
+
+    /// Sets the balance of `account_id` in the scheduler's ledger, creating the account if needed.
+    pub fn set_ledger_balance(&mut self, account_id: String, balance: JsValue) -> Result<(), JsValue> {
+        validate_account_id(&account_id).map_err(|e| js_sys::Error::new(&e))?;
+        self.scheduler.account_mut(&account_id).balance = u128_from_js(&balance)?;
+        Ok(())
+    }
+
+    /// Estimates the prepaid gas `method` needs, see `VM::estimate_method_gas`. `context` has the
+    /// same format as the one the VM is created with, and defaults to the VM's.
+    pub fn estimate_gas(&mut self, method: String, args: String, context: JsValue) -> Result<JsValue, JsValue> {
+        let context = if context.is_undefined() || context.is_null() {
+            self.context.clone()
+        } else {
+            parse_context(context)?
+        };
+        let estimate = self.estimate_method_gas(&method, args.into_bytes(), context)
+            .map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+        Ok(serde_wasm_bindgen::to_value(&estimate)?)
+    }
+
+    /// Sets the price signers pay for the gas of the calls they schedule, and get refunded at.
+    pub fn set_gas_price(&mut self, price: JsValue) -> Result<(), JsValue> {
+        self.scheduler.gas_price = u128_from_js(&price)?;
+        Ok(())
+    }
+
+    /// Schedules a call to `method` of `receiver_id`, as if sent in a transaction, and returns the
+    /// id of its receipt. It runs in the next block. `opts` are the same as for `call`. Fails if
+    /// the predecessor or the signer can't pay for it.
+    pub fn schedule_call(&mut self, receiver_id: String, method: String, args: String, opts: JsValue) -> Result<f64, JsValue> {
+        let opts = CallOptions::from_js(opts)?;
+        let id = self.schedule(receiver_id, &method, args.into_bytes(), opts)
+            .map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+        Ok(id as f64)
+    }
+
+    /// Schedules a call like `schedule_call`, and returns a `Promise` of its result once it and
+    /// every receipt it spawned finished, see `VM::call_and_wait`. The blocks run before the
+    /// method returns, and the promise is already settled: to interleave other calls with the
+    /// flow, step through it with `nextBlock` instead.
+    #[wasm_bindgen(js_name = callAsync)]
+    pub fn call_async(&mut self, receiver_id: String, method: String, args: String, opts: JsValue, max_blocks: Option<u32>) -> js_sys::Promise {
+        let res = CallOptions::from_js(opts).and_then(|opts| {
+            let finished = self.call_and_wait(receiver_id, &method, args.into_bytes(), opts, max_blocks.unwrap_or(100))
+                .map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+            Ok(serde_wasm_bindgen::to_value(&finished)?)
+        });
+        future_to_promise(async move { res })
+    }
+
+    /// Same as `next_block`, returning a `Promise`.
+    #[wasm_bindgen(js_name = nextBlock)]
+    pub fn next_block_async(&mut self) -> js_sys::Promise {
+        let res = self.next_block();
+        future_to_promise(async move { res })
+    }
+
+    /// Same as `run_until_idle`, returning a `Promise`.
+    #[wasm_bindgen(js_name = runUntilIdle)]
+    pub fn run_until_idle_async(&mut self, max_blocks: Option<u32>) -> js_sys::Promise {
+        let res = self.run_until_idle(max_blocks);
+        future_to_promise(async move { res })
+    }
+
+    /// Runs the next block and returns the receipts that ran in it. Receipts created by the VM's
+    /// own account are scheduled first.
+    pub fn next_block(&mut self) -> Result<JsValue, JsValue> {
+        let executed = self.step_block().map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+        Ok(serde_wasm_bindgen::to_value(&executed)?)
+    }
+
+    /// Runs blocks until no receipts are pending, 100 blocks at most by default, and returns the
+    /// receipts that ran.
+    pub fn run_until_idle(&mut self, max_blocks: Option<u32>) -> Result<JsValue, JsValue> {
+        let executed = self.run_blocks_until_idle(max_blocks.unwrap_or(100))
+            .map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+        Ok(serde_wasm_bindgen::to_value(&executed)?)
+    }
+
+    /// Runs the receipts that are ready in a block in a random order, replayable from
+    /// `ordering.seed`, or in the order they were created when `ordering` is `null`. See
+    /// `Ordering` for the options.
+    pub fn set_receipt_ordering(&mut self, ordering: JsValue) -> Result<(), JsValue> {
+        let ordering = if ordering.is_undefined() || ordering.is_null() {
+            None
+        } else {
+            Some(serde_wasm_bindgen::from_value(ordering)?)
+        };
+        self.scheduler.set_ordering(ordering);
+        Ok(())
+    }
+
+    /// The ids of the receipts that ran since the ordering was set, in order.
+    pub fn receipt_order(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.scheduler.history)?)
+    }
+
+    /// Runs the pending receipts in `runs` random orderings, with the seeds following
+    /// `ordering.seed`, and calls `invariant` after every block with the `block_index`, the
+    /// receipts `executed` in the block and the state of the `accounts`. Returns the first
+    /// ordering for which the invariant throws or returns `false`, or `null`. The state of the VM
+    /// doesn't change.
+    pub fn explore_orderings(&mut self, runs: u32, ordering: JsValue, invariant: js_sys::Function) -> Result<JsValue, JsValue> {
+        let ordering: Ordering = if ordering.is_undefined() || ordering.is_null() {
+            Ordering::default()
+        } else {
+            serde_wasm_bindgen::from_value(ordering)?
+        };
+        let end = ordering.seed.checked_add(u64::from(runs))
+            .ok_or_else(|| js_sys::Error::new(&format!("{} runs from the seed {} overflow the seeds", runs, ordering.seed)))?;
+        self.sync_to_scheduler().map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+        let seeds = ordering.seed..end;
+        let counterexample = self.scheduler.explore(seeds, &ordering, 100, |scheduler, executed| {
+            let check = js_sys::Object::new();
+            let set = |key: &str, value: Result<JsValue, serde_wasm_bindgen::Error>| -> Result<(), String> {
+                let value = value.map_err(|e| e.to_string())?;
+                js_sys::Reflect::set(&check, &key.into(), &value).map_err(|_| "Can't build the invariant check".to_string())?;
+                Ok(())
+            };
+            set("block_index", Ok(JsValue::from_f64(scheduler.block_index as f64)))?;
+            set("executed", serde_wasm_bindgen::to_value(executed))?;
+            set("accounts", serde_wasm_bindgen::to_value(&scheduler.account_views()))?;
+            invariants::run_check(&invariant, &check)
+        });
+        self.sync_from_scheduler();
+        match counterexample {
+            Some(counterexample) => Ok(serde_wasm_bindgen::to_value(&counterexample)?),
+            None => Ok(JsValue::NULL),
+        }
+    }
+
+    /// Lists the receipts waiting to run, with the dependencies they are waiting for, the
+    /// `shard_id` of the receiver and the block they reach it in, `delivered_at`.
+    pub fn pending_receipts(&self) -> Result<JsValue, JsValue> {
+        let pending: Vec<PendingReceiptView> = self.scheduler.pending.iter()
+            .map(|receipt| PendingReceiptView::new(receipt, &self.scheduler.sharding))
+            .collect();
+        Ok(serde_wasm_bindgen::to_value(&pending)?)
+    }
+
+    /// Splits the accounts of the ledger into shards, with `accounts` mapping account ids to
+    /// shards and `boundary_accounts` for the others, and sets the `cross_shard_delay` in blocks
+    /// and the `block_gas_limit` of each shard. See `Sharding` for the details. `null` puts every
+    /// account back on a single shard. Receipts that were already scheduled keep the block they
+    /// were due in. Fails if `block_gas_limit` is 0.
+    pub fn set_sharding(&mut self, layout: JsValue) -> Result<(), JsValue> {
+        let sharding = if layout.is_undefined() || layout.is_null() {
+            Sharding::default()
+        } else {
+            serde_wasm_bindgen::from_value(layout)?
+        };
+        self.scheduler.set_sharding(sharding).map_err(|e| js_sys::Error::new(&e).into())
+    }
+
+    pub fn shard_of(&self, account_id: String) -> u64 {
+        self.scheduler.sharding.shard_of(&account_id)
+    }
+
+    /// The ids of the receipts put off to the next block because their shard reached its gas
+    /// limit, in the order they will run.
+    pub fn delayed_receipts(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.scheduler.delayed)?)
+    }
+}
+
+#[wasm_bindgen]
+impl VM {
+    #[wasm_bindgen(constructor)]
+    pub fn new(context: JsValue) -> Self {
+        set_panic_hook();
+        let c: VMContext = parse_context(context).unwrap();
+        Self::from_context(c)
     }
     
     fn run_vm<T, F: FnOnce(&mut VMLogic) -> VMResult<T>>(&mut self, f: F) -> VMResult<T> {
@@ -112,19 +960,282 @@ impl VM {
         }
         res
     }
-    
+
+    /// Runs the host function `name` with the registered hooks, and surfaces host errors as JS
+    /// exceptions so that they unwind the contract instead of aborting the whole module.
+    fn run<T: HookValue, F: FnOnce(&mut VMLogic) -> VMResult<T>>(&mut self, name: &str, args: &[u64], f: F) -> Result<T, JsValue> {
+        let seed = if name == "random_seed" { self.seed_derivation.clone() } else { None };
+        self.trace.push(HostCall { name: name.to_string(), args: args.to_vec(), seed });
+        let count = self.hooks.record(name);
+        let res = match self.hooks.before(name, args, count)? {
+            HookAction::Continue => match self.faults.check(name, args)
+                .or_else(|| account_id::check_host_call(name, args, &self.builder.memory))
+                .or_else(|| public_key::check_host_call(name, args, &self.builder.memory))
+            {
+                Some(e) => Err(e),
+                None => self.run_vm(f),
+            },
+            HookAction::Return(value) => Ok(T::from_js(&value)?),
+            HookAction::Fail(e) => Err(e),
+        };
+        let res = match self.hooks.after(name, args, count, &res)? {
+            HookAction::Continue => res,
+            HookAction::Return(value) => Ok(T::from_js(&value)?),
+            HookAction::Fail(e) => Err(e),
+        };
+        res.map_err(|e| {
+            let err = host_error(&e);
+            self.last_error = Some(e);
+            err
+        })
+    }
+
+    fn current_outcome(&mut self) -> VMOutcome {
+        let mut vm = self.builder.build(self.context.clone());
+        if self.internal_state.is_some() {
+            vm.restore_state(self.internal_state.as_ref().unwrap());
+        }
+        vm.outcome()
+    }
+
+    /// Prepares the VM to execute `method` as a new receipt. The JS side is expected to invoke
+    /// the contract's export and then call `end_call`, see `call` and `view` in `imports.js`.
+    pub fn begin_call(&mut self, method: String, args: String, opts: JsValue, is_view: bool) -> Result<(), JsValue> {
+        let opts = CallOptions::from_js(opts)?;
+        self.start_call(method, args.into_bytes(), opts, is_view)
+            .map_err(|e| js_sys::Error::new(&e).into())
+    }
+
+    /// Finishes the call started by `begin_call` and returns its decoded outcome. `error` is the
+    /// message of the exception thrown by the contract, if any.
+    pub fn end_call(&mut self, error: Option<String>) -> Result<JsValue, JsValue> {
+        let method = self.pending_call.as_ref().map(|pending| pending.method.clone()).unwrap_or_default();
+        let outcome = self.finish_call(error)
+            .map_err(|e| JsValue::from(js_sys::Error::new(&e)))?
+            .to_js()?;
+        self.check_invariants(&method, &outcome)?;
+        Ok(outcome)
+    }
+
+    /// Runs every call twice from the same state, and fails the calls whose two runs differ in
+    /// outcome, gas, storage, receipts or error. `runMethod` in `imports.js` invokes the export
+    /// again after `rerun_call`, and `execute` runs the interpreter twice.
+    pub fn set_determinism_check(&mut self, enabled: bool) {
+        self.check_determinism = enabled;
+    }
+
+    pub fn checks_determinism(&self) -> bool {
+        self.check_determinism
+    }
+
+    /// Goes back to the state the call started by `begin_call` started from, for the export to be
+    /// invoked again. `error` is the message of the exception thrown by the first run, if any.
+    pub fn rerun_call(&mut self, error: Option<String>) -> Result<(), JsValue> {
+        self.restart_call(error).map_err(|e| js_sys::Error::new(&e).into())
+    }
+
+    /// Lists the calls whose two runs differed, with the `differences` and the first host call
+    /// they made differently, as `diverging_call`.
+    pub fn nondeterminism_reports(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.nondeterminism)?)
+    }
+
+    /// Gives every call its own seed, derived from the one set by `set_random_seed`, the block
+    /// index and the position of the call in the block, see `seed::SCHEME`. Calls that are rolled
+    /// back, like views and failed calls, don't take a position. With the `interpreter`
+    /// feature, the receipts of the scheduler get one too, from their position in their block.
+    /// The calls to `random_seed` in traces record how the seed was derived.
+    pub fn set_seed_derivation(&mut self, enabled: bool) {
+        self.derive_seeds = enabled;
+        #[cfg(feature = "interpreter")]
+        {
+            self.scheduler.derive_seeds = enabled;
+        }
+    }
+
+    /// How the seed of the current or last call was derived, if it was.
+    pub fn seed_derivation(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.seed_derivation)?)
+    }
+
+    /// The host functions the current or last call made, in order, with their arguments.
+    pub fn host_calls(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.trace)?)
+    }
+
+    /// Adds an invariant, checked after every call and, with the `interpreter` feature, after
+    /// every receipt. `check` gets the `call`, the `storage` of the account, the `accounts` and
+    /// the `outcome`, and breaks the invariant by throwing or returning `false`. Set `via_host`
+    /// for invariants that read the state through the host functions instead, like those written
+    /// in AssemblyScript: they only run after the calls made by `call` and `view`.
+    pub fn add_invariant(&mut self, name: String, check: js_sys::Function, via_host: Option<bool>) {
+        self.invariants.add(name, check, via_host.unwrap_or(false));
+    }
+
+    pub fn remove_invariant(&mut self, name: String) {
+        self.invariants.remove(&name);
+    }
+
+    /// Removes the invariants along with their failures.
+    pub fn clear_invariants(&mut self) {
+        self.invariants.clear();
+    }
+
+    /// Lists the invariants that broke, with the `call` or `receipt_id` that broke them, the
+    /// `error` and the `trace` of the host functions the call made.
+    pub fn invariant_failures(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.invariants.failures)?)
+    }
+
+    pub fn has_host_invariants(&self) -> bool {
+        self.invariants.has_via_host()
+    }
+
+    /// Starts running the invariants that read the state through the host functions, after the
+    /// call to `method` from JS. Returns the `state` to pass them and the `invariants` as
+    /// `{ name, check }`. Whatever they do to the VM is undone by
+    /// `end_invariants`.
+    pub fn begin_invariants(&mut self, method: String, outcome: JsValue) -> Result<JsValue, JsValue> {
+        let state = self.invariant_state(&method, &outcome)?;
+        self.pending_invariants = Some(PendingInvariants {
+            call: method,
+            ext: self.builder.ext.clone(),
+            internal_state: self.internal_state.clone(),
+            trace: std::mem::replace(&mut self.trace, vec![]),
+        });
+        let res = js_sys::Object::new();
+        js_sys::Reflect::set(&res, &"state".into(), &state)?;
+        js_sys::Reflect::set(&res, &"invariants".into(), &self.invariants.via_host_to_js()?)?;
+        Ok(res.into())
+    }
+
+    /// Restores the state from before the invariants ran, and records the ones that broke, given
+    /// as `{ name, error }`. Throws if any did.
+    pub fn end_invariants(&mut self, failures: JsValue) -> Result<(), JsValue> {
+        #[derive(Deserialize)]
+        struct Failure {
+            name: String,
+            error: String,
+        }
+        let pending = self.pending_invariants.take().ok_or("No invariants are running")?;
+        self.builder.ext = pending.ext;
+        self.internal_state = pending.internal_state;
+        self.trace = pending.trace;
+        let failures: Vec<Failure> = serde_wasm_bindgen::from_value(failures)?;
+        for failure in &failures {
+            self.invariants.failures.push(InvariantFailure {
+                invariant: failure.name.clone(),
+                call: pending.call.clone(),
+                receipt_id: None,
+                error: failure.error.clone(),
+                trace: self.trace.clone(),
+            });
+        }
+        match failures.len() {
+            0 => Ok(()),
+            failed => Err(js_sys::Error::new(&self.invariants.describe_last(failed)).into()),
+        }
+    }
+
+    fn checkpoint_now(&mut self) -> Checkpoint {
+        let outcome = self.current_outcome();
+        Checkpoint {
+            ext: self.builder.ext.clone(),
+            internal_state: self.internal_state.clone(),
+            context: self.context.clone(),
+            storage_usage: outcome.storage_usage,
+            balance: outcome.balance,
+        }
+    }
+
+    fn named_checkpoint(&self, name: &str) -> Result<&Checkpoint, JsValue> {
+        self.checkpoints.get(name)
+            .ok_or_else(|| js_sys::Error::new(&format!("No checkpoint named {}", name)).into())
+    }
+
     pub fn save_state(&mut self) {
-        self.saved_state = self.internal_state.clone();
-        self.saved_ext = Some(self.builder.ext.clone());
+        self.saved_state = Some(self.checkpoint_now());
     }
 
     pub fn restore_state(&mut self) {
-        self.internal_state = self.saved_state.clone();
-        if self.saved_ext.is_some() {
-            self.builder.ext = self.saved_ext.as_ref().unwrap().clone()
+        match &self.saved_state {
+            Some(saved) => {
+                self.internal_state = saved.internal_state.clone();
+                self.builder.ext = saved.ext.clone();
+            }
+            None => self.internal_state = None,
         }
     }
 
+    /// Saves the state and context of the VM under `name`, to diff against or restore later.
+    pub fn checkpoint(&mut self, name: String) {
+        let checkpoint = self.checkpoint_now();
+        self.checkpoints.insert(name, checkpoint);
+    }
+
+    /// Restores the state and context saved by `checkpoint`.
+    pub fn restore_checkpoint(&mut self, name: String) -> Result<(), JsValue> {
+        let checkpoint = self.named_checkpoint(&name)?.clone();
+        self.builder.ext = checkpoint.ext;
+        self.internal_state = checkpoint.internal_state;
+        self.context = checkpoint.context;
+        Ok(())
+    }
+
+    /// Lists the keys added, removed and modified between the checkpoints `from` and `to`, along
+    /// with the changes in storage usage and balance. Without `to`, compares with the current state.
+    pub fn storage_diff(&mut self, from: String, to: Option<String>) -> Result<JsValue, JsValue> {
+        let current;
+        let to = match to {
+            Some(name) => self.named_checkpoint(&name)?,
+            None => {
+                current = self.checkpoint_now();
+                &current
+            }
+        };
+        let diff = StorageDiff::between(self.named_checkpoint(&from)?, to);
+        Ok(serde_wasm_bindgen::to_value(&diff)?)
+    }
+
+    /// Renders a snapshot of a call as canonical JSON, see `snapshot_value`. `outcome` is the JSON
+    /// of the outcome returned by `call`.
+    pub fn snapshot(&mut self, outcome: String, since: String) -> Result<String, JsValue> {
+        let outcome = serde_json::from_str(&outcome).map_err(|e| js_sys::Error::new(&format!("Invalid outcome: {}", e)))?;
+        let snapshot = self.snapshot_value(outcome, &since).map_err(|e| js_sys::Error::new(&e))?;
+        Ok(to_canonical_string(&snapshot))
+    }
+
+    /// Same as `storage_diff`, comparing the state saved by the last `save_state` with the
+    /// current one.
+    pub fn storage_diff_since_save(&mut self) -> Result<JsValue, JsValue> {
+        let current = self.checkpoint_now();
+        let saved = self.saved_state.as_ref().ok_or("The state hasn't been saved")?;
+        Ok(serde_wasm_bindgen::to_value(&StorageDiff::between(saved, &current))?)
+    }
+
+    /// Lists the contract's storage as `[key, value]` pairs of `Uint8Array`s, sorted by key.
+    pub fn storage_entries(&self) -> js_sys::Array {
+        let mut entries: Vec<_> = self.builder.ext.fake_trie.iter().collect();
+        entries.sort();
+        entries.into_iter()
+            .map(|(key, value)| {
+                let entry: js_sys::Array = [js_sys::Uint8Array::from(&key[..]), js_sys::Uint8Array::from(&value[..])]
+                    .iter()
+                    .collect();
+                JsValue::from(entry)
+            })
+            .collect()
+    }
+
+    /// The contract's storage grouped into the near-runtime-ts collections it holds, see
+    /// `StorageView::of`. Values are rendered as `"utf8"`, the default, `"json"` or `"hex"`.
+    pub fn storage_view(&self, mode: Option<String>) -> Result<JsValue, JsValue> {
+        let mode = RenderMode::parse(mode.as_deref().unwrap_or("utf8")).map_err(|e| js_sys::Error::new(&e))?;
+        let view = StorageView::of(&self.builder.ext.fake_trie, mode);
+        let json = serde_json::to_string(&view).map_err(|e| js_sys::Error::new(&e.to_string()))?;
+        js_sys::JSON::parse(&json)
+    }
+
     pub fn save_context(&mut self) {
         self.saved_context = Some(self.context.clone())
     }
@@ -135,30 +1246,80 @@ impl VM {
         }
     }
 
-    pub fn set_context(&mut self, context: JsValue) {
-        self.context = serde_wasm_bindgen::from_value(context).unwrap()
+    /// Registers a callback that runs before every call to the host function `name`, see
+    /// `HostHooks` for what it receives and can return. Callbacks must not call the VM.
+    pub fn before_host_call(&mut self, name: String, callback: js_sys::Function) {
+        self.hooks.set_before(name, callback)
+    }
+
+    /// Registers a callback that runs after every call to the host function `name`, see
+    /// `HostHooks` for what it receives and can return. Callbacks must not call the VM.
+    pub fn after_host_call(&mut self, name: String, callback: js_sys::Function) {
+        self.hooks.set_after(name, callback)
+    }
+
+    pub fn clear_host_hooks(&mut self) {
+        self.hooks.clear()
+    }
+
+    /// Sets the faults to inject from now on, see `FaultPlan` for the options. Faults that fire
+    /// are reported in the `faults` of the outcome.
+    pub fn set_fault_plan(&mut self, plan: JsValue) -> Result<(), JsValue> {
+        let plan: FaultPlan = serde_wasm_bindgen::from_value(plan)?;
+        self.faults.set_plan(plan).map_err(|e| js_sys::Error::new(&e).into())
+    }
+
+    pub fn clear_fault_plan(&mut self) {
+        self.faults.clear()
+    }
+
+    /// Sets the results of the promises the current call is a callback of, see
+    /// `parse_promise_results` for the format. The fault plan can make some of them fail.
+    pub fn set_promise_results(&mut self, results: JsValue) -> Result<(), JsValue> {
+        let mut results = parse_promise_results(results)?;
+        self.faults.apply_to_promise_results(&mut results);
+        self.builder.promise_results = results;
+        Ok(())
     }
 
-    pub fn set_current_account_id(&mut self, s: JsValue) {
-      self.context.current_account_id = serde_wasm_bindgen::from_value(s).unwrap()
+    pub fn set_context(&mut self, context: JsValue) -> Result<(), JsValue> {
+        self.context = parse_context(context)?;
+        Ok(())
+    }
+
+    pub fn set_current_account_id(&mut self, s: JsValue) -> Result<(), JsValue> {
+      self.context.current_account_id = parse_account_id(s)?;
+      Ok(())
     }
 
     pub fn set_input(&mut self, s: JsValue) {
       self.context.input = serde_wasm_bindgen::from_value(s).unwrap()
     }
 
-    pub fn set_signer_account_id(&mut self, s: JsValue) {
-      self.context.signer_account_id = serde_wasm_bindgen::from_value(s).unwrap()
+    pub fn set_signer_account_id(&mut self, s: JsValue) -> Result<(), JsValue> {
+      self.context.signer_account_id = parse_account_id(s)?;
+      Ok(())
     }
  // string
       /// The public key that was used to sign the original transaction that led to
       /// this execution.
-    pub fn set_signer_account_pk(&mut self, s: JsValue) {
-      self.context.signer_account_pk = serde_wasm_bindgen::from_value(s).unwrap()
+    pub fn set_signer_account_pk(&mut self, s: JsValue) -> Result<(), JsValue> {
+      let key = s.as_string().ok_or("Public keys must be strings")?;
+      let key = ParsedPublicKey::parse(&key).map_err(|e| js_sys::Error::new(&e))?;
+      self.context.signer_account_pk = key.to_bytes();
+      Ok(())
+    }
+
+    /// The signer's public key as `<curve>:<base58>`.
+    pub fn signer_public_key(&self) -> Result<String, JsValue> {
+      ParsedPublicKey::from_bytes(&self.context.signer_account_pk)
+        .map(|key| key.to_string())
+        .map_err(|e| js_sys::Error::new(&e).into())
     }
  // string base58
-    pub fn set_predecessor_account_id(&mut self, s: JsValue) {
-      self.context.predecessor_account_id = serde_wasm_bindgen::from_value(s).unwrap()
+    pub fn set_predecessor_account_id(&mut self, s: JsValue) -> Result<(), JsValue> {
+      self.context.predecessor_account_id = parse_account_id(s)?;
+      Ok(())
     }
  // string
     pub fn set_block_index(&mut self, block_height: u64) {
@@ -169,21 +1330,48 @@ impl VM {
       self.context.block_timestamp = stmp
     }
 
-    pub fn set_account_balance(&mut self, lo: u64, hi: u64) {
-        self.context.account_balance = u128_from_u64s(lo, hi) + self.context.attached_deposit // TODO: serde_wasm_bindgen::from_value(_u128).unwrap()
-        
+    /// Whether balances passed to `set_account_balance` already include the attached deposit.
+    ///
+    /// Like on chain, the attached deposit is added to the account balance before the contract
+    /// runs. By default the balance given is the one before that happens, so the contract sees
+    /// `balance + attached_deposit`. When this is set, the contract sees exactly the balance given.
+    pub fn set_balance_includes_deposit(&mut self, b: bool) {
+        self.balance_includes_deposit = b
+    }
+
+    /// Accepts a decimal string, a number or a `BigInt`.
+    pub fn set_account_balance(&mut self, balance: JsValue) -> Result<(), JsValue> {
+        let balance = u128_from_js(&balance)?;
+        self.context.account_balance = if self.balance_includes_deposit {
+            balance.checked_sub(self.context.attached_deposit)
+                .ok_or("Account balance must include the attached deposit")?
+        } else {
+            balance
+        };
+        Ok(())
     }
 
-    pub fn set_account_locked_balance(&mut self, lo: u64, hi: u64) {
-        self.context.account_locked_balance = u128_from_u64s(lo, hi) // TODO: serde_wasm_bindgen::from_value(_u128).unwrap()
+    /// Accepts a decimal string, a number or a `BigInt`.
+    pub fn set_account_locked_balance(&mut self, balance: JsValue) -> Result<(), JsValue> {
+        self.context.account_locked_balance = u128_from_js(&balance)?;
+        Ok(())
     }
 
     pub fn set_storage_usage(&mut self, amt: JsValue) {
       self.context.storage_usage = serde_wasm_bindgen::from_value(amt).unwrap()
     }
 
-    pub fn set_attached_deposit(&mut self, lo: u64, hi: u64) {
-        self.context.attached_deposit = u128_from_u64s(lo, hi) // TODO: serde_wasm_bindgen::from_value(_u128).unwrap()
+    /// Accepts a decimal string, a number or a `BigInt`. If the account balance includes the
+    /// deposit, it is adjusted so that the total stays the same.
+    pub fn set_attached_deposit(&mut self, deposit: JsValue) -> Result<(), JsValue> {
+        let deposit = u128_from_js(&deposit)?;
+        if self.balance_includes_deposit {
+            self.context.account_balance = (self.context.account_balance + self.context.attached_deposit)
+                .checked_sub(deposit)
+                .ok_or("Account balance must include the attached deposit")?;
+        }
+        self.context.attached_deposit = deposit;
+        Ok(())
     }
 
     pub fn set_prepaid_gas(&mut self, _u64: u64) {
@@ -198,8 +1386,13 @@ impl VM {
       self.context.is_view = b
     }
 
-    pub fn set_output_data_receivers(&mut self, arr: JsValue) {
-      self.context.output_data_receivers = serde_wasm_bindgen::from_value(arr).unwrap()
+    pub fn set_output_data_receivers(&mut self, arr: JsValue) -> Result<(), JsValue> {
+      let receivers: Vec<AccountId> = serde_wasm_bindgen::from_value(arr)?;
+      for receiver in &receivers {
+          validate_account_id(receiver).map_err(|e| js_sys::Error::new(&e))?;
+      }
+      self.context.output_data_receivers = receivers;
+      Ok(())
     }
 
 
@@ -228,14 +1421,21 @@ impl VM {
    /// # Cost
    ///
    /// `base + read_register_base + read_register_byte * num_bytes + write_memory_base + write_memory_byte * num_bytes`
-    pub fn read_register(&mut self, register_id: u64, ptr: u64) {
+    pub fn read_register(&mut self, register_id: u64, ptr: u64) -> Result<(), JsValue> {
         // let data = &vec![42];
         // vm.wrapped_internal_write_register(register_id, &data);
-        let res = self.run_vm(|vm| vm.read_register(register_id, ptr));
+        let res = self.run("read_register", &[register_id, ptr], |vm| vm.read_register(register_id, ptr));
         match res {
-            Ok(()) => (),
-            Err(VMLogicError::HostError(_e)) => console_log!("Host Error"),
-            Err(e) => panic!(e)
+            Ok(()) => Ok(()),
+            Err(e) => match self.last_error.take() {
+                // Reading an unused register is ignored, as it always was. Hooks and faults can't
+                // inject this error, so the ones they inject still fail the call.
+                Some(VMLogicError::HostError(HostError::InvalidRegisterId { .. })) => Ok(()),
+                error => {
+                    self.last_error = error;
+                    Err(e)
+                }
+            }
         }
     }
 
@@ -250,8 +1450,19 @@ impl VM {
     // # Cost
     //
     // `base`
-    pub fn register_len(&mut self, register_id: u64) -> u64 {
-        self.run_vm(|vm| vm.register_len(register_id)).unwrap_or(std::u64::MAX)
+    pub fn register_len(&mut self, register_id: u64) -> Result<u64, JsValue> {
+        let res = self.run("register_len", &[register_id], |vm| vm.register_len(register_id));
+        match res {
+            Ok(len) => Ok(len),
+            Err(e) => match self.last_error.take() {
+                // Like for `read_register`, only the error of an unused register is ignored.
+                Some(VMLogicError::HostError(HostError::InvalidRegisterId { .. })) => Ok(std::u64::MAX),
+                error => {
+                    self.last_error = error;
+                    Err(e)
+                }
+            }
+        }
     }
 
     // Copies `data` from the guest memory into the register. If register is unused will initialize
@@ -267,8 +1478,8 @@ impl VM {
     // # Cost
     //
     // `base + read_memory_base + read_memory_bytes * num_bytes + write_register_base + write_register_bytes * num_bytes`
-    pub fn write_register(&mut self, register_id: u64, data_len: u64, data_ptr: u64) -> () {
-        self.run_vm(|vm| vm.write_register(register_id, data_len, data_ptr)).unwrap()
+    pub fn write_register(&mut self, register_id: u64, data_len: u64, data_ptr: u64) -> Result<(), JsValue> {
+        self.run("write_register", &[register_id, data_len, data_ptr], |vm| vm.write_register(register_id, data_len, data_ptr))
     }
    /// ###################################
    /// # String reading helper functions #
@@ -318,8 +1529,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + write_register_base + write_register_byte * num_bytes`
-    pub fn current_account_id(&mut self, register_id: u64) -> () {
-        self.run_vm(|vm| vm.current_account_id(register_id)).unwrap()
+    pub fn current_account_id(&mut self, register_id: u64) -> Result<(), JsValue> {
+        self.run("current_account_id", &[register_id], |vm| vm.current_account_id(register_id))
     }
    /// All contract calls are a result of some transaction that was signed by some account using
    /// some access key and submitted into a memory pool (either through the wallet using RPC or by
@@ -334,8 +1545,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + write_register_base + write_register_byte * num_bytes`
-    pub fn signer_account_id(&mut self, register_id: u64) -> () {
-        self.run_vm(|vm| vm.signer_account_id(register_id)).unwrap()
+    pub fn signer_account_id(&mut self, register_id: u64) -> Result<(), JsValue> {
+        self.run("signer_account_id", &[register_id], |vm| vm.signer_account_id(register_id))
     }
    /// Saves the public key fo the access key that was used by the signer into the register. In
    /// rare situations smart contract might want to know the exact access key that was used to send
@@ -349,8 +1560,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + write_register_base + write_register_byte * num_bytes`
-    pub fn signer_account_pk(&mut self, register_id: u64) -> () {
-        self.run_vm(|vm| vm.signer_account_pk(register_id)).unwrap()
+    pub fn signer_account_pk(&mut self, register_id: u64) -> Result<(), JsValue> {
+        self.run("signer_account_pk", &[register_id], |vm| vm.signer_account_pk(register_id))
     }
    /// All contract calls are a result of a receipt, this receipt might be created by a transaction
    /// that does function invocation on the contract or another contract as a result of
@@ -364,8 +1575,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + write_register_base + write_register_byte * num_bytes`
-    pub fn predecessor_account_id(&mut self, register_id: u64) -> () {
-        self.run_vm(|vm| vm.predecessor_account_id(register_id)).unwrap()
+    pub fn predecessor_account_id(&mut self, register_id: u64) -> Result<(), JsValue> {
+        self.run("predecessor_account_id", &[register_id], |vm| vm.predecessor_account_id(register_id))
     }
    /// Reads input to the contract call into the register. Input is expected to be in JSON-format.
    /// If input is provided saves the bytes (potentially zero) of input into register. If input is
@@ -374,8 +1585,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + write_register_base + write_register_byte * num_bytes`
-    pub fn input(&mut self, register_id: u64) -> () {
-        self.run_vm(|vm| vm.input(register_id)).unwrap()
+    pub fn input(&mut self, register_id: u64) -> Result<(), JsValue> {
+        self.run("input", &[register_id], |vm| vm.input(register_id))
     }
    /// Returns the current block height.
    ///
@@ -383,16 +1594,16 @@ impl VM {
    ///
    /// `base`
    /// TODO #1903 rename to `block_height`
-    pub fn block_index(&mut self) -> u64 {
-        self.run_vm(|vm| vm.block_index()).unwrap()
+    pub fn block_index(&mut self) -> Result<u64, JsValue> {
+        self.run("block_index", &[], |vm| vm.block_index())
     }
    /// Returns the current block timestamp.
    ///
    /// # Cost
    ///
    /// `base`
-    pub fn block_timestamp(&mut self) -> u64 {
-        self.run_vm(|vm| vm.block_timestamp()).unwrap()
+    pub fn block_timestamp(&mut self) -> Result<u64, JsValue> {
+        self.run("block_timestamp", &[], |vm| vm.block_timestamp())
     }
    /// Returns the number of bytes used by the contract if it was saved to the trie as of the
    /// invocation. This includes:
@@ -404,8 +1615,8 @@ impl VM {
    /// # Cost
    ///
    /// `base`
-    pub fn storage_usage(&mut self) -> StorageUsage {
-        self.run_vm(|vm| vm.storage_usage()).unwrap()
+    pub fn storage_usage(&mut self) -> Result<StorageUsage, JsValue> {
+        self.run("storage_usage", &[], |vm| vm.storage_usage())
     }
    /// #################
    /// # Economics API #
@@ -417,17 +1628,17 @@ impl VM {
    /// # Cost
    ///
    /// `base + memory_write_base + memory_write_size * 16`
-    pub fn account_balance(&mut self, balance_ptr: u64) -> () {
+    pub fn account_balance(&mut self, balance_ptr: u64) -> Result<(), JsValue> {
         // self.builder.memory.write_memory(balance_ptr, &self.context.account_balance.to_le_bytes())
-        self.run_vm(|vm| vm.account_balance(balance_ptr)).unwrap()
+        self.run("account_balance", &[balance_ptr], |vm| vm.account_balance(balance_ptr))
     }
    /// The current amount of tokens locked due to staking.
    ///
    /// # Cost
    ///
    /// `base + memory_write_base + memory_write_size * 16`
-    pub fn account_locked_balance(&mut self, balance_ptr: u64) -> () {
-        self.run_vm(|vm| vm.account_locked_balance(balance_ptr)).unwrap()
+    pub fn account_locked_balance(&mut self, balance_ptr: u64) -> Result<(), JsValue> {
+        self.run("account_locked_balance", &[balance_ptr], |vm| vm.account_locked_balance(balance_ptr))
     }
    /// The balance that was attached to the call that will be immediately deposited before the
    /// contract execution starts.
@@ -439,8 +1650,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + memory_write_base + memory_write_size * 16`
-    pub fn attached_deposit(&mut self, balance_ptr: u64) -> () {
-        self.run_vm(|vm| vm.attached_deposit(balance_ptr)).unwrap()
+    pub fn attached_deposit(&mut self, balance_ptr: u64) -> Result<(), JsValue> {
+        self.run("attached_deposit", &[balance_ptr], |vm| vm.attached_deposit(balance_ptr))
     }
    /// The amount of gas attached to the call that can be used to pay for the gas fees.
    ///
@@ -451,8 +1662,8 @@ impl VM {
    /// # Cost
    ///
    /// `base`
-    pub fn prepaid_gas(&mut self) -> Gas {
-        self.run_vm(|vm| vm.prepaid_gas()).unwrap()
+    pub fn prepaid_gas(&mut self) -> Result<Gas, JsValue> {
+        self.run("prepaid_gas", &[], |vm| vm.prepaid_gas())
     }
    /// The gas that was already burnt during the contract execution (cannot exceed `prepaid_gas`)
    ///
@@ -463,8 +1674,8 @@ impl VM {
    /// # Cost
    ///
    /// `base`
-    pub fn used_gas(&mut self) -> Gas {
-        self.run_vm(|vm| vm.used_gas()).unwrap()
+    pub fn used_gas(&mut self) -> Result<Gas, JsValue> {
+        self.run("used_gas", &[], |vm| vm.used_gas())
     }
    /// ############
    /// # Math API #
@@ -479,8 +1690,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + write_register_base + write_register_byte * num_bytes`.
-    pub fn random_seed(&mut self, register_id: u64) -> () {
-        self.run_vm(|vm| vm.random_seed(register_id)).unwrap()
+    pub fn random_seed(&mut self, register_id: u64) -> Result<(), JsValue> {
+        self.run("random_seed", &[register_id], |vm| vm.random_seed(register_id))
     }
    /// Hashes the random sequence of bytes using sha256 and returns it into `register_id`.
    ///
@@ -492,8 +1703,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + write_register_base + write_register_byte * num_bytes + sha256_base + sha256_byte * num_bytes`
-    pub fn sha256(&mut self, value_len: u64, value_ptr: u64, register_id: u64) -> () {
-        self.run_vm(|vm| vm.sha256(value_len, value_ptr, register_id)).unwrap()
+    pub fn sha256(&mut self, value_len: u64, value_ptr: u64, register_id: u64) -> Result<(), JsValue> {
+        self.run("sha256", &[value_len, value_ptr, register_id], |vm| vm.sha256(value_len, value_ptr, register_id))
         
     }
    /// Called by gas metering injected into Wasm. Counts both towards `burnt_gas` and `used_gas`.
@@ -503,8 +1714,8 @@ impl VM {
    /// * If passed gas amount somehow overflows internal gas counters returns `IntegerOverflow`;
    /// * If we exceed usage limit imposed on burnt gas returns `GasLimitExceeded`;
    /// * If we exceed the `prepaid_gas` then returns `GasExceeded`.
-    pub fn gas(&mut self, gas_amount: u32) -> () {
-        self.run_vm(|vm| vm.gas(gas_amount)).unwrap()
+    pub fn gas(&mut self, gas_amount: u32) -> Result<(), JsValue> {
+        self.run("gas", &[u64::from(gas_amount)], |vm| vm.gas(gas_amount))
     }
 
    /// ################
@@ -559,8 +1770,8 @@ impl VM {
         arguments_ptr: u64,
         amount_ptr: u64,
         gas: Gas,
-    ) -> u64 {
-        self.run_vm(|vm| vm.promise_create(
+    ) -> Result<u64, JsValue> {
+        self.run("promise_create", &[account_id_len, account_id_ptr, method_name_len, method_name_ptr, arguments_len, arguments_ptr, amount_ptr, gas], |vm| vm.promise_create(
             account_id_len,
             account_id_ptr,
             method_name_len,
@@ -568,7 +1779,7 @@ impl VM {
             arguments_len,
             arguments_ptr,
             amount_ptr,
-            gas)).unwrap()
+            gas))
     }
 
    /// Attaches the callback that is executed after promise pointed by `promise_idx` is complete.
@@ -601,8 +1812,8 @@ impl VM {
         arguments_ptr: u64,
         amount_ptr: u64,
         gas: u64,
-    ) -> u64 {
-        self.run_vm(|vm| vm.promise_then(
+    ) -> Result<u64, JsValue> {
+        self.run("promise_then", &[promise_idx, account_id_len, account_id_ptr, method_name_len, method_name_ptr, arguments_len, arguments_ptr, amount_ptr, gas], |vm| vm.promise_then(
             promise_idx,
             account_id_len,
             account_id_ptr,
@@ -612,7 +1823,7 @@ impl VM {
             arguments_ptr,
             amount_ptr,
             gas,
-        )).unwrap()
+        ))
     }
 
    /// Creates a new promise which completes when time all promises passed as arguments complete.
@@ -640,8 +1851,8 @@ impl VM {
         &mut self,
         promise_idx_ptr: u64,
         promise_idx_count: u64,
-    ) -> PromiseIndex {
-        self.run_vm(|vm| vm.promise_and(promise_idx_ptr, promise_idx_count)).unwrap()
+    ) -> Result<PromiseIndex, JsValue> {
+        self.run("promise_and", &[promise_idx_ptr, promise_idx_count], |vm| vm.promise_and(promise_idx_ptr, promise_idx_count))
     }
 
    /// Creates a new promise towards given `account_id` without any actions attached to it.
@@ -665,8 +1876,8 @@ impl VM {
         &mut self,
         account_id_len: u64,
         account_id_ptr: u64,
-    ) -> u64 {
-        self.run_vm(|vm| vm.promise_batch_create(account_id_len, account_id_ptr)).unwrap()
+    ) -> Result<u64, JsValue> {
+        self.run("promise_batch_create", &[account_id_len, account_id_ptr], |vm| vm.promise_batch_create(account_id_len, account_id_ptr))
     }
 
    /// Creates a new promise towards given `account_id` without any actions attached, that is
@@ -693,8 +1904,8 @@ impl VM {
         promise_idx: u64,
         account_id_len: u64,
         account_id_ptr: u64,
-    ) -> u64 {
-        self.run_vm(|vm| vm.promise_batch_then(promise_idx, account_id_len, account_id_ptr)).unwrap()
+    ) -> Result<u64, JsValue> {
+        self.run("promise_batch_then", &[promise_idx, account_id_len, account_id_ptr], |vm| vm.promise_batch_then(promise_idx, account_id_len, account_id_ptr))
     }
 
    /// Appends `CreateAccount` action to the batch of actions for the given promise pointed by
@@ -711,8 +1922,8 @@ impl VM {
    ///
    /// `burnt_gas := base + dispatch action fee`
    /// `used_gas := burnt_gas + exec action fee`
-    pub fn promise_batch_action_create_account(&mut self, promise_idx: u64) -> () {
-        self.run_vm(|vm| vm.promise_batch_action_create_account(promise_idx)).unwrap()
+    pub fn promise_batch_action_create_account(&mut self, promise_idx: u64) -> Result<(), JsValue> {
+        self.run("promise_batch_action_create_account", &[promise_idx], |vm| vm.promise_batch_action_create_account(promise_idx))
     }
    /// Appends `DeployContract` action to the batch of actions for the given promise pointed by
    /// `promise_idx`.
@@ -735,8 +1946,8 @@ impl VM {
         promise_idx: u64,
         code_len: u64,
         code_ptr: u64,
-    ) -> () {
-        self.run_vm(|vm| vm.promise_batch_action_deploy_contract(promise_idx, code_len, code_ptr)).unwrap()
+    ) -> Result<(), JsValue> {
+        self.run("promise_batch_action_deploy_contract", &[promise_idx, code_len, code_ptr], |vm| vm.promise_batch_action_deploy_contract(promise_idx, code_len, code_ptr))
     }
 
    /// Appends `FunctionCall` action to the batch of actions for the given promise pointed by
@@ -766,8 +1977,8 @@ impl VM {
         arguments_ptr: u64,
         amount_ptr: u64,
         gas: Gas,
-    ) -> () {
-        self.run_vm(|vm| vm.promise_batch_action_function_call(promise_idx, method_name_len, method_name_ptr, arguments_len, arguments_ptr, amount_ptr, gas)).unwrap()
+    ) -> Result<(), JsValue> {
+        self.run("promise_batch_action_function_call", &[promise_idx, method_name_len, method_name_ptr, arguments_len, arguments_ptr, amount_ptr, gas], |vm| vm.promise_batch_action_function_call(promise_idx, method_name_len, method_name_ptr, arguments_len, arguments_ptr, amount_ptr, gas))
     }
 
    /// Appends `Transfer` action to the batch of actions for the given promise pointed by
@@ -790,8 +2001,8 @@ impl VM {
         &mut self,
         promise_idx: u64,
         amount_ptr: u64,
-    ) -> () {
-        self.run_vm(|vm| vm.promise_batch_action_transfer(promise_idx, amount_ptr)).unwrap()
+    ) -> Result<(), JsValue> {
+        self.run("promise_batch_action_transfer", &[promise_idx, amount_ptr], |vm| vm.promise_batch_action_transfer(promise_idx, amount_ptr))
     }
 
    /// Appends `Stake` action to the batch of actions for the given promise pointed by
@@ -817,8 +2028,8 @@ impl VM {
         amount_ptr: u64,
         public_key_len: u64,
         public_key_ptr: u64,
-    ) -> () {
-        self.run_vm(|vm| vm.promise_batch_action_stake(promise_idx, amount_ptr, public_key_len, public_key_ptr)).unwrap()
+    ) -> Result<(), JsValue> {
+        self.run("promise_batch_action_stake", &[promise_idx, amount_ptr, public_key_len, public_key_ptr], |vm| vm.promise_batch_action_stake(promise_idx, amount_ptr, public_key_len, public_key_ptr))
     }
 
    /// Appends `AddKey` action to the batch of actions for the given promise pointed by
@@ -844,8 +2055,8 @@ impl VM {
         public_key_len: u64,
         public_key_ptr: u64,
         nonce: u64,
-    ) -> () {
-        self.run_vm(|vm| vm.promise_batch_action_add_key_with_full_access(promise_idx, public_key_len, public_key_ptr, nonce)).unwrap()
+    ) -> Result<(), JsValue> {
+        self.run("promise_batch_action_add_key_with_full_access", &[promise_idx, public_key_len, public_key_ptr, nonce], |vm| vm.promise_batch_action_add_key_with_full_access(promise_idx, public_key_len, public_key_ptr, nonce))
     }
 
    /// Appends `AddKey` action to the batch of actions for the given promise pointed by
@@ -878,8 +2089,8 @@ impl VM {
         receiver_id_ptr: u64,
         method_names_len: u64,
         method_names_ptr: u64,
-    ) -> () {
-        self.run_vm(|vm| vm.promise_batch_action_add_key_with_function_call(
+    ) -> Result<(), JsValue> {
+        self.run("promise_batch_action_add_key_with_function_call", &[promise_idx, public_key_len, public_key_ptr, nonce, allowance_ptr, receiver_id_len, receiver_id_ptr, method_names_len, method_names_ptr], |vm| vm.promise_batch_action_add_key_with_function_call(
             promise_idx,
             public_key_len,
             public_key_ptr,
@@ -889,7 +2100,7 @@ impl VM {
             receiver_id_ptr,
             method_names_len,
             method_names_ptr,
-        )).unwrap()
+        ))
 
     }
 
@@ -915,8 +2126,8 @@ impl VM {
         promise_idx: u64,
         public_key_len: u64,
         public_key_ptr: u64,
-    ) -> () {
-        self.run_vm(|vm| vm.promise_batch_action_delete_key(promise_idx, public_key_len, public_key_ptr)).unwrap()
+    ) -> Result<(), JsValue> {
+        self.run("promise_batch_action_delete_key", &[promise_idx, public_key_len, public_key_ptr], |vm| vm.promise_batch_action_delete_key(promise_idx, public_key_len, public_key_ptr))
     }
 
    /// Appends `DeleteAccount` action to the batch of actions for the given promise pointed by
@@ -940,8 +2151,8 @@ impl VM {
         promise_idx: u64,
         beneficiary_id_len: u64,
         beneficiary_id_ptr: u64,
-    ) -> () {
-       self.run_vm(|vm| vm.promise_batch_action_delete_account(promise_idx, beneficiary_id_len, beneficiary_id_ptr)).unwrap()
+    ) -> Result<(), JsValue> {
+       self.run("promise_batch_action_delete_account", &[promise_idx, beneficiary_id_len, beneficiary_id_ptr], |vm| vm.promise_batch_action_delete_account(promise_idx, beneficiary_id_len, beneficiary_id_ptr))
     }
 
    /// If the current function is invoked by a callback we can access the execution results of the
@@ -958,8 +2169,8 @@ impl VM {
    /// # Cost
    ///
    /// `base`
-    pub fn promise_results_count(&mut self) -> u64 {
-        self.run_vm(|vm| vm.promise_results_count()).unwrap()
+    pub fn promise_results_count(&mut self) -> Result<u64, JsValue> {
+        self.run("promise_results_count", &[], |vm| vm.promise_results_count())
     }
    /// If the current function is invoked by a callback we can access the execution results of the
    /// promises that caused the callback. This function returns the result in blob format and
@@ -983,8 +2194,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + cost of writing data into a register`
-    pub fn promise_result(&mut self, result_idx: u64, register_id: u64) -> u64 {
-        self.run_vm(|vm| vm.promise_result(result_idx, register_id)).unwrap()
+    pub fn promise_result(&mut self, result_idx: u64, register_id: u64) -> Result<u64, JsValue> {
+        self.run("promise_result", &[result_idx, register_id], |vm| vm.promise_result(result_idx, register_id))
     }
    /// When promise `promise_idx` finishes executing its result is considered to be the result of
    /// the current function.
@@ -997,8 +2208,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + promise_return`
-    pub fn promise_return(&mut self, promise_idx: u64) -> () {
-        self.run_vm(|vm| vm.promise_return(promise_idx)).unwrap()
+    pub fn promise_return(&mut self, promise_idx: u64) -> Result<(), JsValue> {
+        self.run("promise_return", &[promise_idx], |vm| vm.promise_return(promise_idx))
     }
    /// #####################
    /// # Miscellaneous API #
@@ -1013,16 +2224,16 @@ impl VM {
    ///
    /// # Cost
    /// `base + cost of reading return value from memory or register + dispatch&exec cost per byte of the data sent * num data receivers`
-    pub fn value_return(&mut self, value_len: u64, value_ptr: u64) -> () {
-        self.run_vm(|vm| vm.value_return(value_len, value_ptr)).unwrap()
+    pub fn value_return(&mut self, value_len: u64, value_ptr: u64) -> Result<(), JsValue> {
+        self.run("value_return", &[value_len, value_ptr], |vm| vm.value_return(value_len, value_ptr))
     }
    /// Terminates the execution of the program with panic `GuestPanic`.
    ///
    /// # Cost
    ///
    /// `base`
-    pub fn panic(&mut self) -> () {
-        self.run_vm(|vm| vm.panic()).unwrap()
+    pub fn panic(&mut self) -> Result<(), JsValue> {
+        self.run("panic", &[], |vm| vm.panic())
     }
    /// Guest panics with the UTF-8 encoded string.
    /// If `len == u64::MAX` then treats the string as null-terminated with character `'\0'`.
@@ -1035,8 +2246,8 @@ impl VM {
    ///
    /// # Cost
    /// `base + cost of reading and decoding a utf8 string`
-    pub fn panic_utf8(&mut self, len: u64, ptr: u64) -> () {
-        self.run_vm(|vm| vm.panic_utf8(len, ptr)).unwrap()
+    pub fn panic_utf8(&mut self, len: u64, ptr: u64) -> Result<(), JsValue> {
+        self.run("panic_utf8", &[len, ptr], |vm| vm.panic_utf8(len, ptr))
     }
    /// Logs the UTF-8 encoded string.
    /// If `len == u64::MAX` then treats the string as null-terminated with character `'\0'`.
@@ -1050,8 +2261,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + log_base + log_byte + num_bytes + utf8 decoding cost`
-    pub fn log_utf8(&mut self, len: u64, ptr: u64) -> () {
-        self.run_vm(|vm| vm.log_utf8(len, ptr)).unwrap()
+    pub fn log_utf8(&mut self, len: u64, ptr: u64) -> Result<(), JsValue> {
+        self.run("log_utf8", &[len, ptr], |vm| vm.log_utf8(len, ptr))
     }
    /// Logs the UTF-16 encoded string. If `len == u64::MAX` then treats the string as
    /// null-terminated with two-byte sequence of `0x00 0x00`.
@@ -1064,8 +2275,8 @@ impl VM {
    /// # Cost
    ///
    /// `base + log_base + log_byte * num_bytes + utf16 decoding cost`
-    pub fn log_utf16(&mut self, len: u64, ptr: u64) -> () {
-        self.run_vm(|vm| vm.log_utf16(len, ptr)).unwrap()
+    pub fn log_utf16(&mut self, len: u64, ptr: u64) -> Result<(), JsValue> {
+        self.run("log_utf16", &[len, ptr], |vm| vm.log_utf16(len, ptr))
     }
    /// Special import kept for compatibility with AssemblyScript contracts. Not called by smart
    /// contracts directly, but instead called by the code generated by AssemblyScript.
@@ -1073,8 +2284,8 @@ impl VM {
    /// # Cost
    ///
    /// `base +  log_base + log_byte * num_bytes + utf16 decoding cost`
-    pub fn abort(&mut self, msg_ptr: u32, filename_ptr: u32, line: u32, col: u32) -> () {
-        self.run_vm(|vm| vm.abort(msg_ptr, filename_ptr, line, col)).unwrap()
+    pub fn abort(&mut self, msg_ptr: u32, filename_ptr: u32, line: u32, col: u32) -> Result<(), JsValue> {
+        self.run("abort", &[u64::from(msg_ptr), u64::from(filename_ptr), u64::from(line), u64::from(col)], |vm| vm.abort(msg_ptr, filename_ptr, line, col))
     }
    /// ###############
    /// # Storage API #
@@ -1116,13 +2327,13 @@ impl VM {
         value_len: u64,
         value_ptr: u64,
         register_id: u64,
-    ) -> u64 {
-        self.run_vm(|vm| {
+    ) -> Result<u64, JsValue> {
+        self.run("storage_write", &[key_len, key_ptr, value_len, value_ptr, register_id], |vm| {
             // console::log_1(&vm.current_storage_usage.to_string().into());
             let res = vm.storage_write(key_len, key_ptr, value_len, value_ptr, register_id); 
             // console::log_1(&vm.current_storage_usage.to_string().into());
             res
-        }).unwrap()
+        })
     }
 
    /// Reads the value stored under the given key.
@@ -1141,8 +2352,8 @@ impl VM {
    ///
    /// `base + storage_read_base + storage_read_key_byte * num_key_bytes + storage_read_value_byte + num_value_bytes
    ///  cost to read key from register + cost to write value into register`.
-    pub fn storage_read(&mut self, key_len: u64, key_ptr: u64, register_id: u64) -> u64 {
-        self.run_vm(|vm| vm.storage_read(key_len, key_ptr, register_id)).unwrap()
+    pub fn storage_read(&mut self, key_len: u64, key_ptr: u64, register_id: u64) -> Result<u64, JsValue> {
+        self.run("storage_read", &[key_len, key_ptr, register_id], |vm| vm.storage_read(key_len, key_ptr, register_id))
     }
    /// Removes the value stored under the given key.
    /// * If key is used, removes the key-value from the trie and copies the content of the value
@@ -1161,8 +2372,8 @@ impl VM {
    ///
    /// `base + storage_remove_base + storage_remove_key_byte * num_key_bytes + storage_remove_ret_value_byte * num_value_bytes
    /// + cost to read the key + cost to write the value`.
-    pub fn storage_remove(&mut self, key_len: u64, key_ptr: u64, register_id: u64) -> u64 {
-        self.run_vm(|vm| vm.storage_remove(key_len, key_ptr, register_id)).unwrap()
+    pub fn storage_remove(&mut self, key_len: u64, key_ptr: u64, register_id: u64) -> Result<u64, JsValue> {
+        self.run("storage_remove", &[key_len, key_ptr, register_id], |vm| vm.storage_remove(key_len, key_ptr, register_id))
     }
     /// Checks if there is a key-value pair.
     /// * If key is used returns `1`, even if the value is zero bytes;
@@ -1175,8 +2386,8 @@ impl VM {
     /// # Cost
     ///
     /// `base + storage_has_key_base + storage_has_key_byte * num_bytes + cost of reading key`
-    pub fn storage_has_key(&mut self, key_len: u64, key_ptr: u64) -> u64 {
-        self.run_vm(|vm| vm.storage_has_key(key_len, key_ptr)).unwrap()
+    pub fn storage_has_key(&mut self, key_len: u64, key_ptr: u64) -> Result<u64, JsValue> {
+        self.run("storage_has_key", &[key_len, key_ptr], |vm| vm.storage_has_key(key_len, key_ptr))
     }
    /// Creates an iterator object inside the host. Returns the identifier that uniquely
    /// differentiates the given iterator from other iterators that can be simultaneously created.
@@ -1192,8 +2403,8 @@ impl VM {
    ///
    /// `base + storage_iter_create_prefix_base + storage_iter_create_key_byte * num_prefix_bytes
    ///  cost of reading the prefix`.
-    pub fn storage_iter_prefix(&mut self, prefix_len: u64, prefix_ptr: u64) -> u64 {
-        self.run_vm(|vm| vm.storage_iter_prefix(prefix_len, prefix_ptr)).unwrap()
+    pub fn storage_iter_prefix(&mut self, prefix_len: u64, prefix_ptr: u64) -> Result<u64, JsValue> {
+        self.run("storage_iter_prefix", &[prefix_len, prefix_ptr], |vm| vm.storage_iter_prefix(prefix_len, prefix_ptr))
     }
    /// Iterates over all key-values such that keys are between `start` and `end`, where `start` is
    /// inclusive and `end` is exclusive. Unless lexicographically `start < end`, it creates an
@@ -1215,8 +2426,8 @@ impl VM {
         start_ptr: u64,
         end_len: u64,
         end_ptr: u64,
-    ) -> u64 {
-        self.run_vm(|vm| vm.storage_iter_range(start_len, start_ptr, end_len, end_ptr)).unwrap()
+    ) -> Result<u64, JsValue> {
+        self.run("storage_iter_range", &[start_len, start_ptr, end_len, end_ptr], |vm| vm.storage_iter_range(start_len, start_ptr, end_len, end_ptr))
     }
 
    /// Advances iterator and saves the next key and value in the register.
@@ -1251,37 +2462,178 @@ impl VM {
         iterator_id: u64,
         key_register_id: u64,
         value_register_id: u64,
-    ) -> u64 {
-        self.run_vm(|vm| vm.storage_iter_next(iterator_id, key_register_id, value_register_id)).unwrap()
+    ) -> Result<u64, JsValue> {
+        self.run("storage_iter_next", &[iterator_id, key_register_id, value_register_id], |vm| vm.storage_iter_next(iterator_id, key_register_id, value_register_id))
     }
 
    ///Computes the outcome of execution.
     pub fn outcome(&mut self) -> JsValue {
-        let mut vm = self.builder.build(self.context.clone());
-        if self.internal_state.is_some() {
-            vm.restore_state(self.internal_state.as_ref().unwrap());
-        }
-        let res = vm.outcome();
-        let outcome = _VMOutcome {
-            balance1: (res.balance >> 64) as u64,
-            balance2: ((res.balance << 64) >> 64) as u64,
-            storage_usage: res.storage_usage,
-            return_data: res.return_data,
-            burnt_gas: res.burnt_gas,
-            used_gas: res.used_gas,
-            logs: res.logs
-        };
+        let res = self.current_outcome();
+        let mut outcome = _VMOutcome::from(res);
+        outcome.faults = self.faults.fired.clone();
         serde_wasm_bindgen::to_value(&outcome).unwrap()
     }
 }
 
+/// Without the interpreter, contracts run in the JS host and receipts aren't executed, so the
+/// methods that run blocks reject.
+#[cfg(not(feature = "interpreter"))]
+#[wasm_bindgen]
+impl VM {
+    #[wasm_bindgen(js_name = callAsync)]
+    pub fn call_async(&mut self, _receiver_id: String, method: String, _args: String, _opts: JsValue, _max_blocks: Option<u32>) -> js_sys::Promise {
+        needs_interpreter(&format!("Calling {} through the scheduler", method))
+    }
+
+    #[wasm_bindgen(js_name = nextBlock)]
+    pub fn next_block_async(&mut self) -> js_sys::Promise {
+        needs_interpreter("Running blocks")
+    }
+
+    #[wasm_bindgen(js_name = runUntilIdle)]
+    pub fn run_until_idle_async(&mut self, _max_blocks: Option<u32>) -> js_sys::Promise {
+        needs_interpreter("Running blocks")
+    }
+}
+
+#[cfg(not(feature = "interpreter"))]
+fn needs_interpreter(what: &str) -> js_sys::Promise {
+    let error = JsValue::from(js_sys::Error::new(&format!("{} needs the `interpreter` feature", what)));
+    future_to_promise(async move { Err(error) })
+}
+
 #[derive(Serialize)]
 pub struct _VMOutcome {
-    pub balance1: u64,
-    pub balance2: u64,
+    /// Decimal string, since JS numbers can't hold a `u128`.
+    pub balance: String,
     pub storage_usage: StorageUsage,
     pub return_data: ReturnData,
     pub burnt_gas: Gas,
     pub used_gas: Gas,
     pub logs: Vec<String>,
+    /// Faults injected by the fault plan.
+    pub faults: Vec<FiredFault>,
+}
+
+impl From<VMOutcome> for _VMOutcome {
+    fn from(res: VMOutcome) -> Self {
+        _VMOutcome {
+            balance: res.balance.to_string(),
+            storage_usage: res.storage_usage,
+            return_data: res.return_data,
+            burnt_gas: res.burnt_gas,
+            used_gas: res.used_gas,
+            logs: res.logs,
+            faults: vec![],
+        }
+    }
+}
+
+#[cfg(all(test, feature = "interpreter"))]
+mod tests {
+    use super::{CallOptions, VM};
+    use crate::mock::test_contracts::{context, wasm, ATTACHED_GAS, CONTRACT};
+    use near_vm_logic::External;
+
+    #[test]
+    fn keeps_the_balance_across_blocks() {
+        let mut vm = VM::from_context(context());
+        vm.load_contract(wasm(CONTRACT));
+        let balance = vm.context.account_balance;
+        for _ in 0..3 {
+            vm.step_block().unwrap();
+            assert_eq!(vm.context.account_balance, balance);
+            // The ledger holds the balance the contract sees, with the attached deposit.
+            assert_eq!(vm.scheduler().accounts["alice"].balance, balance + vm.context.attached_deposit);
+        }
+    }
+
+    #[test]
+    fn estimates_the_least_gas_that_succeeds() {
+        let mut vm = VM::from_context(context());
+        vm.load_contract(wasm(CONTRACT));
+        vm.instrument_contract("contract.wasm").unwrap();
+        let estimate = vm.estimate_method_gas("call_echo", b"hi".to_vec(), context()).unwrap();
+        assert_eq!(estimate.promise_gas, 2 * ATTACHED_GAS);
+        assert!(estimate.prepaid_gas >= estimate.used_gas);
+        assert!(vm.coverage().unwrap().summaries().iter().all(|function| function.calls == 0));
+        assert!(vm.ext().get_receipt_create_calls().is_empty());
+
+        let call = |vm: &mut VM, gas| {
+            let opts = CallOptions { gas: Some(gas), deposit: context().attached_deposit, ..CallOptions::default() };
+            vm.execute("call_echo", b"hi".to_vec(), opts, false).unwrap()
+        };
+        assert!(call(&mut vm, estimate.prepaid_gas - 1).error.is_some());
+        let res = call(&mut vm, estimate.prepaid_gas);
+        assert_eq!(res.error, None);
+        assert_eq!(res.outcome.burnt_gas, estimate.burnt_gas);
+    }
+
+    #[test]
+    fn fails_to_estimate_a_method_that_fails_with_the_most_gas() {
+        let mut vm = VM::from_context(context());
+        vm.load_contract(wasm(CONTRACT));
+        let error = vm.estimate_method_gas("fail", vec![], context()).err().unwrap();
+        assert!(error.starts_with("fail fails even with"));
+        assert!(error.contains("GuestPanic"));
+        assert!(vm.ext().fake_trie.is_empty());
+        assert!(vm.ext().get_receipt_create_calls().is_empty());
+    }
+
+    #[test]
+    fn only_calls_that_stay_take_a_position_in_the_block() {
+        let mut vm = VM::from_context(context());
+        vm.load_contract(wasm(CONTRACT));
+        vm.set_seed_derivation(true);
+        let call = |vm: &mut VM, method: &str| {
+            let res = vm.execute(method, b"x".to_vec(), CallOptions::default(), false).unwrap();
+            (res.error.is_none(), vm.seed_derivation.as_ref().unwrap().receipt_index)
+        };
+        assert_eq!(call(&mut vm, "bad_register"), (false, 0));
+        vm.estimate_method_gas("store", b"x".to_vec(), context()).unwrap();
+        assert_eq!(call(&mut vm, "store"), (true, 0));
+        assert_eq!(call(&mut vm, "store"), (true, 1));
+    }
+
+    #[test]
+    fn keeps_the_storage_when_a_receipt_cannot_be_scheduled() {
+        let mut vm = VM::from_context(context());
+        vm.load_contract(wasm(CONTRACT));
+        let res = vm.execute("call_echo", b"hi".to_vec(), CallOptions::default(), false).unwrap();
+        assert_eq!(res.error, None);
+        vm.step_block().unwrap();
+        vm.execute("store", b"x".to_vec(), CallOptions::default(), false).unwrap();
+        // Depends on a receipt of the previous execution.
+        vm.ext_mut().create_receipt(vec![0], "bob".to_string()).unwrap();
+        let error = vm.step_block().unwrap_err();
+        assert!(error.contains("same execution"));
+        assert_eq!(vm.ext().fake_trie.get(&b"key".to_vec()), Some(&b"x".to_vec()));
+        assert_eq!(vm.scheduler().block_index, 1);
+    }
+
+    #[test]
+    fn reports_where_two_runs_diverge() {
+        let mut vm = VM::from_context(context());
+        vm.load_contract(wasm(CONTRACT));
+        vm.set_determinism_check(true);
+        let res = vm.execute("store", b"x".to_vec(), CallOptions::default(), false).unwrap();
+        assert_eq!(res.error, None);
+        assert!(vm.nondeterminism().is_empty());
+
+        // A second run that returns its input instead of writing it, like a contract whose
+        // behaviour depends on something outside of its state.
+        vm.start_call("store".to_string(), b"y".to_vec(), CallOptions::default(), false).unwrap();
+        let error = vm.run_interpreter("store", false);
+        vm.restart_call(error).unwrap();
+        assert!(vm.restart_call(None).unwrap_err().contains("already ran twice"));
+        let error = vm.run_interpreter("echo", false);
+        let message = vm.finish_call(error).err().unwrap();
+        assert!(message.starts_with("Nondeterminism in store: the outcome, storage differed"));
+        let call = vm.nondeterminism()[0].diverging_call.clone().unwrap();
+        assert_eq!(call.index, 3);
+        assert_eq!(call.first.unwrap().name, "storage_write");
+        assert_eq!(call.second.unwrap().name, "value_return");
+        // The call was rolled back.
+        assert_eq!(vm.ext().fake_trie.get(&b"key".to_vec()), Some(&b"x".to_vec()));
+    }
 }
@@ -1,3 +1,6 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
 pub fn set_panic_hook() {
     // When the `console_error_panic_hook` feature is enabled, we can call the
     // `set_panic_hook` function at least once during initialization, and then
@@ -9,6 +12,53 @@ pub fn set_panic_hook() {
     console_error_panic_hook::set_once();
 }
 
-pub fn u128_from_u64s(lo: u64, hi: u64) -> u128 {
-    u128::from(hi).rotate_left(64) + u128::from(lo)
+/// Parses a `u128` from a decimal string, a number or a `BigInt`.
+pub fn u128_from_js(value: &JsValue) -> Result<u128, JsValue> {
+    if value.is_undefined() || value.is_null() {
+        return Err(js_sys::Error::new("Expected a u128, found nothing").into());
+    }
+    let s: String = match value.as_string() {
+        Some(s) => s,
+        None => value.unchecked_ref::<js_sys::Object>().to_string().into(),
+    };
+    s.trim()
+        .parse::<u128>()
+        .map_err(|_| js_sys::Error::new(&format!("Invalid u128: {}", s)).into())
+}
+
+/// Small deterministic random number generator (SplitMix64), so that anything random in the mock
+/// can be replayed from its seed.
+#[derive(Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Renders bytes as UTF-8 when they are valid UTF-8, and as `0x` prefixed hex otherwise.
+pub fn display_bytes(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => to_hex(bytes),
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("0x{}", hex)
 }
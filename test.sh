@@ -1,2 +1,2 @@
 #! /usr/bin/sh
-wasm-pack --verbose build --target nodejs --debug && node tests/test.js 
\ No newline at end of file
+wasm-pack --verbose build --target nodejs --debug -- --features interpreter && node tests/test.js 
\ No newline at end of file
@@ -0,0 +1,74 @@
+//! Runs the command line interface against a contract written to a temporary directory.
+#![cfg(feature = "interpreter")]
+
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// `store` writes its input under `key`, and `load` returns the value under `key`.
+const CONTRACT: &str = r#"(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (import "env" "storage_write" (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "storage_read" (func $storage_read (param i64 i64 i64) (result i64)))
+  (memory (export "memory") 1)
+  (data (i32.const 16) "key")
+  (func (export "store")
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 1024))
+    (drop (call $storage_write (i64.const 3) (i64.const 16) (call $register_len (i64.const 0)) (i64.const 1024) (i64.const 1))))
+  (func (export "load")
+    (drop (call $storage_read (i64.const 3) (i64.const 16) (i64.const 0)))
+    (call $read_register (i64.const 0) (i64.const 1024))
+    (call $value_return (call $register_len (i64.const 0)) (i64.const 1024))))
+"#;
+
+/// Writes the contract and a state with `key` set to `old`, and returns the directory.
+fn setup(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wasm-mock-vm-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("contract.wasm"), wat::parse_str(CONTRACT).unwrap()).unwrap();
+    fs::write(dir.join("state.json"), r#"{ "key": "old" }"#).unwrap();
+    dir
+}
+
+fn cli(dir: &PathBuf, args: &[&str]) -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_wasm-mock-vm"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn view_reads_the_loaded_state() {
+    let dir = setup("view");
+    let outcome = cli(&dir, &["view", "contract.wasm", "load", "--state", "state.json"]);
+    assert_eq!(outcome["error"], Value::Null);
+    assert_eq!(outcome["return_value"], "old");
+    // The default context's 12 bytes, and the 6 of the loaded key and value.
+    assert_eq!(outcome["storage_usage"], 18);
+}
+
+#[test]
+fn run_writes_over_the_loaded_state() {
+    let dir = setup("run");
+    let outcome = cli(&dir, &["run", "contract.wasm", "store", "--args", "newer", "--state", "state.json"]);
+    assert_eq!(outcome["error"], Value::Null);
+    assert_eq!(outcome["storage_usage"], 20);
+    assert!(outcome["burnt_gas"].as_u64().unwrap() > 0);
+    assert_eq!(outcome["receipts"], Value::Array(vec![]));
+}
+
+#[test]
+fn fails_without_a_method() {
+    let dir = setup("usage");
+    let output = Command::new(env!("CARGO_BIN_EXE_wasm-mock-vm")).current_dir(&dir).args(&["run", "contract.wasm"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Missing <method>"));
+}
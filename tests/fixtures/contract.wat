@@ -0,0 +1,67 @@
+;; The contract of the tests, see `CONTRACT` in src/mock/test_contracts.rs. contract.wasm is
+;; built from it with `wat2wasm contract.wat`.
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (import "env" "storage_write" (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "storage_read" (func $storage_read (param i64 i64 i64) (result i64)))
+  (import "env" "promise_create" (func $promise_create (param i64 i64 i64 i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "promise_then" (func $promise_then (param i64 i64 i64 i64 i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "promise_result" (func $promise_result (param i64 i64) (result i64)))
+  (import "env" "promise_return" (func $promise_return (param i64)))
+  (import "env" "promise_batch_create" (func $promise_batch_create (param i64 i64) (result i64)))
+  (import "env" "promise_batch_action_deploy_contract" (func $deploy_contract (param i64 i64 i64)))
+  (import "env" "promise_batch_action_function_call" (func $function_call (param i64 i64 i64 i64 i64 i64 i64)))
+  (import "env" "panic" (func $panic))
+  (memory (export "memory") 1)
+  ;; The deposit attached to promises, 0, is the u128 at 0.
+  (data (i32.const 16) "key")
+  (data (i32.const 32) "bob")
+  (data (i32.const 48) "echo")
+  (data (i32.const 64) "on_echo")
+  (data (i32.const 80) "alice")
+  (data (i32.const 96) "migrate")
+  ;; Copies the input to 1024 and returns its length.
+  (func $read_input (result i64)
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 1024))
+    (call $register_len (i64.const 0)))
+  (func $return_register
+    (call $read_register (i64.const 0) (i64.const 1024))
+    (call $value_return (call $register_len (i64.const 0)) (i64.const 1024)))
+  (func $create_echo (param $len i64) (result i64)
+    (call $promise_create (i64.const 3) (i64.const 32) (i64.const 4) (i64.const 48)
+      (local.get $len) (i64.const 1024) (i64.const 0) (i64.const 10000000000000)))
+  (func (export "store")
+    (drop (call $storage_write (i64.const 3) (i64.const 16) (call $read_input) (i64.const 1024) (i64.const 1))))
+  (func (export "load")
+    (drop (call $storage_read (i64.const 3) (i64.const 16) (i64.const 0)))
+    (call $return_register))
+  (func (export "echo")
+    (call $input (i64.const 0))
+    (call $return_register))
+  (func (export "call_echo")
+    (drop (call $promise_then (call $create_echo (call $read_input))
+      (i64.const 5) (i64.const 80) (i64.const 7) (i64.const 64)
+      (i64.const 0) (i64.const 0) (i64.const 0) (i64.const 10000000000000))))
+  (func (export "on_echo")
+    (drop (call $promise_result (i64.const 0) (i64.const 0)))
+    (call $return_register))
+  (func (export "forward")
+    (call $promise_return (call $create_echo (call $read_input))))
+  (func (export "fail")
+    (drop (call $storage_write (i64.const 3) (i64.const 16) (i64.const 3) (i64.const 16) (i64.const 1)))
+    (drop (call $create_echo (i64.const 0)))
+    (call $panic))
+  (func (export "upgrade")
+    (local $batch i64)
+    (local $len i64)
+    (local.set $len (call $read_input))
+    (local.set $batch (call $promise_batch_create (i64.const 5) (i64.const 80)))
+    (call $deploy_contract (local.get $batch) (local.get $len) (i64.const 1024))
+    (call $function_call (local.get $batch) (i64.const 7) (i64.const 96)
+      (i64.const 0) (i64.const 0) (i64.const 0) (i64.const 10000000000000)))
+  (func (export "bad_register")
+    (call $read_register (i64.const 99) (i64.const 1024))))
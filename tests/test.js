@@ -2,6 +2,8 @@ let rust = require("../pkg/wasm_mock_vm");
 let assert = require("assert");
 let utils = require('./utils');
 let bs58 = require("bs58");
+let fs = require("fs");
+let path = require("path");
 
 const memory = new Uint8Array(10000);
 
@@ -34,45 +36,10 @@ global.write_memory = (offset, buffer) => {
     memory.set(buffer, utils.toNum(offset));
 }
 
-const current_account_id = "alice"; 
-const signer_account_id = "bob";
-const signer_account_pk = "HuxUynD5GdrcZ5MauxJuu74sGHgS6wLfCqqhQkLWK";
-const predecessor_account_id = "carol";
-const input = "{ arg1: 1 }";
-const block_index = 10;
-const block_timestamp = 42;
-const account_balance = 2;
-const account_locked_balance = 1;
-const storage_usage = 12;
-const attached_deposit = 2;
-const prepaid_gas = 10**(14);
-const random_seed = "HuxUynD5GdrcZ5MauxJuu74sGHgS6wLfCqqhQkLWK";
-const is_view = false;
-const output_data_receivers= new Uint8Array([]);
-
-
-context =  {
-    /// The account id of the current contract that we are executing.
-    current_account_id, // string
-    /// The account id of that signed the original transaction that led to this
-    /// execution.
-    signer_account_id, // string
-    /// The public key that was used to sign the original transaction that led to
-    /// this execution.
-    signer_account_pk,
-    predecessor_account_id,
-    input,
-    block_index,
-    block_timestamp,
-    account_balance,
-    account_locked_balance,
-    storage_usage,
-    attached_deposit,
-    prepaid_gas,
-    random_seed,
-    is_view,
-    output_data_receivers,
-}
+// The default context, the same as `context.json` and the one `imports.js` falls back to
+context = new rust.ContextBuilder().build();
+const { signer_account_pk, input } = context;
+assert.deepEqual(context, JSON.parse(fs.readFileSync(path.join(__dirname, "..", "context.json"), "utf8")));
 
 function readReg(id) {
     const ptr = 10;
@@ -92,7 +59,9 @@ function readReg(id) {
 let vm = new rust.VM(context);
 vm.signer_account_pk(BigInt(1));
 // vm.read_register(BigInt(1), BigInt(1));
-assert.equal(bs58.encode(Buffer.from(readReg(1))), signer_account_pk);
+// Contracts get the serialized key: the curve, 0 for ed25519, then the key
+assert.deepEqual(Buffer.from(readReg(1)), Buffer.concat([Buffer.from([0]), bs58.decode(signer_account_pk.split(":")[1])]));
+assert.equal(vm.signer_public_key(), signer_account_pk);
 debugger;
 // vm.read_register(BigInt(0), BigInt(0));
 // assert(memory[0] == 42);
@@ -174,6 +143,14 @@ memory.set(str, 4000);
 vm.log_utf8(BigInt(str.length), BigInt(4000));
 
 console.log(vm.outcome());
+
+// Balances are passed as decimal strings or BigInts
+let balanceVM = new rust.VM(context);
+balanceVM.set_account_balance("1000000000000000000000000000000");
+assert.equal(balanceVM.outcome().balance, "1000000000000000000000000000002");
+balanceVM.set_balance_includes_deposit(true);
+balanceVM.set_account_balance(10n ** 30n);
+assert.equal(balanceVM.outcome().balance, "1000000000000000000000000000000");
 // vm.read_register(BigInt(0), BigInt(0));
 // assert(memory[0] == 84);
 
@@ -185,7 +162,246 @@ console.log(vm.outcome());
 
 
 
+// Hooks can script the results of host functions
+let timestamps = [100, 200, 300];
+vm.before_host_call("block_timestamp", (call) => ({ return: timestamps[call.count - 1] }));
+assert.equal(utils.toNum(vm.block_timestamp()), 100);
+assert.equal(utils.toNum(vm.block_timestamp()), 200);
+
+vm.before_host_call("storage_write", (call) => call.count == 2 ? { error: "GasExceeded" } : undefined);
+storage_write("hooked1", data);
+assert.throws(() => storage_write("hooked2", data), /GasExceeded/);
+assert(!storage_has_key("hooked2"));
+vm.before_host_call("read_register", () => ({ error: "MemoryAccessViolation" }));
+assert.throws(() => vm.read_register(BigInt(0), BigInt(0)), /MemoryAccessViolation/);
+vm.before_host_call("register_len", () => ({ error: "GasExceeded" }));
+assert.throws(() => vm.register_len(BigInt(0)), /GasExceeded/);
+vm.clear_host_hooks();
+vm.read_register(BigInt(99), BigInt(0));
+assert.equal(vm.register_len(BigInt(99)), 2n ** 64n - 1n);
+
+// Fault plans inject failures and report them in the outcome
+vm.set_fault_plan({ storage_write_limit: 20 });
+storage_write("fault1", data);
+assert.throws(() => storage_write("fault2", data), /GasExceeded/);
+assert.equal(vm.outcome().faults[0].kind, "storage_write_limit");
+vm.clear_fault_plan();
+
+// Account ids are validated like on chain
+assert.throws(() => vm.set_current_account_id("Alice!!"), /InvalidAccountId/);
+let badReceiver = utils.StrtoUTF8("Alice!!");
+memory.set(badReceiver, 5000);
+assert.throws(() => vm.promise_batch_create(BigInt(badReceiver.length), BigInt(5000)), /InvalidAccountId/);
+
+// Public keys are parsed and checked for their curve and length
+assert.equal(rust.parse_public_key(signer_account_pk.split(":")[1]).string, signer_account_pk);
+assert.equal(rust.parse_public_key(signer_account_pk).bytes.length, 33);
+assert.throws(() => vm.set_signer_account_pk("HuxUynD5GdrcZ5MauxJuu74sGHgS6wLfCqqhQkLWK"), /InvalidPublicKey/);
+assert.throws(() => vm.set_signer_account_pk("rsa:" + signer_account_pk.split(":")[1]), /InvalidPublicKey/);
+
+// Context builders chain setters and presets, and check the context when it's built
+let callback = new rust.ContextBuilder().current_account_id("exchange").attached_deposit("10").cross_contract_callback().build();
+assert.equal(callback.predecessor_account_id, "exchange");
+assert.equal(callback.attached_deposit, "0");
+let view = new rust.ContextBuilder().view_call().build();
+assert(view.is_view);
+assert.equal(view.signer_account_id, view.current_account_id);
+assert.equal(new rust.ContextBuilder().signer_account_id("dave").wallet_transaction().build().predecessor_account_id, "dave");
+let invalid = new rust.ContextBuilder().view_call().attached_deposit("1").predecessor_account_id("Bad!");
+assert.equal(invalid.validate().length, 2);
+assert.throws(() => invalid.build(), /Invalid context/);
+
+// The storage view groups the keys of near-runtime-ts collections
+let collectionsVM = new rust.VM(context);
+let store = (k, v) => {
+  let [key, value] = [utils.StrtoUTF8(k), utils.StrtoUTF8(v)];
+  memory.set(key, 1000);
+  memory.set(value, 2000);
+  collectionsVM.storage_write(BigInt(key.length), BigInt(1000), BigInt(value.length), BigInt(2000), BigInt(1));
+};
+store("v:len", "2");
+store("v::1", '{"b":2}');
+store("v::0", '{"a":1}');
+store("m::alice", "10");
+store("d:front", "-1");
+store("d:back", "0");
+store("d::-1", "first");
+store("d::0", "last");
+store("plain", "value");
+let storageView = collectionsVM.storage_view("json");
+assert.deepEqual(storageView.collections, [
+  { kind: "deque", prefix: "d", front: -1, back: 0, items: [{ index: -1, value: "first" }, { index: 0, value: "last" }] },
+  { kind: "map", prefix: "m", entries: [{ key: "alice", value: 10 }] },
+  { kind: "vector", prefix: "v", length: 2, items: [{ index: 0, value: { a: 1 } }, { index: 1, value: { b: 2 } }] },
+]);
+assert.deepEqual(storageView.other, [{ key: "plain", value: "value" }]);
+assert.equal(collectionsVM.storage_view("hex").other[0].value, "0x76616c7565");
+
+// Invariants are checked after every call, and report the call and host calls that broke them
+let invariantVM = new rust.VM(context);
+let writeTo = (target, k, v) => {
+  let [key, value] = [utils.StrtoUTF8(k), utils.StrtoUTF8(v)];
+  memory.set(key, 1000);
+  memory.set(value, 2000);
+  target.storage_write(BigInt(key.length), BigInt(1000), BigInt(value.length), BigInt(2000), BigInt(1));
+};
+invariantVM.add_invariant("no forbidden key", ({ storage }) => !("forbidden" in storage));
+invariantVM.begin_call("allowed", "", {}, false);
+writeTo(invariantVM, "allowed", data);
+invariantVM.end_call();
+invariantVM.begin_call("breaks", "", {}, false);
+writeTo(invariantVM, "forbidden", data);
+assert.throws(() => invariantVM.end_call(), /Invariant no forbidden key broken by breaks/);
+let [failure] = invariantVM.invariant_failures();
+assert.equal(failure.call, "breaks");
+assert.deepEqual(failure.trace.map(call => call.name), ["storage_write"]);
+
+// With the determinism check, calls run twice from the same state and must end the same way
+let determinismVM = new rust.VM(context);
+determinismVM.set_determinism_check(true);
+determinismVM.begin_call("stable", "", {}, false);
+writeTo(determinismVM, "key", data);
+determinismVM.rerun_call();
+writeTo(determinismVM, "key", data);
+determinismVM.end_call();
+determinismVM.begin_call("unstable", "", {}, false);
+writeTo(determinismVM, "key", "first");
+determinismVM.rerun_call();
+writeTo(determinismVM, "key", "second");
+assert.throws(() => determinismVM.end_call(), /Nondeterminism in unstable: the outcome, storage differed/);
+let [report] = determinismVM.nondeterminism_reports();
+assert.equal(report.diverging_call.index, 0);
+assert.equal(report.diverging_call.first.name, "storage_write");
+assert.equal(determinismVM.storage_view().other[0].value, data);
+// Both runs see the same counts in hooks, so count-based hooks don't make calls nondeterministic
+determinismVM.before_host_call("storage_write", ({ count }) => count > 1 ? { error: "GasExceeded" } : undefined);
+determinismVM.begin_call("counted", "", {}, false);
+writeTo(determinismVM, "key", data);
+determinismVM.rerun_call();
+writeTo(determinismVM, "key", data);
+determinismVM.end_call();
+determinismVM.clear_host_hooks();
+
+// Derived seeds differ per call and block, and the trace records how they were derived
+let seedVM = new rust.VM(context);
+seedVM.set_seed_derivation(true);
+let draw = () => {
+  seedVM.begin_call("draw", "", {}, false);
+  seedVM.random_seed(BigInt(0));
+  let length = utils.toNum(seedVM.register_len(BigInt(0)));
+  seedVM.read_register(BigInt(0), BigInt(5000));
+  seedVM.end_call();
+  return bs58.encode(Buffer.from(memory.slice(5000, 5000 + length)));
+};
+let seeds = [draw(), draw()];
+seedVM.set_block_index(BigInt(context.block_index + 1));
+seeds.push(draw());
+assert.equal(new Set(seeds).size, 3);
+let [seedCall] = seedVM.host_calls();
+assert.deepEqual(seedCall.seed, seedVM.seed_derivation());
+let { base_seed, block_index, receipt_index, seed } = seedCall.seed;
+let le64 = (n) => { let b = Buffer.alloc(8); b.writeBigUInt64LE(BigInt(n)); return b; };
+let expectedSeed = require("crypto").createHash("sha256")
+  .update(Buffer.concat([Buffer.from(bs58.decode(base_seed)), le64(block_index), le64(receipt_index)])).digest();
+assert.deepEqual([base_seed, block_index, receipt_index], [context.random_seed, context.block_index + 1, 0]);
+assert.equal(seed, bs58.encode(expectedSeed));
+assert.equal(seeds[2], seed);
+
+// Storage diffs between checkpoints
+vm.checkpoint("before");
+storage_write("key", "new data");
+storage_write("diffKey", data);
+let diff = vm.storage_diff("before");
+assert.deepEqual(diff.added, [{ key: "diffKey", value: data }]);
+assert.deepEqual(diff.modified, [{ key: "key", old: data, new: "new data" }]);
+assert.equal(diff.removed.length, 0);
+
+// Snapshots compare canonical JSON, with a tolerance for gas
+let snapshot = vm.snapshot(JSON.stringify(vm.outcome()), "before");
+assert.deepEqual(JSON.parse(snapshot).storage_diff.added, [{ key: "diffKey", value: data }]);
+assert.equal(rust.compare_snapshots(snapshot, snapshot, 0).length, 0);
+let moreGas = JSON.parse(snapshot);
+moreGas.outcome.used_gas = Math.floor(moreGas.outcome.used_gas * 1.005);
+assert.equal(rust.compare_snapshots(snapshot, JSON.stringify(moreGas), 0.01).length, 0);
+assert.equal(rust.compare_snapshots(snapshot, JSON.stringify(moreGas), 0)[0].path, "outcome.used_gas");
+
+// The RPC stand-in keeps a ledger of accounts and applies signed transactions
+let { MockRpc } = require("../rpc");
+let rpc = new MockRpc({ alice: { balance: "100" }, bob: {} });
+function borshString(s) {
+  let len = Buffer.alloc(4);
+  len.writeUInt32LE(s.length);
+  return Buffer.concat([len, Buffer.from(s)]);
+}
+let transfer = Buffer.concat([
+  borshString("alice"), Buffer.alloc(33), Buffer.from([1, 0, 0, 0, 0, 0, 0, 0]), borshString("bob"), Buffer.alloc(32),
+  Buffer.from([1, 0, 0, 0, 3, 10]), Buffer.alloc(15), Buffer.alloc(65),
+]);
+let tx = rpc.handle({ id: 1, method: "broadcast_tx_commit", params: [transfer.toString("base64")] });
+assert.deepEqual(tx.result.status, { SuccessValue: "" });
+let viewAccount = (account_id) => rpc.handle({ id: 2, method: "query", params: { request_type: "view_account", account_id } }).result;
+assert.equal(viewAccount("alice").amount, "90");
+assert.equal(viewAccount("bob").amount, "10");
+assert.equal(rpc.handle({ id: 3, method: "block", params: { finality: "final" } }).result.header.height, 2);
+// Contracts can only import the host functions of the VM, here `env.foo` isn't one
+let importsFoo = Buffer.from([
+  0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+  0x02, 0x0b, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x03, 0x66, 0x6f, 0x6f, 0x00, 0x00,
+]);
+assert.throws(() => rpc.addAccount("carol", { code: importsFoo }), /env\.foo/);
+
+// Coverage counts the blocks run by an instrumented module, when built with the `coverage` feature
+if (rust.Coverage) {
+  let coverage = new rust.Coverage("add.wasm", fs.readFileSync(path.join(__dirname, "..", "src", "mock", "add.wasm")));
+  let add = new WebAssembly.Instance(new WebAssembly.Module(coverage.binary()), { coverage: { hit: (id) => coverage.hit(id) } });
+  assert.equal(add.exports.add(1, 2), 3);
+  assert.deepEqual(coverage.summary(), [{ name: "func[0]", calls: 1, blocks: 1, covered_blocks: 1 }]);
+  assert(coverage.lcov(".ts").includes("FNDA:1,func[0]"));
+}
+
+// Cross-contract flows can be awaited, when built with the `interpreter` feature
+async function testCallAsync() {
+  let contract = fs.readFileSync(path.join(__dirname, "fixtures", "contract.wasm"));
+  let vm = new rust.VM(new rust.ContextBuilder().build());
+  vm.set_account_code("alice", contract);
+  vm.set_account_code("bob", contract);
+  vm.set_ledger_balance("bob", "1000000000000000000000000");
+  // `call_echo` calls `bob.echo`, with `alice.on_echo` as the callback.
+  let { return_value, error, receipts } = await vm.callAsync("alice", "call_echo", "hi", { signer: "bob" });
+  assert.equal(error, null);
+  assert.equal(return_value, "");
+  let calls = receipts.filter(r => r.predecessor_id != "system");
+  assert.deepEqual(calls.map(r => r.actions), [["FunctionCall(call_echo)"], ["FunctionCall(echo)"], ["FunctionCall(on_echo)"]]);
+  assert.equal(calls[2].return_value, "hi");
+  assert(calls[2].block_index > calls[0].block_index);
+  // The refunds of the unused gas ran too.
+  assert(receipts.length > calls.length);
+  assert.equal(vm.pending_receipts().length, 0);
+  await assert.rejects(vm.callAsync("alice", "fail", "", { signer: "bob" }, 0), /didn't finish within 0 blocks/);
+  await vm.runUntilIdle();
+
+  // Calls interleave with a flow stepped through with `nextBlock`.
+  vm.schedule_call("alice", "call_echo", "hi", { signer: "bob" });
+  let calls_in = (executed) => executed.filter(r => r.predecessor_id != "system").map(r => r.actions[0]);
+  assert.deepEqual(calls_in(await vm.nextBlock()), ["FunctionCall(call_echo)"]);
+  vm.schedule_call("alice", "store", "between", { signer: "bob" });
+  assert.deepEqual(calls_in(await vm.nextBlock()), ["FunctionCall(echo)", "FunctionCall(store)"]);
+  assert.deepEqual(calls_in(await vm.nextBlock()), ["FunctionCall(on_echo)"]);
+}
+
+// Without the interpreter, the methods that run blocks reject
+async function testCallAsyncNeedsInterpreter() {
+  let vm = new rust.VM(new rust.ContextBuilder().build());
+  await assert.rejects(vm.callAsync("alice", "call_echo", "hi", {}), /needs the `interpreter` feature/);
+  await assert.rejects(vm.nextBlock(), /needs the `interpreter` feature/);
+}
+
 // rust.pass_context(context);
 // rust.set_context(new VMContext());
 
-console.log("PASSED!");
\ No newline at end of file
+(rust.VM.prototype.run_method ? testCallAsync() : testCallAsyncNeedsInterpreter())
+  .then(() => console.log("PASSED!"))
+  .catch(e => {
+    console.error(e);
+    process.exit(1);
+  });
\ No newline at end of file